@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::sleep;
+use std::time::Duration;
+
+use log::debug;
+
+use crate::config::Ads1115Config;
+use crate::i2c::I2cDevice;
+use crate::sensor::{Measurement, Sensor, SensorError, SensorErrorKind};
+
+const REG_CONVERSION: u8 = 0x00;
+const REG_CONFIG: u8 = 0x01;
+
+/// Start a single conversion, or while reading, not converting
+const CONFIG_OS: u16 = 0x8000;
+/// Single ended input on AIN0, the channel is added to it
+const CONFIG_MUX_AIN0: u16 = 0x4000;
+/// ±4.096V full scale, probes powered from 3.3V stay under it
+const CONFIG_PGA_4_096V: u16 = 0x0200;
+/// Power down after each conversion
+const CONFIG_MODE_SINGLE_SHOT: u16 = 0x0100;
+/// 128 samples per second
+const CONFIG_DR_128SPS: u16 = 0x0080;
+/// Comparator and ALERT/RDY pin off
+const CONFIG_COMP_QUE_DISABLE: u16 = 0x0003;
+
+/// A conversion at 128SPS takes 7.8ms
+const CONVERSION_TIME: Duration = Duration::from_millis(8);
+const CONFIG_POLLS: usize = 10;
+
+/// Held while a channel of a chip is converted. Its channels share one converter, so a
+/// conversion started for one channel must be read before another channel is configured.
+type ChipLock = Arc<Mutex<()>>;
+
+/// Lock of each chip by bus and address
+static CHIP_LOCKS: Mutex<BTreeMap<(u8, u16), ChipLock>> = Mutex::new(BTreeMap::new());
+
+fn chip_lock(bus: u8, address: u16) -> ChipLock {
+    CHIP_LOCKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry((bus, address))
+        .or_default()
+        .clone()
+}
+
+/// Read a soil moisture probe on a channel of a TI ADS1115, mapping the raw value to a
+/// percent with the probe's dry and wet calibration
+#[derive(Debug)]
+pub(crate) struct Ads1115Sensor<I: I2cDevice> {
+    device: I,
+    chip: ChipLock,
+    channel: u8,
+    dry: i16,
+    wet: i16,
+    samples: u16,
+}
+
+impl<I: I2cDevice> Ads1115Sensor<I> {
+    pub(crate) fn new(device: I, ads1115_config: &Ads1115Config) -> Self {
+        Self {
+            device,
+            chip: chip_lock(ads1115_config.bus(), ads1115_config.address()),
+            channel: ads1115_config.channel,
+            dry: ads1115_config.dry,
+            wet: ads1115_config.wet,
+            samples: ads1115_config.samples(),
+        }
+    }
+
+    /// Average of `samples` conversions of the channel
+    pub(crate) fn read_raw(&mut self) -> Result<f64, SensorError> {
+        let mut sum = 0.0;
+        for _ in 0..self.samples {
+            sum += self.convert()? as f64;
+        }
+        Ok(sum / self.samples as f64)
+    }
+
+    pub(crate) fn read(&mut self) -> Result<Measurement, SensorError> {
+        let raw = self.read_raw()?;
+        debug!("ads1115 channel {} raw: {}", self.channel, raw);
+        Ok(Measurement::Moisture(moisture_percent(
+            raw, self.dry, self.wet,
+        )))
+    }
+
+    fn convert(&mut self) -> Result<i16, SensorError> {
+        let config = CONFIG_OS
+            | CONFIG_MUX_AIN0
+            | (self.channel as u16) << 12
+            | CONFIG_PGA_4_096V
+            | CONFIG_MODE_SINGLE_SHOT
+            | CONFIG_DR_128SPS
+            | CONFIG_COMP_QUE_DISABLE;
+        let [msb, lsb] = config.to_be_bytes();
+        // Held until the conversion is read, other channels of the chip wait for it
+        let chip = self.chip.clone();
+        let _chip = chip.lock().unwrap_or_else(PoisonError::into_inner);
+        self.device.write(&[REG_CONFIG, msb, lsb])?;
+        sleep(CONVERSION_TIME);
+        self.wait_for_conversion()?;
+        let mut conversion = [0u8; 2];
+        self.device
+            .read_registers(REG_CONVERSION, &mut conversion)?;
+        Ok(i16::from_be_bytes(conversion))
+    }
+
+    fn wait_for_conversion(&mut self) -> Result<(), SensorError> {
+        let mut config = [0u8; 2];
+        for _ in 0..CONFIG_POLLS {
+            self.device.read_registers(REG_CONFIG, &mut config)?;
+            if u16::from_be_bytes(config) & CONFIG_OS > 0 {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(1));
+        }
+        Err(SensorError::KindMsg(
+            SensorErrorKind::ReadTimeout,
+            "conversion didn't finish",
+        ))
+    }
+}
+
+impl<I: I2cDevice> Sensor for Ads1115Sensor<I> {
+    fn measure(&mut self) -> Result<Vec<Measurement>, SensorError> {
+        Ok(vec![self.read()?])
+    }
+}
+
+/// Map `raw` linearly from `dry` at 0% to `wet` at 100%, clamped to 0 to 100%.
+/// Capacitive probes read lower the wetter the soil, so `wet` is usually below `dry`.
+pub(crate) fn moisture_percent(raw: f64, dry: i16, wet: i16) -> f64 {
+    let percent = (raw - dry as f64) / (wet as f64 - dry as f64) * 100.0;
+    percent.clamp(0.0, 100.0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use crate::ads1115::{moisture_percent, Ads1115Sensor, REG_CONFIG, REG_CONVERSION};
+    use crate::config::Ads1115Config;
+    use crate::i2c::{I2cDevice, RegisterMap};
+    use crate::sensor::{Measurement, SensorError};
+
+    /// One chip shared by the sensors of its channels, converting the channel that was last
+    /// configured to 1000 times the channel number
+    #[derive(Debug, Clone, Default)]
+    struct SharedChip {
+        channel: Arc<Mutex<u8>>,
+    }
+
+    impl I2cDevice for SharedChip {
+        fn write(&mut self, bytes: &[u8]) -> Result<(), SensorError> {
+            if let [REG_CONFIG, msb, _] = bytes {
+                *self.channel.lock().unwrap() = (msb >> 4) & 0b11;
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, _buffer: &mut [u8]) -> Result<(), SensorError> {
+            Ok(())
+        }
+
+        fn write_read(&mut self, bytes: &[u8], buffer: &mut [u8]) -> Result<(), SensorError> {
+            let value: i16 = match bytes {
+                [REG_CONVERSION] => 1000 * *self.channel.lock().unwrap() as i16,
+                // Conversion done
+                _ => 0x8583u16 as i16,
+            };
+            buffer.copy_from_slice(&value.to_be_bytes());
+            Ok(())
+        }
+    }
+
+    fn ads1115_config(channel: u8, samples: Option<u16>) -> Ads1115Config {
+        Ads1115Config {
+            name: "bed_1".to_string(),
+            bus: None,
+            address: None,
+            channel,
+            dry: 20000,
+            wet: 8000,
+            samples,
+        }
+    }
+
+    #[test]
+    fn test_moisture_percent() {
+        assert_eq!(moisture_percent(20000.0, 20000, 8000), 0.0);
+        assert_eq!(moisture_percent(8000.0, 20000, 8000), 100.0);
+        assert_eq!(moisture_percent(11000.0, 20000, 8000), 75.0);
+        assert_eq!(moisture_percent(25000.0, 20000, 8000), 0.0);
+        assert_eq!(moisture_percent(-5.0, 20000, 8000), 100.0);
+        // A probe that reads higher when wet
+        assert_eq!(moisture_percent(300.0, 100, 500), 50.0);
+    }
+
+    #[test]
+    fn test_ads1115_read() {
+        let mut device = RegisterMap::default();
+        // Conversion done, then 14000, 13000 and 12000
+        device.respond(&[0x01], &[0x85, 0x83]);
+        device.respond(&[0x00], &[0x36, 0xB0]);
+        device.respond(&[0x00], &[0x32, 0xC8]);
+        device.respond(&[0x00], &[0x2E, 0xE0]);
+        let mut sensor = Ads1115Sensor::new(device, &ads1115_config(2, Some(3)));
+        assert!(matches!(sensor.read(), Ok(Measurement::Moisture(m)) if (m - 58.33).abs() < 0.01));
+        // Single shot of AIN2
+        assert_eq!(sensor.device.writes[0], vec![0x01, 0xE3, 0x83]);
+        assert_eq!(sensor.device.writes.len(), 9);
+
+        let mut device = RegisterMap::default();
+        device.respond(&[0x01], &[0x80, 0x00]);
+        device.respond(&[0x00], &[0x1F, 0x40]);
+        let mut sensor = Ads1115Sensor::new(device, &ads1115_config(0, None));
+        assert_eq!(sensor.read_raw().unwrap(), 8000.0);
+        assert_eq!(sensor.device.writes[0], vec![0x01, 0xC3, 0x83]);
+
+        // Still converting
+        let mut device = RegisterMap::default();
+        device.respond(&[0x01], &[0x43, 0x83]);
+        let mut sensor = Ads1115Sensor::new(device, &ads1115_config(0, None));
+        assert!(matches!(sensor.read(), Err(SensorError::KindMsg(_, _))));
+    }
+
+    #[test]
+    fn test_ads1115_channels_share_a_chip() {
+        let chip = SharedChip::default();
+        let readers: Vec<_> = [1, 2]
+            .into_iter()
+            .map(|channel| {
+                let mut sensor =
+                    Ads1115Sensor::new(chip.clone(), &ads1115_config(channel, Some(5)));
+                thread::spawn(move || sensor.read_raw().unwrap())
+            })
+            .collect();
+        let raw: Vec<f64> = readers
+            .into_iter()
+            .map(|reader| reader.join().unwrap())
+            .collect();
+        assert_eq!(raw, vec![1000.0, 2000.0]);
+    }
+}
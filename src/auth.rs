@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::warn;
+use sha2::{Digest, Sha256};
+use warp::{Filter, Rejection};
+
+use crate::config::{AuthConfig, Role};
+use crate::error::AuthError;
+
+/// Checks api tokens against the hashed tokens in the config
+#[derive(Debug, Clone)]
+pub(crate) struct Authenticator {
+    /// Token name and role keyed by the sha256 of the token
+    tokens: Arc<HashMap<Vec<u8>, (String, Role)>>,
+    public_metrics: bool,
+}
+
+impl Authenticator {
+    pub(crate) fn new(config: &AuthConfig) -> Self {
+        let tokens: HashMap<Vec<u8>, (String, Role)> = config
+            .tokens
+            .iter()
+            .flatten()
+            .filter_map(|token| {
+                let hash = hex::decode(&token.sha256).ok()?;
+                Some((hash, (token.name.clone(), token.role)))
+            })
+            .collect();
+        if tokens.is_empty() {
+            warn!(
+                "AUTH IS DISABLED: no api tokens are configured under auth.tokens, anyone who \
+                 can reach the server can switch pins and change the config"
+            );
+        }
+        Self {
+            tokens: Arc::new(tokens),
+            public_metrics: config.is_public_metrics(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Authenticator for /metrics, which lets anyone in when `public_metrics` is set
+    pub(crate) fn for_metrics(&self) -> Self {
+        if self.public_metrics {
+            Self {
+                tokens: Arc::new(HashMap::new()),
+                public_metrics: true,
+            }
+        } else {
+            self.clone()
+        }
+    }
+
+    /// Check that `token` exists and has at least `role`
+    pub(crate) fn authorize(&self, token: Option<&str>, role: Role) -> Result<(), AuthError> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let token = token.ok_or(AuthError::MissingToken)?;
+        let hash = Sha256::digest(token.as_bytes()).to_vec();
+        match self.tokens.get(&hash) {
+            Some((_, token_role)) if *token_role >= role => Ok(()),
+            Some((name, _)) => Err(AuthError::Forbidden {
+                token: name.clone(),
+            }),
+            None => Err(AuthError::InvalidToken),
+        }
+    }
+}
+
+/// Reject requests without a token that has `role`. The token is only read from an
+/// `Authorization: Bearer` header, never the query string, which ends up in access logs.
+pub(crate) fn require(
+    auth: Authenticator,
+    role: Role,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                let token = header
+                    .as_deref()
+                    .and_then(|value| value.strip_prefix("Bearer "));
+                auth.authorize(token, role).map_err(warp::reject::custom)
+            }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::auth::Authenticator;
+    use crate::config::{AuthConfig, Role};
+    use crate::error::AuthError;
+    use crate::test_util::api_token;
+
+    #[test]
+    fn test_authorize() {
+        let open = Authenticator::new(&AuthConfig::default());
+        assert!(open.authorize(None, Role::Operator).is_ok());
+
+        let auth = Authenticator::new(&AuthConfig {
+            tokens: Some(vec![
+                // echo -n grafana | sha256sum
+                api_token(
+                    "grafana",
+                    Role::ReadOnly,
+                    "cace491b69555e8d0f77747d47ae54e31ce4cc322fe51a7bdcf64402f3676ebf",
+                ),
+                // echo -n operator | sha256sum
+                api_token(
+                    "ops",
+                    Role::Operator,
+                    "06e55b633481f7bb072957eabcf110c972e86691c3cfedabe088024bffe42f23",
+                ),
+            ]),
+            public_metrics: None,
+        });
+        assert!(matches!(
+            auth.authorize(None, Role::ReadOnly),
+            Err(AuthError::MissingToken)
+        ));
+        assert!(matches!(
+            auth.authorize(Some("nope"), Role::ReadOnly),
+            Err(AuthError::InvalidToken)
+        ));
+        assert!(auth.authorize(Some("grafana"), Role::ReadOnly).is_ok());
+        assert!(matches!(
+            auth.authorize(Some("grafana"), Role::Operator),
+            Err(AuthError::Forbidden { .. })
+        ));
+        assert!(auth.authorize(Some("operator"), Role::ReadOnly).is_ok());
+        assert!(auth.authorize(Some("operator"), Role::Operator).is_ok());
+    }
+}
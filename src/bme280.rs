@@ -0,0 +1,314 @@
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::i2c::I2cDevice;
+use crate::sensor::{Humidity, Measurement, Sensor, SensorError, SensorErrorKind};
+
+const REG_CALIB_00: u8 = 0x88;
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_CALIB_26: u8 = 0xE1;
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_STATUS: u8 = 0xF3;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_DATA: u8 = 0xF7;
+
+const BME280_CHIP_ID: u8 = 0x60;
+/// Engineering samples of the BMP280 report 0x56 and 0x57
+const BMP280_CHIP_IDS: [u8; 3] = [0x56, 0x57, 0x58];
+
+/// Humidity oversampling x1
+const CTRL_HUM: u8 = 0x01;
+/// Temperature and pressure oversampling x1 in forced mode, one measurement then sleep
+const CTRL_MEAS_FORCED: u8 = 0x25;
+/// Set in the status register while a measurement runs
+const STATUS_MEASURING: u8 = 0x08;
+/// Longest measurement time with x1 oversampling is 9.3ms
+const MEASUREMENT_TIME: Duration = Duration::from_millis(10);
+const STATUS_POLLS: usize = 10;
+/// What the data registers hold for a measurement that was skipped
+const SKIPPED: u32 = 0x80000;
+
+/// Trimming parameters programmed into each chip, used to compensate its raw readings
+#[derive(Debug, Clone, PartialEq)]
+struct Calibration {
+    t: [f64; 3],
+    p: [f64; 9],
+    /// None on a BMP280, which doesn't measure humidity
+    h: Option<[f64; 6]>,
+}
+
+impl Calibration {
+    /// Parse the 26 bytes from 0x88 and, on a BME280, the 7 bytes from 0xE1
+    fn parse(calib_00: &[u8; 26], calib_26: Option<&[u8; 7]>) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([calib_00[i], calib_00[i + 1]]) as f64;
+        let i16_at = |i: usize| i16::from_le_bytes([calib_00[i], calib_00[i + 1]]) as f64;
+        let t = [u16_at(0), i16_at(2), i16_at(4)];
+        let mut p = [u16_at(6), 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        for (n, p) in p.iter_mut().enumerate().skip(1) {
+            *p = i16_at(6 + 2 * n);
+        }
+        let h = calib_26.map(|calib_26| {
+            // H4 and H5 are 12 bit, sharing the nibbles of 0xE5
+            let h4 = ((calib_26[3] as i8 as i16) << 4) | (calib_26[4] & 0x0F) as i16;
+            let h5 = ((calib_26[5] as i8 as i16) << 4) | (calib_26[4] >> 4) as i16;
+            [
+                calib_00[25] as f64,
+                i16::from_le_bytes([calib_26[0], calib_26[1]]) as f64,
+                calib_26[2] as f64,
+                h4 as f64,
+                h5 as f64,
+                calib_26[6] as i8 as f64,
+            ]
+        });
+        Self { t, p, h }
+    }
+
+    /// Temperature in degrees celsius and the fine temperature the other values use
+    fn temperature(&self, adc_t: u32) -> (f64, f64) {
+        let [t1, t2, t3] = self.t;
+        let adc_t = adc_t as f64;
+        let var1 = (adc_t / 16384.0 - t1 / 1024.0) * t2;
+        let var2 = (adc_t / 131072.0 - t1 / 8192.0).powi(2) * t3;
+        let t_fine = var1 + var2;
+        (t_fine / 5120.0, t_fine)
+    }
+
+    /// Pressure in pascals
+    fn pressure(&self, adc_p: u32, t_fine: f64) -> f64 {
+        let [p1, p2, p3, p4, p5, p6, p7, p8, p9] = self.p;
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * p6 / 32768.0;
+        var2 += var1 * p5 * 2.0;
+        var2 = var2 / 4.0 + p4 * 65536.0;
+        var1 = (p3 * var1 * var1 / 524288.0 + p2 * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * p1;
+        if var1 == 0.0 {
+            // Avoid dividing by zero, the calibration is missing
+            return 0.0;
+        }
+        let mut p = 1048576.0 - adc_p as f64;
+        p = (p - var2 / 4096.0) * 6250.0 / var1;
+        var1 = p9 * p * p / 2147483648.0;
+        var2 = p * p8 / 32768.0;
+        p + (var1 + var2 + p7) / 16.0
+    }
+
+    /// Relative humidity, clamped to 0 to 100%
+    fn humidity(&self, adc_h: u32, t_fine: f64) -> Option<f64> {
+        let [h1, h2, h3, h4, h5, h6] = self.h?;
+        let mut var_h = t_fine - 76800.0;
+        var_h = (adc_h as f64 - (h4 * 64.0 + h5 / 16384.0 * var_h))
+            * (h2 / 65536.0 * (1.0 + h6 / 67108864.0 * var_h * (1.0 + h3 / 67108864.0 * var_h)));
+        var_h *= 1.0 - h1 * var_h / 524288.0;
+        Some(var_h.clamp(0.0, 100.0))
+    }
+}
+
+/// Read temperature, pressure and, on a BME280, humidity from a Bosch BME280 or BMP280
+#[derive(Debug)]
+pub(crate) struct Bme280Sensor<I: I2cDevice> {
+    device: I,
+    calibration: Calibration,
+}
+
+impl<I: I2cDevice> Bme280Sensor<I> {
+    /// Check the chip id is a BME280, or a BMP280 when not `with_humidity`, and read its
+    /// calibration
+    pub(crate) fn new(mut device: I, with_humidity: bool) -> Result<Self, SensorError> {
+        let mut chip_id = [0u8];
+        device.read_registers(REG_CHIP_ID, &mut chip_id)?;
+        let chip_id = chip_id[0];
+        if with_humidity && chip_id != BME280_CHIP_ID {
+            return Err(SensorError::KindMsg(
+                SensorErrorKind::Initialization,
+                "chip id isn't a BME280's",
+            ));
+        } else if !with_humidity && !BMP280_CHIP_IDS.contains(&chip_id) {
+            return Err(SensorError::KindMsg(
+                SensorErrorKind::Initialization,
+                "chip id isn't a BMP280's",
+            ));
+        }
+
+        let mut calib_00 = [0u8; 26];
+        device.read_registers(REG_CALIB_00, &mut calib_00)?;
+        let calib_26 = if with_humidity {
+            let mut calib_26 = [0u8; 7];
+            device.read_registers(REG_CALIB_26, &mut calib_26)?;
+            Some(calib_26)
+        } else {
+            None
+        };
+        Ok(Self {
+            device,
+            calibration: Calibration::parse(&calib_00, calib_26.as_ref()),
+        })
+    }
+
+    /// Run one forced measurement and compensate it
+    pub(crate) fn read(&mut self) -> Result<Vec<Measurement>, SensorError> {
+        let with_humidity = self.calibration.h.is_some();
+        // ctrl_hum only takes effect after ctrl_meas is written
+        if with_humidity {
+            self.device.write_register(REG_CTRL_HUM, CTRL_HUM)?;
+        }
+        self.device
+            .write_register(REG_CTRL_MEAS, CTRL_MEAS_FORCED)?;
+        sleep(MEASUREMENT_TIME);
+        self.wait_for_measurement()?;
+
+        let mut data = [0u8; 8];
+        let len = if with_humidity { 8 } else { 6 };
+        self.device.read_registers(REG_DATA, &mut data[..len])?;
+        let adc_p = u32::from(data[0]) << 12 | u32::from(data[1]) << 4 | u32::from(data[2]) >> 4;
+        let adc_t = u32::from(data[3]) << 12 | u32::from(data[4]) << 4 | u32::from(data[5]) >> 4;
+        let adc_h = u32::from(data[6]) << 8 | u32::from(data[7]);
+        if adc_t == SKIPPED || adc_p == SKIPPED {
+            return Err(SensorError::KindMsg(
+                SensorErrorKind::InvalidData,
+                "measurement was skipped",
+            ));
+        }
+
+        let (temp_c, t_fine) = self.calibration.temperature(adc_t);
+        if !(-40.0..=85.0).contains(&temp_c) {
+            return Err(SensorError::ValueBounds(format!(
+                "temp_c {} is out of bounds",
+                temp_c
+            )));
+        }
+        let pressure = self.calibration.pressure(adc_p, t_fine) / 100.0;
+        if !(300.0..=1100.0).contains(&pressure) {
+            return Err(SensorError::ValueBounds(format!(
+                "pressure {} is out of bounds",
+                pressure
+            )));
+        }
+        let mut measurements = vec![
+            Measurement::Temperature(temp_c.into()),
+            Measurement::Pressure(pressure),
+        ];
+        if let Some(humidity) = self.calibration.humidity(adc_h, t_fine) {
+            measurements.push(Measurement::Humidity(Humidity::from(humidity)));
+        }
+        Ok(measurements)
+    }
+
+    fn wait_for_measurement(&mut self) -> Result<(), SensorError> {
+        let mut status = [0u8];
+        for _ in 0..STATUS_POLLS {
+            self.device.read_registers(REG_STATUS, &mut status)?;
+            if status[0] & STATUS_MEASURING == 0 {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(2));
+        }
+        Err(SensorError::KindMsg(
+            SensorErrorKind::ReadTimeout,
+            "measurement didn't finish",
+        ))
+    }
+}
+
+impl<I: I2cDevice> Sensor for Bme280Sensor<I> {
+    fn measure(&mut self) -> Result<Vec<Measurement>, SensorError> {
+        self.read()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bme280::{Bme280Sensor, Calibration, CTRL_MEAS_FORCED, REG_CTRL_HUM, REG_CTRL_MEAS};
+    use crate::i2c::RegisterMap;
+    use crate::sensor::{Measurement, SensorError};
+
+    /// Calibration of the compensation example in the BMP280 datasheet
+    const CALIB_00: [u8; 26] = [
+        0x70, 0x6B, // T1 27504
+        0x43, 0x67, // T2 26435
+        0x18, 0xFC, // T3 -1000
+        0x7D, 0x8E, // P1 36477
+        0x43, 0xD6, // P2 -10685
+        0xD0, 0x0B, // P3 3024
+        0x27, 0x0B, // P4 2855
+        0x8C, 0x00, // P5 140
+        0xF9, 0xFF, // P6 -7
+        0x8C, 0x3C, // P7 15500
+        0xF8, 0xC6, // P8 -14600
+        0x70, 0x17, // P9 6000
+        0x00, // reserved
+        0x4B, // H1 75
+    ];
+    /// H2 362, H3 0, H4 313, H5 50, H6 30
+    const CALIB_26: [u8; 7] = [0x6A, 0x01, 0x00, 0x13, 0x29, 0x03, 0x1E];
+
+    /// adc_P 415148, adc_T 519888 and adc_H 31118
+    const DATA: [u8; 8] = [0x65, 0x5A, 0xC0, 0x7E, 0xED, 0x00, 0x79, 0x8E];
+
+    fn register_map(chip_id: u8) -> RegisterMap {
+        let mut registers = RegisterMap::default();
+        registers.set(0xD0, &[chip_id]);
+        registers.set(0x88, &CALIB_00);
+        registers.set(0xE1, &CALIB_26);
+        registers.set(0xF7, &DATA);
+        registers
+    }
+
+    #[test]
+    fn test_bme280_compensation() {
+        let calibration = Calibration::parse(&CALIB_00, Some(&CALIB_26));
+        assert_eq!(calibration.h, Some([75.0, 362.0, 0.0, 313.0, 50.0, 30.0]));
+        let (temp_c, t_fine) = calibration.temperature(519888);
+        assert!((temp_c - 25.08).abs() < 0.005, "{}", temp_c);
+        let pressure = calibration.pressure(415148, t_fine);
+        assert!((pressure - 100653.27).abs() < 0.01, "{}", pressure);
+        let humidity = calibration.humidity(31118, t_fine).unwrap();
+        assert!((humidity - 61.2128).abs() < 0.0001, "{}", humidity);
+        assert_eq!(calibration.humidity(0xFFFF, t_fine), Some(100.0));
+
+        let mut sensor = Bme280Sensor::new(register_map(0x60), true).unwrap();
+        let measurements = sensor.read().unwrap();
+        assert_eq!(measurements.len(), 3);
+        assert!(
+            matches!(measurements[1], Measurement::Pressure(hpa) if (hpa - 1006.53).abs() < 0.01)
+        );
+        assert!(matches!(measurements[2], Measurement::Humidity(_)));
+        // Humidity oversampling has to be set before the forced measurement starts
+        assert_eq!(
+            sensor.device.writes[3..5],
+            [
+                vec![REG_CTRL_HUM, 0x01],
+                vec![REG_CTRL_MEAS, CTRL_MEAS_FORCED],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bmp280_and_errors() {
+        let mut sensor = Bme280Sensor::new(register_map(0x58), false).unwrap();
+        assert_eq!(sensor.calibration.h, None);
+        let measurements = sensor.read().unwrap();
+        assert_eq!(measurements.len(), 2);
+        assert!(matches!(measurements[0], Measurement::Temperature(_)));
+
+        assert!(Bme280Sensor::new(register_map(0x58), true).is_err());
+        assert!(Bme280Sensor::new(register_map(0x60), false).is_err());
+
+        let mut registers = register_map(0x60);
+        // Still measuring
+        registers.set(0xF3, &[0x08]);
+        let mut sensor = Bme280Sensor::new(registers, true).unwrap();
+        assert!(matches!(sensor.read(), Err(SensorError::KindMsg(_, _))));
+
+        let mut registers = register_map(0x60);
+        registers.set(0xF7, &[0x80, 0x00, 0x00]);
+        let mut sensor = Bme280Sensor::new(registers, true).unwrap();
+        assert!(sensor.read().is_err());
+
+        // 187°C, more than the chip measures
+        let mut registers = register_map(0x60);
+        registers.set(0xFA, &[0xFF, 0xFF, 0xF0]);
+        let mut sensor = Bme280Sensor::new(registers, true).unwrap();
+        assert!(matches!(sensor.read(), Err(SensorError::ValueBounds(_))));
+    }
+}
@@ -0,0 +1,173 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use log::warn;
+use rppal::gpio::Mode::Output;
+
+use crate::config::{DhtModel, GHAConfig, SensorConfig};
+use crate::dht22;
+use crate::error::GHAError;
+use crate::sensor::create_gpio_backend;
+use crate::sensor_manager::SensorManager;
+use crate::shutdown::ShutdownSignal;
+
+/// Failed DHT22 reads are retried this many times, 2s apart
+const READ_ATTEMPTS: u32 = 5;
+
+/// Greenhouse sensor and switch agent
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub(crate) struct Cli {
+    /// Config file, overlaid on the defaults
+    #[arg(long, global = true, default_value = "gha.yaml")]
+    pub(crate) config: PathBuf,
+
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Run the agent, the default without a subcommand
+    Serve,
+    /// Validate the config and print it merged with the defaults
+    CheckConfig,
+    /// Read the DHT on a pin once and print the temperature and humidity, the model is taken
+    /// from the config's dht sensor on the pin, dht22 otherwise
+    ReadSensor {
+        #[arg(long)]
+        pin: u32,
+    },
+    /// Drive a switch device's pin low or high, held until Ctrl-C
+    SetPin {
+        pin: u32,
+        #[arg(value_parser = clap::value_parser!(u32).range(0..=1))]
+        value: u32,
+    },
+    /// Read every sensor once and print its metrics, without touching any output pins
+    DumpMetrics,
+}
+
+pub(crate) fn check_config(gha_config: &GHAConfig) -> Result<(), GHAError> {
+    print!("{}", serde_yaml::to_string(gha_config)?);
+    Ok(())
+}
+
+pub(crate) async fn read_sensor(gha_config: &GHAConfig, pin: u32) -> Result<(), GHAError> {
+    let gpio = create_gpio_backend(gha_config);
+    // Keep the sensor board powered for the read, the pin is released on drop
+    let _board = match gha_config.dht_board_pin {
+        Some(board_pin) => {
+            let mut board = gpio.open_pin(board_pin, Output)?;
+            if !board.is_high() {
+                board.set_high();
+                tokio::time::sleep(Duration::from_millis(2000)).await;
+            }
+            Some(board)
+        }
+        None => None,
+    };
+
+    let model = gha_config
+        .sensor_configs()
+        .iter()
+        .find_map(|sensor_config| match sensor_config {
+            SensorConfig::Dht(dht_config) if dht_config.pin() == pin => Some(dht_config.model()),
+            _ => None,
+        })
+        .unwrap_or(DhtModel::Dht22);
+    for attempt in 1..=READ_ATTEMPTS {
+        match dht22::read_pin(gpio.as_ref(), pin, model) {
+            Ok((temp_c, humidity)) => {
+                let temp_c = f64::from(temp_c);
+                println!(
+                    "pin {}: {:.1}C {:.1}F / {:.1}% RH",
+                    pin,
+                    temp_c,
+                    (temp_c * 1.8f64) + 32f64,
+                    f64::from(humidity)
+                );
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("Read {} of pin {} failed: {}", attempt, pin, e);
+                if attempt < READ_ATTEMPTS {
+                    tokio::time::sleep(Duration::from_millis(2000)).await;
+                }
+            }
+        }
+    }
+    Err(GHAError::from_string(format!(
+        "No good reading from pin {} in {} attempts",
+        pin, READ_ATTEMPTS
+    )))
+}
+
+pub(crate) async fn set_pin(gha_config: &GHAConfig, pin: u32, value: u32) -> Result<(), GHAError> {
+    // Only switch devices are wired to drive something, other pins could be sensor data lines
+    if !gha_config
+        .switch_devices
+        .iter()
+        .flatten()
+        .any(|switch_device| switch_device.pin() == pin)
+    {
+        return Err(GHAError::from_string(format!(
+            "pin {} is not a configured switch device",
+            pin
+        )));
+    }
+    let mut shutdown_signal = ShutdownSignal::new()?;
+    let gpio = create_gpio_backend(gha_config);
+    let mut output = gpio.open_pin(pin, Output)?;
+    if value == 1 {
+        output.set_high();
+    } else {
+        output.set_low();
+    }
+    println!("pin {} = {}, press Ctrl-C to release it", pin, value);
+    shutdown_signal.recv().await;
+    Ok(())
+}
+
+pub(crate) async fn dump_metrics(gha_config: &GHAConfig) -> Result<(), GHAError> {
+    // Switch devices and the dht board are left alone, sensors on an unpowered board fail
+    print!("{}", SensorManager::read_metrics_once(gha_config, READ_ATTEMPTS).await);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use crate::cli::{set_pin, Cli, Command};
+    use crate::test_util::simulated_config;
+
+    #[test]
+    fn test_cli() {
+        let cli = Cli::try_parse_from(["greenhouse-agent"]).unwrap();
+        assert_eq!(cli.config.to_str(), Some("gha.yaml"));
+        assert!(cli.command.is_none());
+
+        let cli =
+            Cli::try_parse_from(["greenhouse-agent", "serve", "--config", "/etc/gha.yaml"]).unwrap();
+        assert_eq!(cli.config.to_str(), Some("/etc/gha.yaml"));
+        assert!(matches!(cli.command, Some(Command::Serve)));
+
+        let cli = Cli::try_parse_from(["greenhouse-agent", "read-sensor", "--pin", "17"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::ReadSensor { pin: 17 })));
+
+        let cli = Cli::try_parse_from(["greenhouse-agent", "set-pin", "18", "1"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::SetPin { pin: 18, value: 1 })
+        ));
+        assert!(Cli::try_parse_from(["greenhouse-agent", "set-pin", "18", "2"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_set_pin_rejects_unconfigured_pins() {
+        let err = set_pin(&simulated_config(), 17, 1).await.unwrap_err();
+        assert!(err.to_string().contains("is not a configured switch device"));
+    }
+}
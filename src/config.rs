@@ -1,13 +1,52 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+
+use chrono::TimeDelta;
 use serde::{Deserialize, Serialize};
+use warp::http::uri::{Authority, Scheme};
+
+use crate::ds18b20;
+use crate::error::GHAError;
+use crate::scheduler::ScheduleWindows;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct GHAConfig {
     pub(crate) listen_host: Option<String>,
     pub(crate) listen_port: Option<u16>,
+    /// PEM certificate chain, serves HTTPS on listen_port when set with tls_key_path
+    pub(crate) tls_cert_path: Option<String>,
+    /// PEM private key for tls_cert_path
+    pub(crate) tls_key_path: Option<String>,
+    /// Port that redirects plain HTTP requests to HTTPS on listen_port
+    pub(crate) tls_redirect_port: Option<u16>,
     pub(crate) dht_board_pin: Option<u32>,
+    pub(crate) dht_board_line: Option<GpioLine>,
+    /// DHT sensors, the same as `sensors` entries with `type: dht`
+    #[serde(default)]
     pub(crate) dht_configs: Vec<DhtConfig>,
+    /// Sensors of any type, read alongside `dht_configs`
+    pub(crate) sensors: Option<Vec<SensorConfig>>,
     pub(crate) switch_devices: Option<Vec<SwitchDevice>>,
+    #[serde(default)]
     pub(crate) cors_origins: Vec<String>,
+    pub(crate) gpio_backend: Option<GpioBackendKind>,
+    pub(crate) gpio_chip: Option<String>,
+    /// Where the kernel's w1 driver lists 1-Wire devices, defaults to /sys/bus/w1/devices
+    pub(crate) w1_devices_dir: Option<String>,
+    pub(crate) monitor_sources: Option<Vec<MonitorSource>>,
+    pub(crate) monitors: Option<Vec<Monitor>>,
+    pub(crate) fail_safes: Option<Vec<FailSafe>>,
+    pub(crate) state_file: Option<String>,
+    pub(crate) history: Option<HistoryConfig>,
+    pub(crate) auth: Option<AuthConfig>,
+    pub(crate) mqtt: Option<MqttConfig>,
+    /// Sensor values are invalid when there's no good reading for this long, defaults to 5m
+    pub(crate) sensor_stale_after: Option<String>,
+    /// Power off the dht board when the agent shuts down
+    pub(crate) shutdown_dht_board_off: Option<bool>,
+    /// Names of the Prometheus metrics, the legacy per-sensor gauges unless set to labeled
+    pub(crate) metrics_style: Option<MetricsStyle>,
 }
 
 impl GHAConfig {
@@ -15,10 +54,97 @@ impl GHAConfig {
         Self {
             listen_host: Some("0.0.0.0".to_string()),
             listen_port: Some(6666),
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_redirect_port: None,
             dht_configs: Vec::new(),
+            sensors: Some(Vec::new()),
             dht_board_pin: None,
+            dht_board_line: None,
             switch_devices: Some(Vec::new()),
             cors_origins: Vec::new(),
+            gpio_backend: Some(GpioBackendKind::Rppal),
+            gpio_chip: Some("gpiochip0".to_string()),
+            w1_devices_dir: Some("/sys/bus/w1/devices".to_string()),
+            monitor_sources: Some(Vec::new()),
+            monitors: Some(Vec::new()),
+            fail_safes: Some(Vec::new()),
+            state_file: Some("gha_state.json".to_string()),
+            history: Some(HistoryConfig::default()),
+            auth: None,
+            mqtt: None,
+            sensor_stale_after: None,
+            shutdown_dht_board_off: Some(false),
+            metrics_style: Some(MetricsStyle::Legacy),
+        }
+    }
+
+    /// Read the yaml file at `path`, overlay it on the defaults and validate it
+    pub(crate) fn load(path: &Path) -> Result<GHAConfig, GHAError> {
+        let yaml_file_str = std::fs::read(path).map_err(|e| {
+            GHAError::from_string(format!("Unable to read config {}: {}", path.display(), e))
+        })?;
+        let gha_config: GHAConfig = serde_yaml::from_slice(yaml_file_str.as_slice())?;
+        gha_config.with_defaults()
+    }
+
+    /// Overlay this config, as read from a file, on the defaults and validate the result
+    pub(crate) fn with_defaults(self) -> Result<GHAConfig, GHAError> {
+        let gha_config: GHAConfig = serde_merge::omerge(GHAConfig::default(), self)?;
+        gha_config.validate()?;
+        Ok(gha_config)
+    }
+
+    /// Whether `key` is a top level setting
+    pub(crate) fn is_setting(key: &str) -> bool {
+        // Unset settings serialize as null, so every field is a key
+        match serde_yaml::to_value(GHAConfig::default()) {
+            Ok(serde_yaml::Value::Mapping(mapping)) => mapping.contains_key(key),
+            _ => false,
+        }
+    }
+
+    /// Keep the settings of the `running` config that only take effect at startup,
+    /// returning the names of those this config changes
+    pub(crate) fn keep_startup_settings(&mut self, running: &GHAConfig) -> Vec<String> {
+        let mut changed = Vec::new();
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field != running.$field {
+                        changed.push(stringify!($field).to_string());
+                        self.$field = running.$field.clone();
+                    }
+                )*
+            };
+        }
+        keep!(
+            listen_host,
+            listen_port,
+            tls_cert_path,
+            tls_key_path,
+            tls_redirect_port,
+            dht_board_pin,
+            dht_board_line,
+            cors_origins,
+            gpio_backend,
+            gpio_chip,
+            w1_devices_dir,
+            state_file,
+            history,
+            auth,
+            mqtt,
+            sensor_stale_after,
+            metrics_style
+        );
+        changed
+    }
+
+    /// Certificate and key paths when HTTPS is configured
+    pub(crate) fn tls_paths(&self) -> Option<(&str, &str)> {
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => Some((cert_path.as_str(), key_path.as_str())),
+            _ => None,
         }
     }
 
@@ -26,26 +152,1249 @@ impl GHAConfig {
         self.dht_board_pin.is_some()
     }
 
+    pub(crate) fn is_shutdown_dht_board_off(&self) -> bool {
+        self.is_dht_board_pin_set() && self.shutdown_dht_board_off.unwrap_or(false)
+    }
+
+    /// Whether the sensor workers run. Sensors on the dht board are only read with a
+    /// `dht_board_pin`, so configs without one can be run off a Raspberry Pi.
+    pub(crate) fn is_reading_sensors(&self) -> bool {
+        self.is_dht_board_pin_set()
+            || self
+                .sensor_configs()
+                .iter()
+                .any(|sensor_config| self.is_sensor_read(sensor_config))
+    }
+
+    /// Whether the sensor workers read `sensor_config`
+    pub(crate) fn is_sensor_read(&self, sensor_config: &SensorConfig) -> bool {
+        self.is_dht_board_pin_set() || !sensor_config.is_on_dht_board()
+    }
+
+    /// Every sensor, `dht_configs` first and then `sensors`
+    pub(crate) fn sensor_configs(&self) -> Vec<SensorConfig> {
+        self.dht_configs
+            .iter()
+            .cloned()
+            .map(SensorConfig::Dht)
+            .chain(self.sensors.iter().flatten().cloned())
+            .collect()
+    }
+
     pub(crate) fn origins(&self) -> Vec<&str> {
         let mut ret: Vec<&str> = Vec::with_capacity(self.cors_origins.len());
         for cors_origin in &self.cors_origins {
             ret.push(cors_origin.as_str())
         }
-        return ret
+        ret
+    }
+
+    pub(crate) fn gpio_backend(&self) -> GpioBackendKind {
+        self.gpio_backend.unwrap_or(GpioBackendKind::Rppal)
+    }
+
+    pub(crate) fn w1_devices_dir(&self) -> &Path {
+        Path::new(self.w1_devices_dir.as_deref().unwrap_or("/sys/bus/w1/devices"))
+    }
+
+    pub(crate) fn metrics_style(&self) -> MetricsStyle {
+        self.metrics_style.unwrap_or(MetricsStyle::Legacy)
+    }
+
+    pub(crate) fn monitor_sources(&self) -> &[MonitorSource] {
+        self.monitor_sources.as_deref().unwrap_or_default()
+    }
+
+    pub(crate) fn monitors(&self) -> &[Monitor] {
+        self.monitors.as_deref().unwrap_or_default()
+    }
+
+    pub(crate) fn fail_safes(&self) -> &[FailSafe] {
+        self.fail_safes.as_deref().unwrap_or_default()
+    }
+
+    pub(crate) fn history(&self) -> HistoryConfig {
+        self.history.clone().unwrap_or_default()
+    }
+
+    pub(crate) fn sensor_stale_after_millis(&self) -> i64 {
+        duration_millis(self.sensor_stale_after.as_deref(), "5m")
+    }
+
+    pub(crate) fn auth(&self) -> AuthConfig {
+        self.auth.clone().unwrap_or_default()
+    }
+
+    /// Copy of the config that's safe to serve, without token hashes
+    pub(crate) fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if let Some(auth) = config.auth.as_mut() {
+            for token in auth.tokens.iter_mut().flatten() {
+                token.sha256 = "<redacted>".to_string();
+            }
+        }
+        if let Some(mqtt) = config.mqtt.as_mut() {
+            if mqtt.password.is_some() {
+                mqtt.password = Some("<redacted>".to_string());
+            }
+        }
+        config
+    }
+
+    pub(crate) fn monitor_source(&self, name: &str) -> Option<&MonitorSource> {
+        self.monitor_sources().iter().find(|source| source.name == name)
+    }
+
+    /// Check the merged config, returning every problem found with its yaml path
+    pub(crate) fn validate(&self) -> Result<(), GHAError> {
+        let mut problems = Problems::default();
+        let switch_names: Vec<&str> = self
+            .switch_devices
+            .iter()
+            .flatten()
+            .map(|switch_device| switch_device.name.as_str())
+            .collect();
+
+        // Names end up in metric names, urls and mqtt topics, and pins can only have one use
+        let mut pins: Vec<(u32, String)> = Vec::new();
+        if let Some(pin) = self.dht_board_pin {
+            pins.push((pin, "dht_board_pin".to_string()));
+        }
+        let sensor_paths: Vec<String> = (0..self.dht_configs.len())
+            .map(|i| format!("dht_configs[{}]", i))
+            .chain((0..self.sensors.iter().flatten().count()).map(|i| format!("sensors[{}]", i)))
+            .collect();
+        let sensor_configs = self.sensor_configs();
+        let mut rom_ids: Vec<(&str, &str)> = Vec::new();
+        let mut i2c_addresses: Vec<((u8, u16), Option<u8>, &str)> = Vec::new();
+        for (i, sensor_config) in sensor_configs.iter().enumerate() {
+            let path = &sensor_paths[i];
+            let name = sensor_config.name();
+            problems.check_metric_name(path, name);
+            if let Some(j) = sensor_configs[..i].iter().position(|s| s.name() == name) {
+                problems.push(
+                    format!("{}.name", path),
+                    format!("{} is also the name of {}", name, sensor_paths[j]),
+                );
+            }
+            if let SensorConfig::Dht(dht_config) = sensor_config {
+                problems.check_addressing(
+                    path,
+                    dht_config.gpio_pin,
+                    dht_config.gpio_line.as_ref(),
+                    self.gpio_backend(),
+                );
+            }
+            if let Some(pin) = sensor_config.gpio_pin() {
+                pins.push((pin, path.clone()));
+            }
+            if let SensorConfig::Ds18b20(ds18b20_config) = sensor_config {
+                let rom_id = ds18b20_config.rom_id.as_str();
+                let rom_id_path = format!("{}.rom_id", path);
+                if !ds18b20::is_rom_id(rom_id) {
+                    problems.push(
+                        rom_id_path,
+                        format!("{:?} isn't a DS18B20 ROM id like 28-0316a2795aff", rom_id),
+                    );
+                } else if let Some((_, other)) = rom_ids.iter().find(|(r, _)| *r == rom_id) {
+                    problems.push(rom_id_path, format!("{} is also used by {}", rom_id, other));
+                }
+                rom_ids.push((rom_id, path));
+            }
+            // ADS1115 channels share their chip's address
+            let channel = match sensor_config {
+                SensorConfig::Ads1115(ads1115_config) => {
+                    if ads1115_config.channel > 3 {
+                        problems.push(
+                            format!("{}.channel", path),
+                            format!("{} isn't a channel from 0 to 3", ads1115_config.channel),
+                        );
+                    }
+                    if ads1115_config.dry == ads1115_config.wet {
+                        problems.push(
+                            format!("{}.wet", path),
+                            format!("{} is also the dry value", ads1115_config.wet),
+                        );
+                    }
+                    if ads1115_config.samples() == 0 {
+                        problems.push(format!("{}.samples", path), "0 isn't at least 1");
+                    }
+                    Some(ads1115_config.channel)
+                }
+                _ => None,
+            };
+            if let Some((bus, address)) = sensor_config.i2c_address() {
+                let address_path = format!("{}.address", path);
+                if !(0x03..=0x77).contains(&address) {
+                    problems.push(
+                        address_path,
+                        format!("{:#04x} isn't a 7 bit I2C address", address),
+                    );
+                } else if let Some((_, other_channel, other)) =
+                    i2c_addresses.iter().find(|(a, c, _)| {
+                        *a == (bus, address) && (c.is_none() || channel.is_none() || *c == channel)
+                    })
+                {
+                    match (channel, other_channel) {
+                        (Some(channel), Some(_)) => problems.push(
+                            format!("{}.channel", path),
+                            format!("{} is also read by {}", channel, other),
+                        ),
+                        _ => problems.push(
+                            address_path,
+                            format!("{:#04x} on bus {} is also used by {}", address, bus, other),
+                        ),
+                    }
+                }
+                i2c_addresses.push(((bus, address), channel, path));
+            }
+        }
+        let switch_devices = self.switch_devices.as_deref().unwrap_or_default();
+        for (i, switch_device) in switch_devices.iter().enumerate() {
+            let path = format!("switch_devices[{}]", i);
+            problems.check_metric_name(&path, &switch_device.name);
+            if let Some(j) = switch_devices[..i]
+                .iter()
+                .position(|s| s.name == switch_device.name)
+            {
+                problems.push(
+                    format!("{}.name", path),
+                    format!(
+                        "{} is also the name of switch_devices[{}]",
+                        switch_device.name, j
+                    ),
+                );
+            }
+            for (j, schedule) in switch_device.schedules.iter().flatten().enumerate() {
+                if let Err(e) = ScheduleWindows::parse(schedule) {
+                    problems.push(format!("{}.schedules[{}]", path, j), e);
+                }
+            }
+            problems.check_addressing(
+                &path,
+                switch_device.gpio_pin,
+                switch_device.gpio_line.as_ref(),
+                self.gpio_backend(),
+            );
+            pins.push((switch_device.pin(), path));
+        }
+        for (i, (pin, path)) in pins.iter().enumerate() {
+            if let Some((_, other)) = pins[..i].iter().find(|(other_pin, _)| other_pin == pin) {
+                let path = if path == "dht_board_pin" {
+                    path.clone()
+                } else {
+                    format!("{}.gpio_pin", path)
+                };
+                problems.push(path, format!("pin {} is already used by {}", pin, other));
+            }
+        }
+
+        for (i, origin) in self.cors_origins.iter().enumerate() {
+            if let Err(e) = check_origin(origin) {
+                problems.push(format!("cors_origins[{}]", i), e);
+            }
+        }
+
+        for (i, source) in self.monitor_sources().iter().enumerate() {
+            let path = format!("monitor_sources[{}]", i);
+            if let Some(j) = self.monitor_sources()[..i]
+                .iter()
+                .position(|s| s.name == source.name)
+            {
+                problems.push(
+                    format!("{}.name", path),
+                    format!("{} is also the name of monitor_sources[{}]", source.name, j),
+                );
+            }
+            let names_path = match source.aggregate {
+                SourceAggregate::Avg(_) => format!("{}.avg.names", path),
+                SourceAggregate::Min(_) => format!("{}.min.names", path),
+                SourceAggregate::Max(_) => format!("{}.max.names", path),
+            };
+            let metrics = source.aggregate.metrics();
+            if metrics.names.is_empty() {
+                problems.push(names_path.as_str(), "no sensor names");
+            }
+            for (j, name) in metrics.names.iter().enumerate() {
+                let path = format!("{}[{}]", names_path, j);
+                let sensor_config = match sensor_configs.iter().find(|s| s.name() == name) {
+                    Some(sensor_config) => sensor_config,
+                    None => {
+                        problems.push(path, format!("unknown sensor {}", name));
+                        continue;
+                    }
+                };
+                if let Some(sensor_type) = metrics.sensor_type {
+                    if sensor_config.sensor_type() != sensor_type {
+                        problems.push(
+                            path.as_str(),
+                            format!("{} is not a {:?} sensor", name, sensor_type),
+                        );
+                    }
+                }
+                if !sensor_config.measures().contains(&metrics.metric) {
+                    problems.push(
+                        path,
+                        format!("{} doesn't measure {:?}", name, metrics.metric),
+                    );
+                }
+            }
+        }
+
+        for (i, monitor) in self.monitors().iter().enumerate() {
+            let path = format!("monitors[{}]", i);
+            if let Some(j) = self.monitors()[..i].iter().position(|m| m.name == monitor.name) {
+                problems.push(
+                    format!("{}.name", path),
+                    format!("{} is also the name of monitors[{}]", monitor.name, j),
+                );
+            }
+            if self.monitor_source(&monitor.source).is_none() {
+                problems.push(
+                    format!("{}.source", path),
+                    format!("unknown monitor source {}", monitor.source),
+                );
+            }
+            for (j, switch_name) in monitor.switch_devices.iter().enumerate() {
+                let switch_device = self
+                    .switch_devices
+                    .iter()
+                    .flatten()
+                    .find(|switch_device| &switch_device.name == switch_name);
+                match switch_device {
+                    None => problems.push(
+                        format!("{}.switch_devices[{}]", path, j),
+                        format!("unknown switch device {}", switch_name),
+                    ),
+                    // The monitor and the schedules would undo each other's changes
+                    Some(switch_device)
+                        if !switch_device.schedules.as_deref().unwrap_or_default().is_empty() =>
+                    {
+                        problems.push(
+                            format!("{}.switch_devices[{}]", path, j),
+                            format!("{} is also switched by its schedules", switch_name),
+                        )
+                    }
+                    Some(_) => {}
+                }
+            }
+            if monitor.threshold.lower > monitor.threshold.upper {
+                problems.push(
+                    format!("{}.threshold", path),
+                    format!(
+                        "lower {} is greater than upper {}",
+                        monitor.threshold.lower, monitor.threshold.upper
+                    ),
+                );
+            }
+        }
+
+        for (i, fail_safe) in self.fail_safes().iter().enumerate() {
+            let path = format!("fail_safes[{}]", i);
+            problems.check_metric_name(&path, &fail_safe.name);
+            if let Some(j) = self.fail_safes()[..i]
+                .iter()
+                .position(|f| f.name == fail_safe.name)
+            {
+                problems.push(
+                    format!("{}.name", path),
+                    format!("{} is also the name of fail_safes[{}]", fail_safe.name, j),
+                );
+            }
+            if fail_safe.sources.is_empty() {
+                problems.push(format!("{}.sources", path), "no monitor sources");
+            }
+            for (j, source) in fail_safe.sources.iter().enumerate() {
+                if self.monitor_source(source).is_none() {
+                    problems.push(
+                        format!("{}.sources[{}]", path, j),
+                        format!("unknown monitor source {}", source),
+                    );
+                }
+            }
+            for (switch_name, level) in &fail_safe.switch_devices {
+                if !switch_names.contains(&switch_name.as_str()) {
+                    problems.push(
+                        format!("{}.switch_devices.{}", path, switch_name),
+                        format!("unknown switch device {}", switch_name),
+                    );
+                }
+                // Fail-safes tripped together couldn't both hold the switch
+                let other = self.fail_safes()[..i].iter().position(|other| {
+                    let other_level = other.switch_devices.get(switch_name);
+                    other_level.is_some_and(|other_level| other_level != level)
+                });
+                if let Some(j) = other {
+                    problems.push(
+                        format!("{}.switch_devices.{}", path, switch_name),
+                        format!("{} is held at another level by fail_safes[{}]", switch_name, j),
+                    );
+                }
+            }
+            if let Err(e) = parse_duration(&fail_safe.stale_after) {
+                problems.push(format!("{}.stale_after", path), e);
+            }
+        }
+
+        let history = self.history();
+        for (key, value) in [
+            ("raw_retention", &history.raw_retention),
+            ("downsample_step", &history.downsample_step),
+            ("retention", &history.retention),
+        ] {
+            if let Some(Err(e)) = value.as_deref().map(parse_duration) {
+                problems.push(format!("history.{}", key), e);
+            }
+        }
+        if history.raw_retention_millis() > history.retention_millis() {
+            problems.push("history.raw_retention", "longer than history.retention");
+        }
+
+        if let Some(Err(e)) = self.sensor_stale_after.as_deref().map(parse_duration) {
+            problems.push("sensor_stale_after", e);
+        }
+
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            problems.push("tls_cert_path", "must be set together with tls_key_path");
+        }
+        if let Some(redirect_port) = self.tls_redirect_port {
+            if self.tls_paths().is_none() {
+                problems.push("tls_redirect_port", "set without tls_cert_path and tls_key_path");
+            }
+            if Some(redirect_port) == self.listen_port {
+                problems.push(
+                    "tls_redirect_port",
+                    format!("{} is the same as listen_port", redirect_port),
+                );
+            }
+        }
+
+        let tokens = self.auth().tokens.unwrap_or_default();
+        for (i, token) in tokens.iter().enumerate() {
+            let path = format!("auth.tokens[{}]", i);
+            if let Some(j) = tokens[..i].iter().position(|t| t.name == token.name) {
+                problems.push(
+                    format!("{}.name", path),
+                    format!("{} is also the name of auth.tokens[{}]", token.name, j),
+                );
+            }
+            if token.sha256.len() != 64 || hex::decode(&token.sha256).is_err() {
+                problems.push(format!("{}.sha256", path), "must be 64 hex characters");
+            }
+        }
+
+        problems.into_result()
+    }
+
+    /// Character device lines configured for pins, keyed by their `gpio_pin`
+    pub(crate) fn gpio_lines(&self) -> BTreeMap<u32, GpioLine> {
+        let mut lines = BTreeMap::new();
+        for sensor_config in self.sensor_configs() {
+            if let (Some(pin), Some(line)) = (sensor_config.gpio_pin(), sensor_config.gpio_line()) {
+                lines.insert(pin, line.clone());
+            }
+        }
+        for switch_device in self.switch_devices.iter().flatten() {
+            if let Some(line) = &switch_device.gpio_line {
+                lines.insert(switch_device.pin(), line.clone());
+            }
+        }
+        if let (Some(pin), Some(line)) = (self.dht_board_pin, &self.dht_board_line) {
+            lines.insert(pin, line.clone());
+        }
+        lines
+    }
+}
+
+/// What a config reload changed, by sensor and switch device name
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ConfigChanges {
+    pub(crate) sensors_added: Vec<String>,
+    pub(crate) sensors_updated: Vec<String>,
+    pub(crate) sensors_removed: Vec<String>,
+    pub(crate) switches_added: Vec<String>,
+    pub(crate) switches_updated: Vec<String>,
+    pub(crate) switches_removed: Vec<String>,
+    /// Changed settings that keep their old value until the agent restarts
+    pub(crate) restart_required: Vec<String>,
+}
+
+/// Config problems found by `GHAConfig::validate`, each prefixed with its yaml path
+#[derive(Debug, Default)]
+struct Problems(Vec<String>);
+
+impl Problems {
+    fn push(&mut self, path: impl Into<String>, problem: impl Display) {
+        self.0.push(format!("{}: {}", path.into(), problem));
+    }
+
+    /// Names become prometheus metric name prefixes like `<name>_state` with the legacy
+    /// `metrics_style`
+    fn check_metric_name(&mut self, path: &str, name: &str) {
+        let mut chars = name.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            self.push(
+                format!("{}.name", path),
+                format!(
+                    "{:?} must start with a letter or _ and only have letters, digits and _",
+                    name
+                ),
+            );
+        }
+    }
+
+    /// Pins are addressed by either a BCM `gpio_pin` or a character device `gpio_line`
+    fn check_addressing(
+        &mut self,
+        path: &str,
+        gpio_pin: Option<u32>,
+        gpio_line: Option<&GpioLine>,
+        backend: GpioBackendKind,
+    ) {
+        match (gpio_pin, gpio_line) {
+            (Some(_), Some(_)) => self.push(
+                format!("{}.gpio_line", path),
+                "set either gpio_pin or gpio_line, not both",
+            ),
+            (None, None) => self.push(
+                format!("{}.gpio_pin", path),
+                "gpio_pin or gpio_line is required",
+            ),
+            (None, Some(line)) => {
+                if backend != GpioBackendKind::Cdev {
+                    self.push(
+                        format!("{}.gpio_line", path),
+                        "lines need gpio_backend: cdev",
+                    );
+                } else if line.pin_id().is_none() {
+                    self.push(
+                        format!("{}.gpio_line.chip", path),
+                        format!(
+                            "{:?} isn't a gpiochipN name or /dev/gpiochipN path",
+                            line.chip.as_deref().unwrap_or_default()
+                        ),
+                    );
+                }
+            }
+            (Some(_), None) => {}
+        }
+    }
+
+    fn into_result(self) -> Result<(), GHAError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(GHAError::from_string(format!(
+                "Invalid config, {} problem(s):\n  {}",
+                self.0.len(),
+                self.0.join("\n  ")
+            )))
+        }
+    }
+}
+
+/// Check that a cors origin is a scheme and host like `http://localhost:8080`
+fn check_origin(origin: &str) -> Result<(), String> {
+    if origin.is_empty() {
+        return Err("empty origin".to_string());
+    }
+    let (scheme, authority) = origin
+        .split_once("://")
+        .ok_or_else(|| format!("{} is missing a scheme like http://", origin))?;
+    Scheme::try_from(scheme).map_err(|e| format!("{} has an invalid scheme: {}", origin, e))?;
+    if authority.is_empty() {
+        return Err(format!("{} is missing a host", origin));
+    }
+    Authority::try_from(authority).map_err(|_| {
+        format!("{} must be a scheme and host without a path, like http://host:8080", origin)
+    })?;
+    Ok(())
+}
+
+/// Parse durations like `90s`, `10m`, `4h` or `1h30m`
+pub(crate) fn parse_duration(value: &str) -> Result<TimeDelta, String> {
+    let mut seconds: i64 = 0;
+    let mut number = String::new();
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(format!("invalid duration unit {} in {}", c, value)),
+        };
+        let n: i64 = number
+            .parse()
+            .map_err(|_| format!("missing number before {} in {}", c, value))?;
+        seconds = n
+            .checked_mul(unit)
+            .and_then(|n| seconds.checked_add(n))
+            .ok_or_else(|| format!("{} is out of range", value))?;
+        number.clear();
+    }
+    if !number.is_empty() || seconds <= 0 {
+        return Err(format!("{} is not a duration like 90s, 10m or 1h30m", value));
+    }
+    TimeDelta::try_seconds(seconds).ok_or_else(|| format!("{} is out of range", value))
+}
+
+/// Embedded history of sensor readings and switch transitions
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct HistoryConfig {
+    /// Record history, defaults to true
+    pub(crate) enabled: Option<bool>,
+    /// JSON lines file the history is appended to
+    pub(crate) path: Option<String>,
+    /// How long every reading is kept before it's downsampled, defaults to 7d
+    pub(crate) raw_retention: Option<String>,
+    /// Width of the averages older readings are downsampled to, defaults to 10m
+    pub(crate) downsample_step: Option<String>,
+    /// How long downsampled readings are kept, defaults to 365d
+    pub(crate) retention: Option<String>,
+}
+
+impl HistoryConfig {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("gha_history.jsonl")
+    }
+
+    pub(crate) fn raw_retention_millis(&self) -> i64 {
+        duration_millis(self.raw_retention.as_deref(), "7d")
+    }
+
+    pub(crate) fn downsample_step_millis(&self) -> i64 {
+        duration_millis(self.downsample_step.as_deref(), "10m")
+    }
+
+    pub(crate) fn retention_millis(&self) -> i64 {
+        duration_millis(self.retention.as_deref(), "365d")
+    }
+}
+
+/// Bearer token auth for the http routes. Routes are open to anyone when no tokens are
+/// configured.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct AuthConfig {
+    pub(crate) tokens: Option<Vec<ApiToken>>,
+    /// Serve /metrics without a token so Prometheus can scrape it, defaults to false
+    pub(crate) public_metrics: Option<bool>,
+}
+
+impl AuthConfig {
+    pub(crate) fn is_public_metrics(&self) -> bool {
+        self.public_metrics.unwrap_or(false)
+    }
+
+    pub(crate) fn has_tokens(&self) -> bool {
+        !self.tokens.as_deref().unwrap_or_default().is_empty()
+    }
+}
+
+/// An api token, only the hex sha256 of the token is kept in the config
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ApiToken {
+    pub(crate) name: String,
+    pub(crate) role: Role,
+    pub(crate) sha256: String,
+}
+
+/// What a token may do, an operator can also do everything read_only can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Role {
+    /// Metrics, config, state, history and the live stream
+    ReadOnly,
+    /// Pin writes and override flags
+    Operator,
+}
+
+/// MQTT broker that readings and switch states are published to, with Home Assistant
+/// discovery
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MqttConfig {
+    pub(crate) host: String,
+    /// Defaults to 1883
+    pub(crate) port: Option<u16>,
+    /// Defaults to greenhouse-agent, also used as the Home Assistant device id
+    pub(crate) client_id: Option<String>,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    /// Prefix of the state and command topics, defaults to greenhouse
+    pub(crate) topic_prefix: Option<String>,
+    /// Publish Home Assistant discovery payloads, defaults to true
+    pub(crate) discovery: Option<bool>,
+    /// Defaults to homeassistant
+    pub(crate) discovery_prefix: Option<String>,
+    /// Take switch commands from the command topics. The broker's access control is the
+    /// only check on them, so this defaults to false when api tokens are configured.
+    pub(crate) commands: Option<bool>,
+}
+
+impl MqttConfig {
+    pub(crate) fn port(&self) -> u16 {
+        self.port.unwrap_or(1883)
+    }
+
+    pub(crate) fn client_id(&self) -> &str {
+        self.client_id.as_deref().unwrap_or("greenhouse-agent")
+    }
+
+    pub(crate) fn topic_prefix(&self) -> &str {
+        self.topic_prefix.as_deref().unwrap_or("greenhouse")
+    }
+
+    pub(crate) fn is_discovery(&self) -> bool {
+        self.discovery.unwrap_or(true)
+    }
+
+    pub(crate) fn discovery_prefix(&self) -> &str {
+        self.discovery_prefix.as_deref().unwrap_or("homeassistant")
+    }
+
+    /// Whether commands are taken, which follows the http api being open unless it's set
+    pub(crate) fn is_commands(&self, auth: &AuthConfig) -> bool {
+        self.commands.unwrap_or(!auth.has_tokens())
+    }
+}
+
+/// Milliseconds of a configured duration, falling back to the default when it's unset or
+/// invalid. Invalid values are reported by `validate`.
+fn duration_millis(value: Option<&str>, default: &str) -> i64 {
+    value
+        .and_then(|value| parse_duration(value).ok())
+        .or_else(|| parse_duration(default).ok())
+        .map(|duration| duration.num_milliseconds())
+        .unwrap_or_default()
+}
+
+/// GPIO implementation used for sensor and switch pins
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum GpioBackendKind {
+    /// Raspberry Pi GPIO through rppal
+    Rppal,
+    /// In-memory pins for running off a Raspberry Pi
+    Simulated,
+    /// Linux GPIO character device (/dev/gpiochipN) lines
+    Cdev,
+}
+
+/// How sensor, switch and fail-safe values are named in `/metrics`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MetricsStyle {
+    /// `greenhouse_*` families labeled with the sensor or switch, like
+    /// `greenhouse_temperature_celsius{sensor="outside",pin="17"}`
+    Labeled,
+    /// A gauge per sensor and switch named after it, like `outside_c` and `fan_state`
+    Legacy,
+}
+
+/// A line on a Linux GPIO character device, set instead of a `gpio_pin`.
+///
+/// Pins without a `gpio_line` use the line at offset `gpio_pin` on `gpio_chip`. Devices
+/// addressed by a line are numbered `1000 * N + offset` for lines on gpiochipN, or
+/// `offset` for lines on `gpio_chip`, in routes and state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct GpioLine {
+    /// Chip name (gpiochip1) or path (/dev/gpiochip1), defaults to `gpio_chip`
+    pub(crate) chip: Option<String>,
+    pub(crate) offset: u32,
+}
+
+impl GpioLine {
+    /// Number of the pin in routes and state, None when `chip` isn't a gpiochipN name
+    pub(crate) fn pin_id(&self) -> Option<u32> {
+        match &self.chip {
+            None => Some(self.offset),
+            Some(chip) => {
+                let chip_number = chip
+                    .strip_prefix("/dev/")
+                    .unwrap_or(chip)
+                    .strip_prefix("gpiochip")?
+                    .parse::<u32>()
+                    .ok()?;
+                Some(1000 * chip_number + self.offset)
+            }
+        }
+    }
+}
+
+/// Pin number of a device addressed by either `gpio_pin` or `gpio_line`
+fn pin_number(gpio_pin: Option<u32>, gpio_line: Option<&GpioLine>) -> u32 {
+    gpio_pin
+        .or_else(|| gpio_line.and_then(GpioLine::pin_id))
+        .unwrap_or_default()
+}
+
+/// A sensor, the `type` selects the kind of sensor and its settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum SensorConfig {
+    /// DHT sensor on a GPIO data pin, powered by the dht board when `dht_board_pin` is set
+    Dht(DhtConfig),
+    /// DS18B20 1-Wire temperature probe read through the kernel's w1 driver
+    Ds18b20(Ds18b20Config),
+    /// Bosch BME280 temperature, humidity and pressure sensor on an I2C bus
+    Bme280(I2cSensorConfig),
+    /// Bosch BMP280, the BME280 without humidity
+    Bmp280(I2cSensorConfig),
+    /// Sensirion SHT31 temperature and humidity sensor on an I2C bus
+    Sht31(I2cSensorConfig),
+    /// Soil moisture probe on a channel of a TI ADS1115 ADC on an I2C bus
+    Ads1115(Ads1115Config),
+}
+
+impl SensorConfig {
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            SensorConfig::Dht(dht_config) => &dht_config.name,
+            SensorConfig::Ds18b20(ds18b20_config) => &ds18b20_config.name,
+            SensorConfig::Bme280(i2c_config)
+            | SensorConfig::Bmp280(i2c_config)
+            | SensorConfig::Sht31(i2c_config) => &i2c_config.name,
+            SensorConfig::Ads1115(ads1115_config) => &ads1115_config.name,
+        }
+    }
+
+    pub(crate) fn sensor_type(&self) -> SensorType {
+        match self {
+            SensorConfig::Dht(_) => SensorType::Dht,
+            SensorConfig::Ds18b20(_) => SensorType::Ds18b20,
+            SensorConfig::Bme280(_) => SensorType::Bme280,
+            SensorConfig::Bmp280(_) => SensorType::Bmp280,
+            SensorConfig::Sht31(_) => SensorType::Sht31,
+            SensorConfig::Ads1115(_) => SensorType::Ads1115,
+        }
+    }
+
+    /// GPIO pin the sensor is wired to, None for sensors on a bus
+    pub(crate) fn gpio_pin(&self) -> Option<u32> {
+        match self {
+            SensorConfig::Dht(dht_config) => Some(dht_config.pin()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn gpio_line(&self) -> Option<&GpioLine> {
+        match self {
+            SensorConfig::Dht(dht_config) => dht_config.gpio_line.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// I2C bus and address of the sensor, None for sensors that aren't on an I2C bus
+    pub(crate) fn i2c_address(&self) -> Option<(u8, u16)> {
+        match self {
+            SensorConfig::Bme280(i2c_config) | SensorConfig::Bmp280(i2c_config) => {
+                Some((i2c_config.bus(), i2c_config.address.unwrap_or(0x76)))
+            }
+            SensorConfig::Sht31(i2c_config) => {
+                Some((i2c_config.bus(), i2c_config.address.unwrap_or(0x44)))
+            }
+            SensorConfig::Ads1115(ads1115_config) => {
+                Some((ads1115_config.bus(), ads1115_config.address()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Metrics of each good reading
+    pub(crate) fn measures(&self) -> &'static [SensorMetric] {
+        match self {
+            SensorConfig::Dht(_) => {
+                &[SensorMetric::TempC, SensorMetric::TempF, SensorMetric::Humidity]
+            }
+            SensorConfig::Ds18b20(_) => &[SensorMetric::TempC, SensorMetric::TempF],
+            SensorConfig::Bme280(_) => &[
+                SensorMetric::TempC,
+                SensorMetric::TempF,
+                SensorMetric::Humidity,
+                SensorMetric::Pressure,
+            ],
+            SensorConfig::Bmp280(_) => {
+                &[SensorMetric::TempC, SensorMetric::TempF, SensorMetric::Pressure]
+            }
+            SensorConfig::Sht31(_) => {
+                &[SensorMetric::TempC, SensorMetric::TempF, SensorMetric::Humidity]
+            }
+            SensorConfig::Ads1115(_) => &[SensorMetric::Moisture],
+        }
+    }
+
+    /// Powered by the dht board, which is restarted when the sensor stops reporting
+    pub(crate) fn is_on_dht_board(&self) -> bool {
+        matches!(self, SensorConfig::Dht(_))
+    }
+
+    /// Added to the temperature read, in degrees celsius
+    pub(crate) fn temp_offset(&self) -> Option<f64> {
+        match self {
+            SensorConfig::Dht(dht_config) => dht_config.temp_offset,
+            SensorConfig::Ds18b20(ds18b20_config) => ds18b20_config.temp_offset,
+            SensorConfig::Bme280(i2c_config)
+            | SensorConfig::Bmp280(i2c_config)
+            | SensorConfig::Sht31(i2c_config) => i2c_config.temp_offset,
+            SensorConfig::Ads1115(_) => None,
+        }
+    }
+
+    /// Added to the relative humidity read
+    pub(crate) fn humidity_offset(&self) -> Option<f64> {
+        match self {
+            SensorConfig::Dht(dht_config) => dht_config.humidity_offset,
+            SensorConfig::Bme280(i2c_config) | SensorConfig::Sht31(i2c_config) => {
+                i2c_config.humidity_offset
+            }
+            _ => None,
+        }
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct DhtConfig {
-    pub(crate) gpio_pin: u32,
+    /// BCM pin number, or set `gpio_line` instead
+    pub(crate) gpio_pin: Option<u32>,
     pub(crate) name: String,
     pub(crate) temp_offset: Option<f64>,
     pub(crate) humidity_offset: Option<f64>,
+    pub(crate) gpio_line: Option<GpioLine>,
+    /// Defaults to dht22
+    pub(crate) model: Option<DhtModel>,
+}
+
+impl DhtConfig {
+    pub(crate) fn pin(&self) -> u32 {
+        pin_number(self.gpio_pin, self.gpio_line.as_ref())
+    }
+
+    pub(crate) fn model(&self) -> DhtModel {
+        self.model.unwrap_or(DhtModel::Dht22)
+    }
+}
+
+/// The kind of DHT sensor, which sets its decoding and value bounds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DhtModel {
+    /// Integral and decimal bytes rather than tenths, 0 to 50°C
+    Dht11,
+    /// Also sold as the AM2301
+    Dht21,
+    Dht22,
+    /// The DHT22 in a case with wires
+    Am2302,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Ds18b20Config {
+    pub(crate) name: String,
+    /// Name of the probe's directory in `w1_devices_dir`, like 28-0316a2795aff
+    pub(crate) rom_id: String,
+    pub(crate) temp_offset: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct I2cSensorConfig {
+    pub(crate) name: String,
+    /// Number of the /dev/i2c-N bus, defaults to 1
+    pub(crate) bus: Option<u8>,
+    /// Defaults to 0x76 for the BME280 and BMP280 and 0x44 for the SHT31
+    pub(crate) address: Option<u16>,
+    pub(crate) temp_offset: Option<f64>,
+    pub(crate) humidity_offset: Option<f64>,
+}
+
+impl I2cSensorConfig {
+    pub(crate) fn bus(&self) -> u8 {
+        self.bus.unwrap_or(1)
+    }
+}
+
+/// A channel of an ADS1115, which converts its four channels one at a time so several
+/// sensors can share its address
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Ads1115Config {
+    pub(crate) name: String,
+    /// Number of the /dev/i2c-N bus, defaults to 1
+    pub(crate) bus: Option<u8>,
+    /// Defaults to 0x48, the ADDR pin to ground
+    pub(crate) address: Option<u16>,
+    /// Input AIN0 to AIN3
+    pub(crate) channel: u8,
+    /// Raw value of the probe in dry soil or air, read as 0%
+    pub(crate) dry: i16,
+    /// Raw value of the probe in water or soaked soil, read as 100%
+    pub(crate) wet: i16,
+    /// Conversions averaged for each reading, defaults to 1
+    pub(crate) samples: Option<u16>,
+}
+
+impl Ads1115Config {
+    pub(crate) fn bus(&self) -> u8 {
+        self.bus.unwrap_or(1)
+    }
+
+    pub(crate) fn address(&self) -> u16 {
+        self.address.unwrap_or(0x48)
+    }
+
+    pub(crate) fn samples(&self) -> u16 {
+        self.samples.unwrap_or(1)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct SwitchDevice {
-    pub(crate) gpio_pin: u32,
+    /// BCM pin number, or set `gpio_line` instead
+    pub(crate) gpio_pin: Option<u32>,
     pub(crate) name: String,
     pub(crate) auto: Option<bool>,
+    pub(crate) gpio_line: Option<GpioLine>,
+    pub(crate) schedules: Option<Vec<Schedule>>,
+    pub(crate) boot_state: Option<BootState>,
+    /// Level the switch device is driven to on shutdown, left as is when not set
+    pub(crate) safe_state: Option<SwitchLevel>,
+}
+
+impl SwitchDevice {
+    pub(crate) fn pin(&self) -> u32 {
+        pin_number(self.gpio_pin, self.gpio_line.as_ref())
+    }
+}
+
+/// Level a switch device is driven to when the agent starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum BootState {
+    /// The last commanded level saved in `state_file`, if any
+    Restore,
+    Off,
+    On,
+}
+
+/// Time windows during which an auto switch device is turned on.
+///
+/// Times are local, start times have minute resolution and durations are written like
+/// `90s`, `10m` or `1h30m`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Schedule {
+    // Flattened so the kind is written as a plain `daily:` key rather than a YAML tag
+    #[serde(flatten)]
+    pub(crate) window: ScheduleWindow,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ScheduleWindow {
+    /// On every day from `start` until `end`, as `HH:MM`
+    Daily { start: String, end: String },
+    /// On for `duration` every `every`, counted from midnight
+    Interval { every: String, duration: String },
+    /// On for `duration` at each minute matching a 5 field cron expression
+    Cron { expression: String, duration: String },
+}
+
+/// A named value computed from sensor gauges that monitors compare to thresholds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MonitorSource {
+    pub(crate) name: String,
+    #[serde(flatten)]
+    pub(crate) aggregate: SourceAggregate,
+}
+
+/// How the metrics of a monitor source's sensors are combined
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SourceAggregate {
+    Avg(SourceMetrics),
+    Min(SourceMetrics),
+    Max(SourceMetrics),
+}
+
+impl SourceAggregate {
+    pub(crate) fn metrics(&self) -> &SourceMetrics {
+        match self {
+            SourceAggregate::Avg(metrics) => metrics,
+            SourceAggregate::Min(metrics) => metrics,
+            SourceAggregate::Max(metrics) => metrics,
+        }
+    }
+
+    /// Combine the values read for each sensor, None if there are no values
+    pub(crate) fn apply(&self, values: &[f64]) -> Option<f64> {
+        if values.is_empty() {
+            return None;
+        }
+        let value = match self {
+            SourceAggregate::Avg(_) => values.iter().sum::<f64>() / values.len() as f64,
+            SourceAggregate::Min(_) => values.iter().copied().fold(f64::INFINITY, f64::min),
+            SourceAggregate::Max(_) => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        };
+        Some(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SourceMetrics {
+    /// Only sensors of this type, any sensor measuring `metric` when not set
+    #[serde(rename = "type")]
+    pub(crate) sensor_type: Option<SensorType>,
+    pub(crate) metric: SensorMetric,
+    pub(crate) names: Vec<String>,
+}
+
+/// The `type` of a sensor in `sensors`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SensorType {
+    Dht,
+    Ds18b20,
+    Bme280,
+    Bmp280,
+    Sht31,
+    Ads1115,
+}
+
+/// A value sensors measure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SensorMetric {
+    TempC,
+    TempF,
+    Humidity,
+    /// Barometric pressure in hectopascals
+    Pressure,
+    /// Soil moisture in percent, from dry at 0 to wet at 100
+    Moisture,
+    /// Illuminance in lux
+    Light,
+    /// CO2 concentration in parts per million
+    Co2,
+}
+
+impl SensorMetric {
+    pub(crate) const ALL: [SensorMetric; 7] = [
+        SensorMetric::TempC,
+        SensorMetric::TempF,
+        SensorMetric::Humidity,
+        SensorMetric::Pressure,
+        SensorMetric::Moisture,
+        SensorMetric::Light,
+        SensorMetric::Co2,
+    ];
+
+    /// Name in readings, history and mqtt payloads
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            SensorMetric::TempC => "temp_c",
+            SensorMetric::TempF => "temp_f",
+            SensorMetric::Humidity => "humidity",
+            SensorMetric::Pressure => "pressure",
+            SensorMetric::Moisture => "moisture",
+            SensorMetric::Light => "light",
+            SensorMetric::Co2 => "co2",
+        }
+    }
+}
+
+/// Turns switch devices on while a monitor source is past its threshold
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Monitor {
+    pub(crate) name: String,
+    pub(crate) source: String,
+    pub(crate) switch_devices: Vec<String>,
+    pub(crate) threshold: Threshold,
+}
+
+/// Forces switch devices to safe levels while any sensor of its sources has gone without a
+/// good reading for longer than `stale_after`. Monitors leave those switches alone until
+/// the fail-safe clears.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct FailSafe {
+    pub(crate) name: String,
+    /// Monitor sources whose sensors are checked
+    pub(crate) sources: Vec<String>,
+    pub(crate) stale_after: String,
+    /// Level each switch device is held at while the fail-safe is active
+    pub(crate) switch_devices: BTreeMap<String, SwitchLevel>,
+}
+
+impl FailSafe {
+    pub(crate) fn stale_after_secs(&self) -> f64 {
+        duration_millis(Some(&self.stale_after), "10m") as f64 / 1000.0
+    }
+
+    /// Whether the fail-safe is active given the seconds since each sensor's last good
+    /// reading
+    pub(crate) fn is_tripped(&self, seconds_since_good_readings: &[f64]) -> bool {
+        let stale_after_secs = self.stale_after_secs();
+        seconds_since_good_readings
+            .iter()
+            .any(|&seconds| seconds > stale_after_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SwitchLevel {
+    On,
+    Off,
+}
+
+/// Hysteresis band for a monitor.
+///
+/// With direction `upper` the monitor becomes active above `upper` and stays active
+/// until the value drops to `lower`. With direction `lower` it becomes active at or
+/// below `lower` and stays active until the value rises to `upper`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Threshold {
+    pub(crate) direction: ThresholdDirection,
+    pub(crate) upper: f64,
+    pub(crate) lower: f64,
+}
+
+impl Threshold {
+    /// Whether the monitor is active for `value`, given whether it was active before
+    pub(crate) fn is_active(&self, was_active: bool, value: f64) -> bool {
+        match self.direction {
+            ThresholdDirection::Upper => {
+                if value > self.upper {
+                    true
+                } else if value <= self.lower {
+                    false
+                } else {
+                    was_active
+                }
+            }
+            ThresholdDirection::Lower => {
+                if value <= self.lower {
+                    true
+                } else if value >= self.upper {
+                    false
+                } else {
+                    was_active
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ThresholdDirection {
+    Upper,
+    Lower,
 }
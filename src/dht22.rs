@@ -1,11 +1,16 @@
 use std::fmt::{Debug, Formatter};
+use std::ops::RangeInclusive;
 use std::thread;
 use std::time::Duration;
 
 use log::debug;
 use rppal::gpio::Mode;
 
-use crate::sensor::{DataPin, Humidity, SensorError, SensorErrorKind, TemperatureCelsius};
+use crate::config::DhtModel;
+use crate::sensor::{
+    DataPin, GpioBackend, Humidity, Measurement, Sensor, SensorError, SensorErrorKind,
+    TemperatureCelsius,
+};
 
 pub(crate) const DHT_MAX_COUNT: u32 = 32_000;
 // 800;
@@ -47,7 +52,7 @@ impl Pulses {
         for i in (0..counts.len()).step_by(2) {
             while pin.is_low() {
                 counts[i] += 1;
-                if counts[i] >= DHT_MAX_COUNT as u32 {
+                if counts[i] >= DHT_MAX_COUNT {
                     return Err(SensorError::KindMsg(
                         SensorErrorKind::ReadTimeout,
                         "timeout waiting for low pulse capture",
@@ -57,7 +62,7 @@ impl Pulses {
 
             while pin.is_high() {
                 counts[i + 1] += 1;
-                if counts[i + 1] >= DHT_MAX_COUNT as u32 {
+                if counts[i + 1] >= DHT_MAX_COUNT {
                     return Err(SensorError::KindMsg(
                         SensorErrorKind::ReadTimeout,
                         "timeout waiting for high pulse capture",
@@ -150,25 +155,41 @@ impl Reading {
     }
 }
 
-impl From<Reading> for (TemperatureCelsius, Humidity) {
-    /// Convert a `Reading` sensor reading into temperature and humidity measurements.
+impl Reading {
+    /// Convert the bytes into temperature and humidity measurements, as sent by `model`.
     ///
     /// This conversion is guaranteed to succeed because the checksum enforced during creation
     /// of instances of `Reading` ensures the bytes read from the sensor are valid.
-    fn from(reading: Reading) -> Self {
-        // See https://cdn-shop.adafruit.com/datasheets/Digital+humidity+and+temperature+sensor+AM2302.pdf
-        // first two bytes are humidity as a u16 * 10
-        let humidity_raw =
-            (reading.bytes[0] as u16) * 256 /* shift left 8 bits */ + reading.bytes[1] as u16;
-        // second two bytes are temperature as a u16 * 10 with the highest bit indicating sign
-        let temp_raw = ((reading.bytes[2] & 0b0111_1111) as u16) * 256 /* shift left 8 bits */ + reading.bytes[3] as u16;
-
-        let humidity_dec = humidity_raw as f64 / 10.0;
-        let mut temp_dec = temp_raw as f64 / 10.0;
-        // highest bit of the temperature is `1` to indicate a negative value
-        if reading.bytes[2] & 0b1000_0000 > 0 {
-            temp_dec = -temp_dec;
-        }
+    fn decode(&self, model: DhtModel) -> (TemperatureCelsius, Humidity) {
+        let (temp_dec, humidity_dec) = match model {
+            // Integral and decimal bytes, the decimal temperature byte has the sign in its
+            // highest bit
+            DhtModel::Dht11 => {
+                let humidity_dec = self.bytes[0] as f64 + self.bytes[1] as f64 / 10.0;
+                let mut temp_dec =
+                    self.bytes[2] as f64 + (self.bytes[3] & 0b0000_1111) as f64 / 10.0;
+                if self.bytes[3] & 0b1000_0000 > 0 {
+                    temp_dec = -temp_dec;
+                }
+                (temp_dec, humidity_dec)
+            }
+            // See https://cdn-shop.adafruit.com/datasheets/Digital+humidity+and+temperature+sensor+AM2302.pdf
+            DhtModel::Dht21 | DhtModel::Dht22 | DhtModel::Am2302 => {
+                // first two bytes are humidity as a u16 * 10
+                let humidity_raw =
+                    (self.bytes[0] as u16) * 256 /* shift left 8 bits */ + self.bytes[1] as u16;
+                // second two bytes are temperature as a u16 * 10 with the highest bit indicating sign
+                let temp_raw = ((self.bytes[2] & 0b0111_1111) as u16) * 256 /* shift left 8 bits */ + self.bytes[3] as u16;
+
+                let humidity_dec = humidity_raw as f64 / 10.0;
+                let mut temp_dec = temp_raw as f64 / 10.0;
+                // highest bit of the temperature is `1` to indicate a negative value
+                if self.bytes[2] & 0b1000_0000 > 0 {
+                    temp_dec = -temp_dec;
+                }
+                (temp_dec, humidity_dec)
+            }
+        };
 
         let humidity = Humidity::from(humidity_dec);
         let temperature = TemperatureCelsius::from(temp_dec);
@@ -177,24 +198,38 @@ impl From<Reading> for (TemperatureCelsius, Humidity) {
     }
 }
 
-/// Read temperature in degrees celsius and relative humidity from a DHT22 sensor
+/// Temperatures the model can measure, readings outside of them are corrupt
+fn temp_c_bounds(model: DhtModel) -> RangeInclusive<f64> {
+    match model {
+        DhtModel::Dht11 => 0.0..=50.0,
+        DhtModel::Dht21 | DhtModel::Dht22 | DhtModel::Am2302 => -40.0..=80.0,
+    }
+}
+
+/// Read temperature in degrees celsius and relative humidity from a DHT22 sensor, or one
+/// of the other DHT models
 pub struct DHT22Sensor {
     pin: Box<dyn DataPin + Send + Sync + 'static>,
+    model: DhtModel,
 }
 
 impl DHT22Sensor {
-    pub fn from_pin<T>(pin: T) -> Self
+    pub fn from_pin<T>(pin: T, model: DhtModel) -> Self
     where
         T: DataPin + Send + Sync + 'static,
     {
-        Self { pin: Box::new(pin) }
+        Self {
+            pin: Box::new(pin),
+            model,
+        }
     }
 
     fn prepare_for_read(&mut self) {
         // https://cdn-shop.adafruit.com/datasheets/Digital+humidity+and+temperature+sensor+AM2302.pdf
         // Host needs to set the sensor:
         // * high to start the read process, waking the sensor up from low-power mode
-        // * low for at least 1ms to ensure the sensor detected the start of this process
+        // * low for at least 1ms, 18ms for the DHT11, to ensure the sensor detected the start
+        //   of this process
         // * high for 20-40us to then wait for the sensor's response
         self.pin.set_mode(Mode::Output);
         self.pin.set_high();
@@ -213,29 +248,211 @@ impl DHT22Sensor {
         let pulses = Pulses::from_data_pin(self.pin.as_ref())?;
         debug!("pulses: {:?}", &pulses.counts[..]);
         let data = Reading::from_pulses(&pulses)?;
-        let (temp, hum): (TemperatureCelsius, Humidity) = data.into();
+        let (temp, hum) = data.decode(self.model);
         let temp_c = f64::from(temp);
-        if temp_c > 80.0 || temp_c < -80.0 {
+        let humidity = f64::from(hum);
+        if !temp_c_bounds(self.model).contains(&temp_c) {
             Err(SensorError::ValueBounds(format!(
                 "temp_c {} is out of bounds",
                 temp_c
             )))
+        } else if !(0.0..=100.0).contains(&humidity) {
+            Err(SensorError::ValueBounds(format!(
+                "humidity {} is out of bounds",
+                humidity
+            )))
         } else {
             Ok((temp, hum))
         }
     }
 }
 
+impl Sensor for DHT22Sensor {
+    fn measure(&mut self) -> Result<Vec<Measurement>, SensorError> {
+        let (temp_c, humidity) = self.read()?;
+        Ok(vec![
+            Measurement::Temperature(temp_c),
+            Measurement::Humidity(humidity),
+        ])
+    }
+}
+
+/// Open `pin` on `gpio` and read the DHT `model` on it once
+pub(crate) fn read_pin(
+    gpio: &dyn GpioBackend,
+    pin: u32,
+    model: DhtModel,
+) -> Result<(TemperatureCelsius, Humidity), SensorError> {
+    let mut sensor = DHT22Sensor::from_pin(gpio.open_pin(pin, Mode::Input)?, model);
+    sensor.read()
+}
+
 impl Debug for DHT22Sensor {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DHT22Sensor")
             .field("pin", &self.pin.pin())
+            .field("model", &self.model)
             .finish()
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_cast)]
 mod test {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use rppal::gpio::Mode;
+
+    use crate::config::DhtModel;
+    use crate::dht22::DHT22Sensor;
+    use crate::sensor::{DataPin, SensorError};
+
+    /// Replays the pulses of a sensor response once the host releases the pin, and records
+    /// how long the host held it low
+    #[derive(Debug)]
+    struct FakePin {
+        /// Levels the pin reads, true for high, one per cycle the driver counts
+        levels: Vec<bool>,
+        position: AtomicUsize,
+        low_since: Option<Instant>,
+        start_signal: Arc<Mutex<Option<Duration>>>,
+    }
+
+    impl FakePin {
+        /// A response sending `bytes`, 0 bits are short high pulses and 1 bits long ones
+        fn sending(bytes: [u8; 5]) -> Self {
+            let mut pulses = vec![(80, 80)];
+            for byte in bytes {
+                for bit in (0..8).rev() {
+                    let high = if byte & (1 << bit) > 0 { 70 } else { 26 };
+                    pulses.push((50, high));
+                }
+            }
+            Self::from_pulses(&pulses)
+        }
+
+        /// Low and high cycle counts of each pulse
+        fn from_pulses(pulses: &[(usize, usize)]) -> Self {
+            let mut levels = Vec::new();
+            for &(low, high) in pulses {
+                levels.extend(std::iter::repeat_n(false, low));
+                levels.extend(std::iter::repeat_n(true, high));
+            }
+            Self {
+                levels,
+                position: AtomicUsize::new(0),
+                low_since: None,
+                start_signal: Arc::new(Mutex::new(None)),
+            }
+        }
+
+        /// Read the level, moving to the next cycle while it's `level`. The line stays low
+        /// after the last pulse.
+        fn read(&self, level: bool) -> bool {
+            let position = self.position.load(Relaxed);
+            let current = self.levels.get(position).copied().unwrap_or(false);
+            if current == level && position < self.levels.len() {
+                self.position.store(position + 1, Relaxed);
+            }
+            current == level
+        }
+    }
+
+    impl DataPin for FakePin {
+        fn is_low(&self) -> bool {
+            self.read(false)
+        }
+
+        fn is_high(&self) -> bool {
+            self.read(true)
+        }
+
+        fn pin(&self) -> u32 {
+            17
+        }
+
+        fn set_high(&mut self) {
+            if let Some(low_since) = self.low_since.take() {
+                *self.start_signal.lock().unwrap() = Some(low_since.elapsed());
+            }
+        }
+
+        fn set_low(&mut self) {
+            self.low_since = Some(Instant::now());
+        }
+
+        fn set_mode(&mut self, _mode: Mode) {}
+    }
+
+    fn with_checksum(bytes: [u8; 4]) -> [u8; 5] {
+        let sum = bytes.iter().map(|&b| b as u16).sum::<u16>();
+        [bytes[0], bytes[1], bytes[2], bytes[3], (sum & 0xFF) as u8]
+    }
+
+    fn read(model: DhtModel, pin: FakePin) -> Result<(f64, f64), SensorError> {
+        let mut sensor = DHT22Sensor::from_pin(pin, model);
+        sensor
+            .read()
+            .map(|(temp_c, humidity)| (f64::from(temp_c), f64::from(humidity)))
+    }
+
+    #[test]
+    fn test_dht22_decode() {
+        // 65.2% and 35.1°C in tenths
+        let pin = FakePin::sending(with_checksum([0x02, 0x8C, 0x01, 0x5F]));
+        let start_signal = pin.start_signal.clone();
+        assert_eq!(read(DhtModel::Dht22, pin).unwrap(), (35.1, 65.2));
+        assert!(start_signal.lock().unwrap().unwrap() >= Duration::from_millis(18));
+
+        // The sign bit makes it -10.1°C
+        let pin = FakePin::sending(with_checksum([0x02, 0x8C, 0x80, 0x65]));
+        assert_eq!(read(DhtModel::Am2302, pin).unwrap(), (-10.1, 65.2));
+        let pin = FakePin::sending(with_checksum([0x02, 0x8C, 0x80, 0x65]));
+        assert_eq!(read(DhtModel::Dht21, pin).unwrap(), (-10.1, 65.2));
+
+        // 90.0°C is past what a DHT22 measures
+        let pin = FakePin::sending(with_checksum([0x02, 0x8C, 0x03, 0x84]));
+        assert!(matches!(read(DhtModel::Dht22, pin), Err(SensorError::ValueBounds(_))));
+    }
+
+    #[test]
+    fn test_dht11_decode() {
+        // 45% and 23.4°C as integral and decimal bytes
+        let pin = FakePin::sending(with_checksum([45, 0, 23, 4]));
+        let start_signal = pin.start_signal.clone();
+        assert_eq!(read(DhtModel::Dht11, pin).unwrap(), (23.4, 45.0));
+        assert!(start_signal.lock().unwrap().unwrap() >= Duration::from_millis(18));
+
+        // Read as a DHT22 the same bytes are 589.2°C
+        let pin = FakePin::sending(with_checksum([45, 0, 23, 4]));
+        assert!(matches!(read(DhtModel::Dht22, pin), Err(SensorError::ValueBounds(_))));
+
+        // 60°C is past what a DHT11 measures
+        let pin = FakePin::sending(with_checksum([40, 0, 60, 0]));
+        assert!(matches!(read(DhtModel::Dht11, pin), Err(SensorError::ValueBounds(_))));
+    }
+
+    #[test]
+    fn test_dht_read_errors() {
+        let mut bytes = with_checksum([0x02, 0x8C, 0x01, 0x5F]);
+        bytes[4] ^= 0x01;
+        let pin = FakePin::sending(bytes);
+        assert!(matches!(
+            read(DhtModel::Dht22, pin),
+            Err(SensorError::CheckSum(0xEF, 0xEE))
+        ));
+
+        // The response stops after 10 bits
+        let pin = FakePin::from_pulses(&[(80, 80); 11]);
+        assert!(matches!(
+            read(DhtModel::Dht22, pin),
+            Err(SensorError::KindMsg(_, "timeout waiting for low pulse capture"))
+        ));
+    }
+
     #[test]
     fn test_loop_count_thing() {
         let mut b: u8 = 0;
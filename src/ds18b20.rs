@@ -0,0 +1,237 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::sensor::{Measurement, Sensor, SensorError, SensorErrorKind, TemperatureCelsius};
+
+/// Directory name prefix of DS18B20 probes, their 1-Wire family code
+const FAMILY_PREFIX: &str = "28-";
+
+/// What a probe reports before its first conversion after power on, in millidegrees
+const POWER_ON_MILLIDEGREES: i64 = 85_000;
+
+/// Read the temperature from a DS18B20 probe through the sysfs files of the kernel's
+/// w1_therm driver
+#[derive(Debug)]
+pub(crate) struct DS18B20Sensor {
+    device_dir: PathBuf,
+}
+
+impl DS18B20Sensor {
+    /// Open the probe with `rom_id` in `devices_dir`, usually `/sys/bus/w1/devices`
+    pub(crate) fn open(devices_dir: &Path, rom_id: &str) -> Result<Self, SensorError> {
+        let device_dir = devices_dir.join(rom_id);
+        if !device_dir.is_dir() {
+            let found = discover(devices_dir);
+            let msg = format!(
+                "no {} in {}, found [{}]",
+                rom_id,
+                devices_dir.display(),
+                found.join(", ")
+            );
+            return Err(SensorError::KindMsgCause(
+                SensorErrorKind::Initialization,
+                "DS18B20 probe not found",
+                Box::new(io::Error::new(io::ErrorKind::NotFound, msg)),
+            ));
+        }
+        Ok(Self { device_dir })
+    }
+
+    /// Read the temperature from `w1_slave`, which takes about 750ms while the probe
+    /// converts. Kernels that only have the `temperature` file check the CRC themselves.
+    pub(crate) fn read(&self) -> Result<TemperatureCelsius, SensorError> {
+        let w1_slave = self.device_dir.join("w1_slave");
+        if w1_slave.exists() {
+            parse_w1_slave(&read_file(&w1_slave)?)
+        } else {
+            let text = read_file(&self.device_dir.join("temperature"))?;
+            let millidegrees = text.trim().parse::<i64>().map_err(|_| {
+                SensorError::KindMsg(SensorErrorKind::InvalidData, "temperature isn't a number")
+            })?;
+            to_celsius(millidegrees)
+        }
+    }
+}
+
+impl Sensor for DS18B20Sensor {
+    fn measure(&mut self) -> Result<Vec<Measurement>, SensorError> {
+        Ok(vec![Measurement::Temperature(self.read()?)])
+    }
+}
+
+/// ROM ids of the DS18B20 probes in `devices_dir`, sorted
+pub(crate) fn discover(devices_dir: &Path) -> Vec<String> {
+    let mut rom_ids: Vec<String> = fs::read_dir(devices_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with(FAMILY_PREFIX))
+        .collect();
+    rom_ids.sort();
+    rom_ids
+}
+
+/// Whether `rom_id` looks like the sysfs name of a DS18B20, like `28-0316a2795aff`
+pub(crate) fn is_rom_id(rom_id: &str) -> bool {
+    rom_id.strip_prefix(FAMILY_PREFIX).is_some_and(|serial| {
+        serial.len() == 12 && serial.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+fn read_file(path: &Path) -> Result<String, SensorError> {
+    fs::read_to_string(path).map_err(|e| {
+        SensorError::KindMsgCause(SensorErrorKind::Io, "unable to read the probe", Box::new(e))
+    })
+}
+
+/// Parse the two lines of `w1_slave`, the scratchpad bytes with the kernel's CRC check
+/// and then the scratchpad again with the temperature in millidegrees:
+///
+/// ```text
+/// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+/// 72 01 4b 46 7f ff 0e 10 57 t=23125
+/// ```
+fn parse_w1_slave(text: &str) -> Result<TemperatureCelsius, SensorError> {
+    let mut lines = text.lines();
+    let (crc_line, t_line) = match (lines.next(), lines.next()) {
+        (Some(crc_line), Some(t_line)) => (crc_line, t_line),
+        _ => {
+            return Err(SensorError::KindMsg(
+                SensorErrorKind::InvalidData,
+                "w1_slave has less than two lines",
+            ))
+        }
+    };
+
+    let (scratchpad, status) = crc_line.split_once(':').unwrap_or((crc_line, ""));
+    let bytes = scratchpad
+        .split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .ok()
+        .filter(|bytes| bytes.len() == 9)
+        .ok_or(SensorError::KindMsg(
+            SensorErrorKind::InvalidData,
+            "w1_slave doesn't start with the 9 scratchpad bytes",
+        ))?;
+    let computed = crc8(&bytes[..8]);
+    if computed != bytes[8] || !status.trim_end().ends_with("YES") {
+        return Err(SensorError::CheckSum(bytes[8], computed));
+    }
+    // What's read off a bus held low, its crc of zero checks out
+    if bytes.iter().all(|&byte| byte == 0) {
+        return Err(SensorError::KindMsg(
+            SensorErrorKind::InvalidData,
+            "w1_slave scratchpad is all zeros",
+        ));
+    }
+
+    let millidegrees = t_line
+        .rsplit_once("t=")
+        .and_then(|(_, t)| t.trim().parse::<i64>().ok())
+        .ok_or(SensorError::KindMsg(
+            SensorErrorKind::InvalidData,
+            "w1_slave has no t= value",
+        ))?;
+    to_celsius(millidegrees)
+}
+
+/// The probe measures -55°C to 125°C. 85°C is the value it reports before its first
+/// conversion, so it's rejected too.
+fn to_celsius(millidegrees: i64) -> Result<TemperatureCelsius, SensorError> {
+    if millidegrees == POWER_ON_MILLIDEGREES || !(-55_000..=125_000).contains(&millidegrees) {
+        Err(SensorError::ValueBounds(format!(
+            "temp_c {} is out of bounds",
+            millidegrees as f64 / 1000.0
+        )))
+    } else {
+        Ok(TemperatureCelsius::from(millidegrees as f64 / 1000.0))
+    }
+}
+
+/// Dallas/Maxim CRC-8 of the scratchpad, polynomial x^8 + x^5 + x^4 + 1
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        let mut byte = byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::ds18b20::{discover, is_rom_id, parse_w1_slave, DS18B20Sensor};
+    use crate::sensor::{SensorError, SensorErrorKind};
+
+    const DEVICES_DIR: &str = "test_w1_devices";
+
+    #[test]
+    fn test_parse_w1_slave() {
+        let temp_c = parse_w1_slave(
+            "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n72 01 4b 46 7f ff 0e 10 57 t=23125\n",
+        )
+        .unwrap();
+        assert_eq!(f64::from(temp_c), 23.125);
+
+        let temp_c = parse_w1_slave(
+            "5e ff 55 00 7f ff 0c 10 57 : crc=57 YES\n5e ff 55 00 7f ff 0c 10 57 t=-10125\n",
+        )
+        .unwrap();
+        assert_eq!(f64::from(temp_c), -10.125);
+
+        assert!(matches!(
+            parse_w1_slave(
+                "72 01 4b 46 7f ff 0e 10 58 : crc=58 NO\n72 01 4b 46 7f ff 0e 10 58 t=23125\n"
+            ),
+            Err(SensorError::CheckSum(0x58, 0x57))
+        ));
+        assert!(matches!(
+            parse_w1_slave(
+                "50 05 4b 46 7f ff 0c 10 1c : crc=1c YES\n50 05 4b 46 7f ff 0c 10 1c t=85000\n"
+            ),
+            Err(SensorError::ValueBounds(_))
+        ));
+        assert!(matches!(
+            parse_w1_slave(
+                "00 00 00 00 00 00 00 00 00 : crc=00 YES\n00 00 00 00 00 00 00 00 00 t=0\n"
+            ),
+            Err(SensorError::KindMsg(SensorErrorKind::InvalidData, _))
+        ));
+        assert!(parse_w1_slave("72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n").is_err());
+    }
+
+    #[test]
+    fn test_ds18b20_sysfs() {
+        let devices_dir = Path::new(DEVICES_DIR);
+        assert_eq!(
+            discover(devices_dir),
+            vec!["28-00000a1b2c3d", "28-0316a2795aff", "28-0316a279bad0"]
+        );
+        assert!(is_rom_id("28-0316a2795aff"));
+        assert!(!is_rom_id("28-0316a2795af"));
+        assert!(!is_rom_id("10-0316a2795aff"));
+
+        let probe = DS18B20Sensor::open(devices_dir, "28-0316a2795aff").unwrap();
+        assert_eq!(f64::from(probe.read().unwrap()), 23.125);
+        // Only a temperature file
+        let probe = DS18B20Sensor::open(devices_dir, "28-00000a1b2c3d").unwrap();
+        assert_eq!(f64::from(probe.read().unwrap()), 18.5);
+        let probe = DS18B20Sensor::open(devices_dir, "28-0316a279bad0").unwrap();
+        assert!(matches!(probe.read(), Err(SensorError::CheckSum(_, _))));
+
+        let err = DS18B20Sensor::open(devices_dir, "28-ffffffffffff").unwrap_err();
+        assert!(err.to_string().contains("found [28-00000a1b2c3d, 28-0316a2795aff"));
+    }
+}
@@ -3,12 +3,15 @@ use std::error::Error;
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
 
+use serde::Serialize;
 use tokio::sync::mpsc::error::SendError;
 use tokio::task::JoinError;
 use warp::http::StatusCode;
 use warp::reject::Reject;
 use warp::{Rejection, Reply};
 
+use crate::sensor::SensorError;
+
 pub struct GHAError {
     details: String,
 }
@@ -23,10 +26,33 @@ impl GHAError {
 pub enum PinError {
     InvalidPinValue { pin: u32, val: u32 },
     InvalidPin(u32),
+    /// Pins are held at their safe states while the agent shuts down
+    ShuttingDown(u32),
+}
+
+/// A request with invalid query parameters or for something that doesn't exist
+#[derive(Debug)]
+pub enum RequestError {
+    InvalidQuery(String),
+    NotFound(String),
+    /// The config file couldn't be reloaded, the running config is kept
+    InvalidConfig(String),
+}
+
+/// A request without a valid api token, or with a token whose role isn't allowed
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    Forbidden { token: String },
 }
 
 impl Reject for PinError {}
 
+impl Reject for AuthError {}
+
+impl Reject for RequestError {}
+
 impl Reject for GHAError {}
 
 impl Display for PinError {
@@ -38,11 +64,44 @@ impl Display for PinError {
             PinError::InvalidPin(pin) => {
                 format!("InvalidPin: pin {} not found", pin)
             }
+            PinError::ShuttingDown(pin) => {
+                format!("ShuttingDown: pin {} can't be changed during shutdown", pin)
+            }
         };
         f.write_str(msg.as_str())
     }
 }
 
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::InvalidQuery(msg) => write!(f, "InvalidQuery: {}", msg),
+            RequestError::NotFound(msg) => write!(f, "NotFound: {}", msg),
+            RequestError::InvalidConfig(msg) => write!(f, "InvalidConfig: {}", msg),
+        }
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => f.write_str("MissingToken: an api token is required"),
+            AuthError::InvalidToken => f.write_str("InvalidToken: unknown api token"),
+            AuthError::Forbidden { token } => {
+                write!(f, "Forbidden: token {} is not allowed to do this", token)
+            }
+        }
+    }
+}
+
+/// JSON body of an error response
+#[derive(Debug, Serialize)]
+struct ErrorReply {
+    code: u16,
+    error: String,
+    message: String,
+}
+
 /// Handle warp Rejection's
 pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     let code;
@@ -50,14 +109,51 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
         message = StatusCode::NOT_FOUND.to_string()
+    } else if let Some(e) = err.find::<AuthError>() {
+        code = match e {
+            AuthError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        };
+        message = e.to_string()
     } else if let Some(e) = err.find::<PinError>() {
+        code = match e {
+            PinError::ShuttingDown(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        message = e.to_string()
+    } else if let Some(e) = err.find::<RequestError>() {
+        code = match e {
+            RequestError::NotFound(_) => StatusCode::NOT_FOUND,
+            RequestError::InvalidQuery(_) | RequestError::InvalidConfig(_) => {
+                StatusCode::BAD_REQUEST
+            }
+        };
+        message = e.to_string()
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        code = StatusCode::BAD_REQUEST;
+        message = e.to_string()
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
         code = StatusCode::BAD_REQUEST;
-        message = format!("{}: {}", StatusCode::BAD_REQUEST, e)
+        message = e.to_string()
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        code = StatusCode::BAD_REQUEST;
+        message = e.to_string()
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        message = e.to_string()
+    } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+        code = StatusCode::METHOD_NOT_ALLOWED;
+        message = e.to_string()
     } else {
         code = StatusCode::INTERNAL_SERVER_ERROR;
         message = StatusCode::INTERNAL_SERVER_ERROR.to_string()
     }
-    Ok(warp::reply::with_status(message, code))
+    let reply = ErrorReply {
+        code: code.as_u16(),
+        error: code.canonical_reason().unwrap_or_default().to_string(),
+        message,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&reply), code))
 }
 
 impl Display for GHAError {
@@ -86,6 +182,12 @@ impl From<rppal::gpio::Error> for GHAError {
     }
 }
 
+impl From<SensorError> for GHAError {
+    fn from(value: SensorError) -> Self {
+        GHAError::from_string(value.to_string())
+    }
+}
+
 impl From<std::io::Error> for GHAError {
     fn from(value: std::io::Error) -> Self {
         GHAError::from_string(value.to_string())
@@ -128,6 +230,12 @@ impl From<JoinError> for GHAError {
     }
 }
 
+impl From<RequestError> for GHAError {
+    fn from(value: RequestError) -> Self {
+        GHAError::from_string(value.to_string())
+    }
+}
+
 impl From<PinError> for GHAError {
     fn from(value: PinError) -> Self {
         GHAError::from_string(value.to_string())
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::{Stream, StreamExt};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+
+use crate::config::ConfigChanges;
+
+/// Events buffered per client, a client that falls further behind skips the oldest
+const EVENT_CHANNEL_SIZE: usize = 256;
+
+/// A change pushed to live stream clients
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum LiveEvent {
+    /// A good reading from a sensor, `t` is unix milliseconds
+    Reading {
+        t: i64,
+        sensor: String,
+        /// Each metric the sensor measures, like `temp_c`
+        #[serde(flatten)]
+        values: BTreeMap<String, f64>,
+    },
+    /// A switch device's pin changed level
+    PinState {
+        name: String,
+        pin_num: u32,
+        pin_state: u32,
+    },
+    /// A switch device's override_auto flag flipped
+    OverrideAuto {
+        name: String,
+        pin_num: u32,
+        override_auto: bool,
+    },
+    /// The config was reloaded, clients refetch the switches and sensors
+    ConfigReloaded(ConfigChanges),
+}
+
+impl LiveEvent {
+    /// Name of the event in the SSE `event:` field
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            LiveEvent::Reading { .. } => "reading",
+            LiveEvent::PinState { .. } => "pin_state",
+            LiveEvent::OverrideAuto { .. } => "override_auto",
+            LiveEvent::ConfigReloaded(_) => "config_reloaded",
+        }
+    }
+
+    pub(crate) fn reading(sensor: &str, values: BTreeMap<String, f64>) -> Self {
+        LiveEvent::Reading {
+            t: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as i64,
+            sensor: sensor.to_string(),
+            values,
+        }
+    }
+}
+
+/// Fans live events out to any number of stream clients. Publishing never waits on
+/// clients, so a slow client can't hold up the sensor workers.
+#[derive(Debug, Clone)]
+pub(crate) struct EventBus {
+    sender: broadcast::Sender<LiveEvent>,
+}
+
+impl EventBus {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CHANNEL_SIZE);
+        Self { sender }
+    }
+
+    pub(crate) fn publish(&self, event: LiveEvent) {
+        // An error only means nobody is listening
+        let _ = self.sender.send(event);
+    }
+
+    /// Stream of events published from now on
+    pub(crate) fn subscribe(&self) -> impl Stream<Item = LiveEvent> {
+        BroadcastStream::new(self.sender.subscribe()).filter_map(|result| async move {
+            match result {
+                Ok(event) => Some(event),
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                    warn!("Live stream client fell behind, skipped {} events", skipped);
+                    None
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use futures_util::StreamExt;
+
+    use crate::events::{EventBus, LiveEvent};
+
+    #[tokio::test]
+    async fn test_event_bus() {
+        let events = EventBus::new();
+        // Nobody listening yet
+        events.publish(LiveEvent::reading("outside", BTreeMap::new()));
+
+        let first = events.subscribe();
+        let second = events.subscribe();
+        let event = LiveEvent::PinState {
+            name: "fan".to_string(),
+            pin_num: 18,
+            pin_state: 1,
+        };
+        events.publish(event.clone());
+        drop(events);
+
+        let first: Vec<LiveEvent> = first.collect().await;
+        let second: Vec<LiveEvent> = second.collect().await;
+        assert_eq!(first, vec![event.clone()]);
+        assert_eq!(second, vec![event.clone()]);
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"pin_state","name":"fan","pin_num":18,"pin_state":1}"#
+        );
+        let reading = LiveEvent::Reading {
+            t: 1,
+            sensor: "outside".to_string(),
+            values: BTreeMap::from([("temp_c".to_string(), 21.5)]),
+        };
+        assert_eq!(
+            serde_json::to_string(&reading).unwrap(),
+            r#"{"type":"reading","t":1,"sensor":"outside","temp_c":21.5}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_event_bus_slow_client() {
+        let events = EventBus::new();
+        let slow = events.subscribe();
+        for i in 0..300 {
+            let values = BTreeMap::from([("temp_c".to_string(), i as f64)]);
+            events.publish(LiveEvent::reading("outside", values));
+        }
+        drop(events);
+
+        // The oldest events are skipped rather than blocking the publisher
+        let received: Vec<LiveEvent> = slow.collect().await;
+        assert_eq!(received.len(), 256);
+        match &received[255] {
+            LiveEvent::Reading { values, .. } => assert_eq!(values["temp_c"], 299.0),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+}
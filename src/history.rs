@@ -0,0 +1,667 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::DateTime;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::{parse_duration, HistoryConfig, SwitchDevice};
+use crate::error::{GHAError, RequestError};
+use crate::persistence::{pin_names, sync_parent_dir};
+
+/// Records buffered for the writer task before new ones are dropped
+const HISTORY_CHANNEL_SIZE: usize = 1024;
+
+/// How often old raw readings are downsampled and expired ones dropped
+const COMPACT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Most points returned by a query when no step is given
+const DEFAULT_MAX_POINTS: i64 = 1000;
+
+/// Records are appended in about the order they were taken, a range read also reads this
+/// many milliseconds either side of it
+const ORDER_SLACK_MILLIS: i64 = 60 * 1000;
+
+/// Bisecting stops once the part of the file left is this small and it's read through
+const BISECT_MIN_BYTES: u64 = 64 * 1024;
+
+/// One line of the append-only history file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum HistoryRecord {
+    /// A good sensor reading, metric name to value
+    Reading {
+        t: i64,
+        sensor: String,
+        values: BTreeMap<String, f64>,
+    },
+    /// A switch device changing level
+    Switch {
+        t: i64,
+        switch: String,
+        pin_state: u32,
+    },
+}
+
+impl HistoryRecord {
+    fn t(&self) -> i64 {
+        match self {
+            HistoryRecord::Reading { t, .. } => *t,
+            HistoryRecord::Switch { t, .. } => *t,
+        }
+    }
+}
+
+/// Work for the writer task
+#[derive(Debug)]
+enum WriterMessage {
+    Record(HistoryRecord),
+    /// Answered once everything queued before it is written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Just the timestamp of a record, to skip lines outside a query without parsing the rest
+#[derive(Debug, Deserialize)]
+struct RecordTime {
+    t: i64,
+}
+
+/// Embedded time series of sensor readings and switch transitions, kept in an
+/// append-only JSON lines file that is periodically downsampled.
+#[derive(Debug, Clone)]
+pub(crate) struct HistoryStore {
+    path: Option<PathBuf>,
+    sender: Option<Sender<WriterMessage>>,
+    // Switch device name by pin number, replaced when the config is reloaded
+    pin_names: Arc<RwLock<BTreeMap<u32, String>>>,
+}
+
+impl HistoryStore {
+    /// Create the store and start its writer task. History is disabled when
+    /// `history.enabled` is false.
+    pub(crate) fn new(config: &HistoryConfig, switch_devices: &[SwitchDevice]) -> Self {
+        let pin_names = Arc::new(RwLock::new(pin_names(switch_devices)));
+        if !config.is_enabled() {
+            info!("History is disabled");
+            return Self {
+                path: None,
+                sender: None,
+                pin_names,
+            };
+        }
+
+        let path = PathBuf::from(config.path());
+        let (sender, receiver) = mpsc::channel::<WriterMessage>(HISTORY_CHANNEL_SIZE);
+        tokio::spawn(run_writer(path.clone(), config.clone(), receiver));
+        Self {
+            path: Some(path),
+            sender: Some(sender),
+            pin_names,
+        }
+    }
+
+    /// Record a good sensor reading without waiting on the file
+    pub(crate) fn record_reading(&self, sensor: &str, values: BTreeMap<String, f64>) {
+        self.record(HistoryRecord::Reading {
+            t: now_millis(),
+            sensor: sensor.to_string(),
+            values,
+        })
+    }
+
+    /// Name the switch devices of a reloaded config in their records
+    pub(crate) fn set_switch_devices(&self, switch_devices: &[SwitchDevice]) {
+        *self.pin_names.write().unwrap() = pin_names(switch_devices);
+    }
+
+    /// Record a switch device changing level, other output pins are ignored
+    pub(crate) fn record_switch(&self, pin_num: u32, is_high: bool) {
+        if let Some(name) = self.pin_names.read().unwrap().get(&pin_num) {
+            self.record(HistoryRecord::Switch {
+                t: now_millis(),
+                switch: name.clone(),
+                pin_state: u32::from(is_high),
+            })
+        }
+    }
+
+    fn record(&self, record: HistoryRecord) {
+        if let Some(sender) = &self.sender {
+            if let Err(e) = sender.try_send(WriterMessage::Record(record)) {
+                warn!("Dropping history record: {}", e);
+            }
+        }
+    }
+
+    /// Wait until every record so far is written to the file
+    pub(crate) async fn flush(&self) {
+        if let Some(sender) = &self.sender {
+            let (done, written) = oneshot::channel();
+            if sender.send(WriterMessage::Flush(done)).await.is_ok() {
+                let _ = written.await;
+            }
+        }
+    }
+
+    /// Run a query against the history file
+    pub(crate) async fn query(&self, query: HistoryQuery) -> Result<HistorySeries, GHAError> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Err(GHAError::from_string("history is disabled".to_string())),
+        };
+        tokio::task::spawn_blocking(move || query.run(path.as_path())).await?
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+async fn run_writer(path: PathBuf, config: HistoryConfig, mut receiver: Receiver<WriterMessage>) {
+    let mut compact_interval = tokio::time::interval(COMPACT_INTERVAL);
+    loop {
+        tokio::select! {
+            message = receiver.recv() => {
+                let mut message = match message {
+                    Some(message) => Some(message),
+                    None => break,
+                };
+                // Write everything that's queued in one go
+                let mut records = Vec::new();
+                let mut flushes = Vec::new();
+                while let Some(next) = message {
+                    match next {
+                        WriterMessage::Record(record) => records.push(record),
+                        WriterMessage::Flush(done) => flushes.push(done),
+                    }
+                    message = receiver.try_recv().ok();
+                }
+                let append_path = path.clone();
+                let appended = tokio::task::spawn_blocking(move || {
+                    append_records(append_path.as_path(), records.as_slice())
+                })
+                .await;
+                if let Err(e) = appended.map_err(GHAError::from).and_then(|result| result) {
+                    error!("Unable to write history to {}: {}", path.display(), e);
+                }
+                for done in flushes {
+                    let _ = done.send(());
+                }
+            }
+            _ = compact_interval.tick() => {
+                let compact_path = path.clone();
+                let compact_config = config.clone();
+                let compacted = tokio::task::spawn_blocking(move || {
+                    compact(compact_path.as_path(), &compact_config, now_millis())
+                })
+                .await;
+                if let Err(e) = compacted.map_err(GHAError::from).and_then(|result| result) {
+                    error!("Unable to compact history {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+fn append_records(path: &Path, records: &[HistoryRecord]) -> Result<(), GHAError> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    for record in records {
+        serde_json::to_writer(&mut writer, record)
+            .map_err(|e| GHAError::from_string(e.to_string()))?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read the records from `from` until `to`, skipping lines that don't parse. The file is
+/// in time order, so only the lines around the range are read.
+fn read_records(path: &Path, from: i64, to: i64) -> Result<Vec<HistoryRecord>, GHAError> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut file = File::open(path)?;
+    let offset = line_offset_before(&mut file, from.saturating_sub(ORDER_SLACK_MILLIS))?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut records = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match serde_json::from_str::<RecordTime>(&line) {
+            Ok(record_time) if record_time.t >= to.saturating_add(ORDER_SLACK_MILLIS) => break,
+            Ok(record_time) if record_time.t >= from && record_time.t < to => {}
+            _ => continue,
+        }
+        match serde_json::from_str::<HistoryRecord>(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Skipping bad history line: {}", e),
+        }
+    }
+    Ok(records)
+}
+
+/// Offset of a line before the first record at `t` or later, found by bisecting the file
+fn line_offset_before(file: &mut File, t: i64) -> Result<u64, GHAError> {
+    // `lo` is always the start of a line, of a record before `t` unless it's 0
+    let (mut lo, mut hi) = (0, file.metadata()?.len());
+    while hi - lo > BISECT_MIN_BYTES {
+        let mid = lo + (hi - lo) / 2;
+        file.seek(SeekFrom::Start(mid))?;
+        let mut reader = BufReader::new(&mut *file);
+        // Skip the rest of the line `mid` is in
+        let mut line = Vec::new();
+        let line_start = mid + reader.read_until(b'\n', &mut line)? as u64;
+        line.clear();
+        reader.read_until(b'\n', &mut line)?;
+        match serde_json::from_slice::<RecordTime>(&line) {
+            Ok(record_time) if record_time.t < t => lo = line_start,
+            _ => hi = mid,
+        }
+    }
+    Ok(lo)
+}
+
+/// Downsample raw readings older than `raw_retention` into `downsample_step` averages
+/// and drop records older than `retention`
+fn compact(path: &Path, config: &HistoryConfig, now: i64) -> Result<(), GHAError> {
+    let retention_start = now - config.retention_millis();
+    let raw_start = now - config.raw_retention_millis();
+    let step = config.downsample_step_millis();
+    let records = read_records(path, retention_start, i64::MAX)?;
+    let count = records.len();
+
+    let mut compacted: Vec<HistoryRecord> = Vec::with_capacity(count);
+    // (sensor, bucket start) to metric sums and counts
+    let mut buckets: BTreeMap<(String, i64), BTreeMap<String, (f64, u32)>> = BTreeMap::new();
+    for record in records {
+        match record {
+            HistoryRecord::Reading { t, sensor, values } if t < raw_start => {
+                let bucket = buckets.entry((sensor, t - t.rem_euclid(step))).or_default();
+                for (metric, value) in values {
+                    let (sum, n) = bucket.entry(metric).or_insert((0.0, 0));
+                    *sum += value;
+                    *n += 1;
+                }
+            }
+            record => compacted.push(record),
+        }
+    }
+    for ((sensor, t), metrics) in buckets {
+        let values = metrics
+            .into_iter()
+            .map(|(metric, (sum, n))| (metric, sum / n as f64))
+            .collect();
+        compacted.push(HistoryRecord::Reading { t, sensor, values });
+    }
+    compacted.sort_by_key(|record| record.t());
+
+    // Write a synced temp file and rename it so a power cut can't lose the history
+    let tmp_path = path.with_extension("tmp");
+    let _ = std::fs::remove_file(&tmp_path);
+    append_records(tmp_path.as_path(), compacted.as_slice())?;
+    File::open(&tmp_path)?.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)?;
+    info!(
+        "Compacted history from {} to {} records",
+        count,
+        compacted.len()
+    );
+    Ok(())
+}
+
+/// Query string of `GET /api/v1/history`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct HistoryParams {
+    sensor: Option<String>,
+    switch: Option<String>,
+    metric: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    step: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum HistoryTarget {
+    Sensor { name: String, metric: String },
+    Switch { name: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HistoryQuery {
+    target: HistoryTarget,
+    from: i64,
+    to: i64,
+    step: i64,
+}
+
+impl HistoryQuery {
+    /// Validate query params. `from` and `to` are unix seconds or RFC 3339 and default
+    /// to the last 24 hours. `step` is a duration like `5m` or seconds.
+    pub(crate) fn parse(params: HistoryParams) -> Result<Self, RequestError> {
+        Self::parse_at(params, now_millis())
+    }
+
+    fn parse_at(params: HistoryParams, now: i64) -> Result<Self, RequestError> {
+        let target = match (params.sensor, params.switch) {
+            (Some(name), None) => HistoryTarget::Sensor {
+                name,
+                metric: params.metric.ok_or(RequestError::InvalidQuery(
+                    "metric is required with sensor".to_string(),
+                ))?,
+            },
+            (None, Some(name)) => HistoryTarget::Switch { name },
+            _ => {
+                return Err(RequestError::InvalidQuery(
+                    "one of sensor or switch is required".to_string(),
+                ))
+            }
+        };
+        let to = match params.to {
+            Some(to) => parse_time(&to)?,
+            None => now,
+        };
+        let from = match params.from {
+            Some(from) => parse_time(&from)?,
+            None => to.saturating_sub(24 * 60 * 60 * 1000),
+        };
+        if from >= to {
+            return Err(RequestError::InvalidQuery(
+                "from must be before to".to_string(),
+            ));
+        }
+        let span = to.checked_sub(from).ok_or(RequestError::InvalidQuery(
+            "from and to are too far apart".to_string(),
+        ))?;
+        let step = match params.step {
+            Some(step) => match step.parse::<i64>() {
+                Ok(seconds) if seconds > 0 => seconds_to_millis(seconds)?,
+                _ => parse_duration(&step)
+                    .map_err(RequestError::InvalidQuery)?
+                    .num_milliseconds(),
+            },
+            None => (span / DEFAULT_MAX_POINTS).max(1000),
+        };
+        Ok(Self {
+            target,
+            from,
+            to,
+            step,
+        })
+    }
+
+    fn run(&self, path: &Path) -> Result<HistorySeries, GHAError> {
+        let records = read_records(path, self.from, self.to)?;
+        let (name, metric, points) = match &self.target {
+            HistoryTarget::Sensor { name, metric } => (
+                name.clone(),
+                Some(metric.clone()),
+                self.sensor_points(name, metric, records),
+            ),
+            // Transitions aren't samples, so they're returned as is
+            HistoryTarget::Switch { name } => {
+                let points = records
+                    .into_iter()
+                    .filter_map(|record| match record {
+                        HistoryRecord::Switch {
+                            t,
+                            switch,
+                            pin_state,
+                        } if &switch == name => Some(HistoryPoint {
+                            t,
+                            value: pin_state as f64,
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+                (name.clone(), None, points)
+            }
+        };
+        Ok(HistorySeries {
+            name,
+            metric,
+            from: self.from,
+            to: self.to,
+            step: self.step,
+            points,
+        })
+    }
+
+    /// Average the readings of the sensor's metric in each step
+    fn sensor_points(
+        &self,
+        name: &str,
+        metric: &str,
+        records: Vec<HistoryRecord>,
+    ) -> Vec<HistoryPoint> {
+        let mut buckets: BTreeMap<i64, (f64, u32)> = BTreeMap::new();
+        for record in records {
+            if let HistoryRecord::Reading { t, sensor, values } = record {
+                if let (true, Some(value)) = (sensor == name, values.get(metric)) {
+                    let bucket = self.from + (t - self.from) / self.step * self.step;
+                    let (sum, n) = buckets.entry(bucket).or_insert((0.0, 0));
+                    *sum += value;
+                    *n += 1;
+                }
+            }
+        }
+        buckets
+            .into_iter()
+            .map(|(t, (sum, n))| HistoryPoint {
+                t,
+                value: sum / n as f64,
+            })
+            .collect()
+    }
+}
+
+fn parse_time(value: &str) -> Result<i64, RequestError> {
+    if let Ok(seconds) = value.parse::<i64>() {
+        return seconds_to_millis(seconds);
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.timestamp_millis())
+        .map_err(|_| {
+            RequestError::InvalidQuery(format!("{} is not unix seconds or RFC 3339", value))
+        })
+}
+
+fn seconds_to_millis(seconds: i64) -> Result<i64, RequestError> {
+    seconds
+        .checked_mul(1000)
+        .ok_or_else(|| RequestError::InvalidQuery(format!("{} seconds is out of range", seconds)))
+}
+
+/// Response of `GET /api/v1/history`, times are unix milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct HistorySeries {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metric: Option<String>,
+    from: i64,
+    to: i64,
+    step: i64,
+    points: Vec<HistoryPoint>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct HistoryPoint {
+    t: i64,
+    value: f64,
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::config::HistoryConfig;
+    use crate::history::{
+        append_records, compact, line_offset_before, read_records, HistoryParams, HistoryPoint,
+        HistoryQuery, HistoryRecord,
+    };
+    use crate::test_util::TempDir;
+
+    const HOUR: i64 = 60 * 60 * 1000;
+
+    fn reading(t: i64, sensor: &str, temp_f: f64) -> HistoryRecord {
+        let mut values = BTreeMap::new();
+        values.insert("temp_f".to_string(), temp_f);
+        HistoryRecord::Reading {
+            t,
+            sensor: sensor.to_string(),
+            values,
+        }
+    }
+
+    #[test]
+    fn test_history_query() {
+        let dir = TempDir::new("history");
+        let path = dir.join("gha_history.jsonl");
+        append_records(
+            path.as_path(),
+            &[
+                reading(1_000, "outside", 50.0),
+                reading(2_000, "outside", 60.0),
+                reading(2_500, "inside_upper", 80.0),
+                HistoryRecord::Switch {
+                    t: 3_000,
+                    switch: "fan".to_string(),
+                    pin_state: 1,
+                },
+                reading(11_000, "outside", 70.0),
+            ],
+        )
+        .unwrap();
+
+        let params = HistoryParams {
+            sensor: Some("outside".to_string()),
+            metric: Some("temp_f".to_string()),
+            from: Some("0".to_string()),
+            to: Some("20".to_string()),
+            step: Some("10s".to_string()),
+            ..HistoryParams::default()
+        };
+        let series = HistoryQuery::parse_at(params, 0)
+            .unwrap()
+            .run(path.as_path())
+            .unwrap();
+        assert_eq!(
+            series.points,
+            vec![
+                HistoryPoint { t: 0, value: 55.0 },
+                HistoryPoint {
+                    t: 10_000,
+                    value: 70.0
+                }
+            ]
+        );
+
+        let params = HistoryParams {
+            switch: Some("fan".to_string()),
+            from: Some("1970-01-01T00:00:00Z".to_string()),
+            to: Some("20".to_string()),
+            ..HistoryParams::default()
+        };
+        let series = HistoryQuery::parse_at(params, 0)
+            .unwrap()
+            .run(path.as_path())
+            .unwrap();
+        assert_eq!(
+            series.points,
+            vec![HistoryPoint {
+                t: 3_000,
+                value: 1.0
+            }]
+        );
+
+        assert!(HistoryQuery::parse_at(HistoryParams::default(), 0).is_err());
+    }
+
+    #[test]
+    fn test_history_query_out_of_range() {
+        let params = |from: &str, to: &str, step: Option<&str>| HistoryParams {
+            switch: Some("fan".to_string()),
+            from: Some(from.to_string()),
+            to: Some(to.to_string()),
+            step: step.map(str::to_string),
+            ..HistoryParams::default()
+        };
+        let huge = i64::MAX.to_string();
+        let err = HistoryQuery::parse_at(params("0", &huge, None), 0).unwrap_err();
+        assert!(err.to_string().contains("is out of range"));
+        let err = HistoryQuery::parse_at(params("0", "20", Some(&huge)), 0).unwrap_err();
+        assert!(err.to_string().contains("is out of range"));
+        let err = HistoryQuery::parse_at(params("0", "20", Some("9999999999999999d")), 0);
+        assert!(err.unwrap_err().to_string().contains("is out of range"));
+        let far = (i64::MAX / 1000).to_string();
+        let err = HistoryQuery::parse_at(params(&format!("-{}", far), &far, None), 0).unwrap_err();
+        assert!(err.to_string().contains("too far apart"));
+    }
+
+    #[test]
+    fn test_history_compact() {
+        let dir = TempDir::new("compact");
+        let path = dir.join("gha_history.jsonl");
+        let now = 100 * 24 * HOUR;
+        let config: HistoryConfig =
+            serde_yaml::from_str("raw_retention: 1d\ndownsample_step: 1h\nretention: 30d").unwrap();
+        append_records(
+            path.as_path(),
+            &[
+                // Expired
+                reading(now - 40 * 24 * HOUR, "outside", 10.0),
+                // Downsampled into one hour
+                reading(now - 48 * HOUR, "outside", 50.0),
+                reading(now - 48 * HOUR + 60_000, "outside", 60.0),
+                // Raw
+                reading(now - HOUR, "outside", 70.0),
+            ],
+        )
+        .unwrap();
+
+        compact(path.as_path(), &config, now).unwrap();
+        let records = read_records(path.as_path(), i64::MIN, i64::MAX).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                reading(now - 48 * HOUR, "outside", 55.0),
+                reading(now - HOUR, "outside", 70.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_history_range_read() {
+        let dir = TempDir::new("range");
+        let path = dir.join("gha_history.jsonl");
+        let records: Vec<HistoryRecord> = (0..20_000)
+            .map(|i| reading(i * 1_000, "outside", i as f64))
+            .collect();
+        append_records(path.as_path(), records.as_slice()).unwrap();
+
+        let mut file = std::fs::File::open(&path).unwrap();
+        let offset = line_offset_before(&mut file, 15_000 * 1_000).unwrap();
+        assert!(offset > 0);
+        // A bisect point lands at a line of a record before the range
+        let line = std::fs::read(&path).unwrap()[offset as usize..]
+            .split(|&b| b == b'\n')
+            .next()
+            .map(|line| serde_json::from_slice::<HistoryRecord>(line).unwrap());
+        assert!(matches!(line, Some(record) if record.t() < 15_000 * 1_000));
+
+        let read = read_records(path.as_path(), 15_000 * 1_000, 15_010 * 1_000).unwrap();
+        assert_eq!(read, records[15_000..15_010].to_vec());
+        assert!(read_records(path.as_path(), 30_000 * 1_000, 40_000 * 1_000)
+            .unwrap()
+            .is_empty());
+    }
+}
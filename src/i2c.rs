@@ -0,0 +1,149 @@
+use std::fmt::Debug;
+
+use rppal::i2c::I2c;
+
+use crate::sensor::{SensorError, SensorErrorKind};
+
+/// A device at one address of an I2C bus, abstracted so the drivers can be tested with
+/// a register map
+pub trait I2cDevice: Debug + Send {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), SensorError>;
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), SensorError>;
+    /// Write `bytes` and read into `buffer` without releasing the bus in between
+    fn write_read(&mut self, bytes: &[u8], buffer: &mut [u8]) -> Result<(), SensorError>;
+
+    /// Read `buffer.len()` registers starting at `register`
+    fn read_registers(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), SensorError> {
+        self.write_read(&[register], buffer)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), SensorError> {
+        self.write(&[register, value])
+    }
+}
+
+/// Open the device at `address` on `/dev/i2c-<bus>`
+pub(crate) fn open_device(bus: u8, address: u16) -> Result<I2c, SensorError> {
+    let mut i2c = I2c::with_bus(bus).map_err(|e| {
+        SensorError::KindMsgCause(
+            SensorErrorKind::Initialization,
+            "unable to open the i2c bus",
+            Box::new(e),
+        )
+    })?;
+    i2c.set_slave_address(address).map_err(|e| {
+        SensorError::KindMsgCause(
+            SensorErrorKind::Initialization,
+            "unable to set the i2c address",
+            Box::new(e),
+        )
+    })?;
+    Ok(i2c)
+}
+
+fn transfer_error(e: rppal::i2c::Error) -> SensorError {
+    SensorError::KindMsgCause(SensorErrorKind::Io, "i2c transfer failed", Box::new(e))
+}
+
+impl I2cDevice for I2c {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), SensorError> {
+        I2c::write(self, bytes).map(|_| ()).map_err(transfer_error)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), SensorError> {
+        I2c::read(self, buffer).map(|_| ()).map_err(transfer_error)
+    }
+
+    fn write_read(&mut self, bytes: &[u8], buffer: &mut [u8]) -> Result<(), SensorError> {
+        I2c::write_read(self, bytes, buffer).map_err(transfer_error)
+    }
+}
+
+/// Sensirion CRC-8 of SHT3x words, polynomial x^8 + x^5 + x^4 + 1 starting from 0xFF
+pub(crate) fn sensirion_crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0xFFu8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 > 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Registers of a mocked I2C device. Writes set the register pointer and store any
+/// following bytes, reads return the registers from the pointer on. Devices driven by
+/// commands rather than registers, or with 16 bit registers, answer reads with the queued
+/// responses to the last write, repeating the last one.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct RegisterMap {
+    pub(crate) registers: std::collections::BTreeMap<u8, u8>,
+    responses: std::collections::BTreeMap<Vec<u8>, std::collections::VecDeque<Vec<u8>>>,
+    /// Every write, in order
+    pub(crate) writes: Vec<Vec<u8>>,
+    pointer: u8,
+    last_write: Vec<u8>,
+}
+
+#[cfg(test)]
+impl RegisterMap {
+    /// Set the registers from `register` on to `values`
+    pub(crate) fn set(&mut self, register: u8, values: &[u8]) {
+        for (i, &value) in values.iter().enumerate() {
+            self.registers.insert(register + i as u8, value);
+        }
+    }
+
+    /// Queue `response` for reads after writing `write`
+    pub(crate) fn respond(&mut self, write: &[u8], response: &[u8]) {
+        self.responses
+            .entry(write.to_vec())
+            .or_default()
+            .push_back(response.to_vec());
+    }
+}
+
+#[cfg(test)]
+impl I2cDevice for RegisterMap {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), SensorError> {
+        self.writes.push(bytes.to_vec());
+        self.last_write = bytes.to_vec();
+        if let Some((&pointer, values)) = bytes.split_first() {
+            self.pointer = pointer;
+            self.set(pointer, values);
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), SensorError> {
+        if let Some(responses) = self.responses.get_mut(&self.last_write) {
+            let response = if responses.len() > 1 {
+                responses.pop_front()
+            } else {
+                responses.front().cloned()
+            };
+            if let Some(response) = response {
+                buffer.copy_from_slice(&response[..buffer.len()]);
+                return Ok(());
+            }
+        }
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self
+                .registers
+                .get(&(self.pointer + i as u8))
+                .copied()
+                .unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    fn write_read(&mut self, bytes: &[u8], buffer: &mut [u8]) -> Result<(), SensorError> {
+        self.write(bytes)?;
+        self.read(buffer)
+    }
+}
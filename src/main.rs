@@ -1,29 +1,71 @@
 use std::net::Ipv4Addr;
+use std::path::Path;
 use std::str::FromStr;
-use std::time::Duration;
 
+use clap::Parser;
+use futures_util::FutureExt;
 use log::info;
-use prometheus::{Encoder, TextEncoder};
 use warp::http::StatusCode;
 use warp::Filter;
 
-use crate::config::GHAConfig;
+use crate::auth::Authenticator;
+use crate::cli::{Cli, Command};
+use crate::config::{GHAConfig, Role};
 use crate::error::{handle_rejection, GHAError};
-use crate::sensor_manager::SensorManager;
-
+use crate::history::{HistoryParams, HistoryQuery};
+use crate::reload::ConfigReloader;
+use crate::sensor_manager::{SensorManager, SwitchUpdate};
+use crate::shutdown::ShutdownSignal;
+use crate::systemd::Notifier;
+
+mod ads1115;
+mod auth;
+mod bme280;
+mod cli;
 mod config;
 mod dht22;
+mod ds18b20;
 mod error;
+mod events;
+mod history;
+mod i2c;
+mod metrics;
+mod monitor;
+mod mqtt;
+mod persistence;
+mod reload;
 mod routes;
+mod scheduler;
 mod sensor;
 mod sensor_manager;
+mod sht31;
+mod shutdown;
+mod systemd;
+#[cfg(test)]
+mod test_util;
+mod tls;
 
 #[tokio::main]
 async fn main() -> Result<(), GHAError> {
     env_logger::init();
+    let cli = Cli::parse();
+
+    // Load config yaml file, gha.yaml by default
+    let gha_config = GHAConfig::load(&cli.config)?;
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(gha_config, &cli.config).await,
+        Command::CheckConfig => cli::check_config(&gha_config),
+        Command::ReadSensor { pin } => cli::read_sensor(&gha_config, pin).await,
+        Command::SetPin { pin, value } => cli::set_pin(&gha_config, pin, value).await,
+        Command::DumpMetrics => cli::dump_metrics(&gha_config).await,
+    }
+}
 
-    // Load config yaml file gha.yaml
-    let gha_config: GHAConfig = load_config().await?;
+/// Run the agent until SIGTERM or SIGINT
+async fn serve(gha_config: GHAConfig, config_path: &Path) -> Result<(), GHAError> {
+    // Readiness, status and watchdog pings for systemd
+    let notifier = Notifier::from_env();
 
     // Create the sensor manager
     let sensor_manager = SensorManager::new(&gha_config);
@@ -32,52 +74,60 @@ async fn main() -> Result<(), GHAError> {
     let origins = gha_config.origins();
     let cors = warp::cors()
         .allow_origins(origins.clone())
-        .allow_methods(vec!["GET", "POST", "PUT", "DELETE"]);
+        .allow_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
+        .allow_headers(vec!["authorization", "content-type"]);
+
+    // Api token checks for the routes
+    let auth = Authenticator::new(&gha_config.auth());
+    let read = auth::require(auth.clone(), Role::ReadOnly);
+    let operate = auth::require(auth.clone(), Role::Operator);
 
     // Prometheus /metrics scrape route
-    let metrics_registry = sensor_manager.metrics_registry.clone();
-    let metrics = warp::path("metrics").map(move || {
-        // Gather the metrics.
-        let mut buffer = vec![];
-        let encoder = TextEncoder::new();
-        let metric_families = metrics_registry.gather();
-        encoder.encode(&metric_families, &mut buffer).unwrap();
-
-        // Output to the standard output.
-        let msg = String::from_utf8(buffer).unwrap();
-        info!("metrics: ----------------------\n{}", msg);
-        format!("{}", msg)
-    }).with(cors.clone());
-
-    // Output pin state route
     let sm = sensor_manager.clone();
-    let output_pin_state = sensor_manager.output_pin_state();
-    let output_pin_update =
-        warp::path!("pin" / "output" / u32 / u32).and_then(move |pin_num: u32, val: u32| {
-            let output_pin_state = output_pin_state.clone();
+    let metrics = warp::path("metrics")
+        .and(auth::require(auth.for_metrics(), Role::ReadOnly))
+        .map(move || {
+            let msg = sm.metrics_text();
+            info!("metrics: ----------------------\n{}", msg);
+            msg
+        })
+        .with(cors.clone());
+
+    // Deprecated output pin state route, use PUT /api/v1/switches/{name}
+    let sm = sensor_manager.clone();
+    let output_pin_update = warp::path!("pin" / "output" / u32 / u32)
+        .and(operate.clone())
+        .and_then(move |pin_num: u32, val: u32| {
             let sm = sm.clone();
             async move {
-                match output_pin_state.set_pin_state(pin_num, val).await {
-                    Ok(is_high) => {
-                        sm.update_pin_state_gauges().await;
-                        Ok(warp::reply::with_status(
-                            format!("{} = {}", pin_num, is_high),
-                            StatusCode::OK,
-                        ))
-                    },
+                let update = SwitchUpdate {
+                    state: Some(val),
+                    override_auto: None,
+                };
+                match sm.update_switch(pin_num, update).await {
+                    Ok(_) => Ok(warp::reply::with_status(
+                        format!("{} = {}", pin_num, val > 0),
+                        StatusCode::OK,
+                    )),
                     Err(pin_err) => Err(warp::reject::custom(pin_err)),
                 }
             }
-        }).with(cors.clone());
+        })
+        .with(warp::reply::with::header("deprecation", "true"))
+        .with(cors.clone());
 
-    // Override pin auto state route
-    let switch_manager = sensor_manager.switch_manager();
-    let override_pin_update =
-        warp::path!("pin" / "override_auto" / u32 / u32).and_then(move |pin_num: u32, val: u32| {
-            let switch_manager = switch_manager.clone();
+    // Deprecated override pin auto state route, use PUT /api/v1/switches/{name}
+    let sm = sensor_manager.clone();
+    let override_pin_update = warp::path!("pin" / "override_auto" / u32 / u32)
+        .and(operate)
+        .and_then(move |pin_num: u32, val: u32| {
+            let sm = sm.clone();
             async move {
-                let value = if val > 0 { true } else { false };
-                match switch_manager.update_override_auto(pin_num, value).await {
+                let update = SwitchUpdate {
+                    state: None,
+                    override_auto: Some(val > 0),
+                };
+                match sm.update_switch(pin_num, update).await {
                     Ok(switch_state) => Ok(warp::reply::with_status(
                         serde_json::to_string(&switch_state).unwrap(),
                         StatusCode::OK,
@@ -85,12 +135,15 @@ async fn main() -> Result<(), GHAError> {
                     Err(pin_err) => Err(warp::reject::custom(pin_err)),
                 }
             }
-        }).with(cors.clone());
+        })
+        .with(warp::reply::with::header("deprecation", "true"))
+        .with(cors.clone());
 
-    // SwitchesState route
+    // Deprecated SwitchesState route, use GET /api/v1/switches
     let switch_manager = sensor_manager.switch_manager();
     let switches_state = warp::path!("switches_state")
         .and(warp::get())
+        .and(read.clone())
         .and_then(move || {
             let switch_manager = switch_manager.clone();
             async move {
@@ -107,124 +160,162 @@ async fn main() -> Result<(), GHAError> {
             "content-type",
             "application/json",
         ))
+        .with(warp::reply::with::header("deprecation", "true"))
         .with(cors.clone());
 
-    let view_conf = gha_config.clone();
+    // Recorded readings and switch transitions route
+    let history = sensor_manager.history();
+    let history_query = warp::path!("api" / "v1" / "history")
+        .and(warp::get())
+        .and(read.clone())
+        .and(warp::query::<HistoryParams>())
+        .and_then(move |params: HistoryParams| {
+            let history = history.clone();
+            async move {
+                let query = HistoryQuery::parse(params).map_err(warp::reject::custom)?;
+                match history.query(query).await {
+                    Ok(series) => Ok(warp::reply::with_status(
+                        serde_json::to_string(&series).unwrap(),
+                        StatusCode::OK,
+                    )),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            }
+        })
+        .with(warp::reply::with::header(
+            "content-type",
+            "application/json",
+        ))
+        .with(cors.clone());
+
+    // The running config, changed by reloads
+    let sm = sensor_manager.clone();
     let config_view = warp::path!("config")
         .and(warp::get())
-        .map(move || serde_json::to_string(&view_conf).unwrap())
+        .and(read)
+        .and_then(move || {
+            let sm = sm.clone();
+            async move {
+                match sm.config().await {
+                    Ok(config) => Ok(serde_json::to_string(&config.redacted()).unwrap()),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            }
+        })
         .with(warp::reply::with::header(
             "content-type",
             "application/json",
         ))
         .with(cors.clone());
 
+    // Applies changes to the config file without a restart
+    let reloader = ConfigReloader::new(config_path, sensor_manager.clone());
+
     // Warp http routes
+    let api_routes = routes::api_routes(sensor_manager.clone(), auth.clone(), origins.clone());
+    let config_routes = routes::config_routes(reloader.clone(), auth.clone(), origins.clone());
+    let live_routes = routes::live_routes(sensor_manager.events(), auth, origins.clone());
     let static_routes = routes::static_routes(origins);
     let routes = static_routes
+        .or(api_routes)
+        .or(config_routes)
+        .or(live_routes)
         .or(config_view)
         .or(switches_state)
+        .or(history_query)
         .or(metrics)
         .or(output_pin_update)
         .or(override_pin_update)
         .recover(handle_rejection).with(cors.clone());
 
-    if gha_config.is_dht_board_pin_set() {
+    // From here on SIGTERM and SIGINT drive the switch devices to their safe states
+    let mut shutdown_signal = ShutdownSignal::new()?;
+
+    if gha_config.is_reading_sensors() {
         // power on sensor board
-        let result = sensor_manager.dht_sensor_board_on().await;
-        if result.is_err() {
-            let msg = format!(
-                "Unable to start sensor board, validate dht_board_pin in the gha.yaml config. \n{}",
-                result.err().unwrap().to_string()
-            );
-            return Err(GHAError::from_string(msg));
+        if gha_config.is_dht_board_pin_set() {
+            let result = sensor_manager.dht_sensor_board_on().await;
+            if result.is_err() {
+                let msg = format!(
+                    "Unable to start sensor board, validate dht_board_pin in the gha.yaml config. \n{}",
+                    result.err().unwrap()
+                );
+                return Err(GHAError::from_string(msg));
+            }
         }
 
-        // Start dht_sensor workers
+        // Start sensor workers
         sensor_manager.start_sensor_workers().await?;
 
         // If all sensors don't report a clean reading in 30s startup will fail
-        sensor_manager.wait_for_sensor_initialization().await?;
-
-        // monitor loop WIP
-        let _ = tokio::spawn(start_monitor_loop(sensor_manager.clone()));
-    }
-
-    // Start warp http server
-    let addr: Ipv4Addr = Ipv4Addr::from_str(sensor_manager.listen_host().await.as_str()).unwrap();
-    warp::serve(routes)
-        .bind((addr, sensor_manager.listen_port().await))
-        .await;
-    Ok(())
-}
+        notifier.status("Waiting for sensors to initialize");
+        tokio::select! {
+            result = sensor_manager.wait_for_sensor_initialization() => result?,
+            signal = shutdown_signal.recv() => {
+                info!("Received {} during startup, shutting down", signal);
+                notifier.stopping();
+                sensor_manager.shutdown().await?;
+                return Ok(());
+            }
+        }
 
-async fn load_config() -> Result<GHAConfig, GHAError> {
-    //parse gha.yaml config and overlay on default config
-    let yaml_file_str = std::fs::read("gha.yaml")?;
-    let default_config = GHAConfig::default();
-    let gha_config: GHAConfig = serde_yaml::from_slice(yaml_file_str.as_slice())?;
-    match serde_merge::omerge(default_config, gha_config) {
-        Ok(c) => Ok(c),
-        Err(e) => Err(GHAError::from(e)),
+        // Evaluate monitors and drive their switches
+        tokio::spawn(monitor::start_monitor_loop(sensor_manager.clone()));
     }
-}
 
-async fn start_monitor_loop(sensor_manager: SensorManager) -> Result<(), GHAError> {
-    let delay: u64 = 10_000;
-    tokio::spawn(async move {
-        loop {
-            if sensor_manager.config().await?.dht_configs.len() > 0 {
-                // Update the pin state gauges
-                sensor_manager.update_pin_state_gauges().await;
-
-                // Check if too hot and turn on fan
-                let is_fan_on = sensor_manager.is_switch_on("fan").await?;
-                let upper_temp_gauge = sensor_manager
-                    .dht_gauge_by_name("inside_upper".to_string())
-                    .await;
-                let lower_temp_gauge = sensor_manager
-                    .dht_gauge_by_name("inside_lower".to_string())
-                    .await;
-                let inside_average_f =
-                    (upper_temp_gauge.temp_f.get() + lower_temp_gauge.temp_f.get()) / 2.0;
-                info!("inside_average_f: {}", inside_average_f);
-                let mut is_hot = is_fan_on || inside_average_f > 90.00;
-
-                if inside_average_f <= 85.0 {
-                    is_hot = false;
-                }
-
-                if is_hot {
-                    // info!("It's hot, turning on fans [{}/{}]", is_fan_on, upper_temp_gauge.temp_f.get());
-                    sensor_manager.auto_switch_on("fan").await?;
-                    sensor_manager.auto_switch_on("case_fan").await?;
-                } else {
-                    // info!("It's cool enough, turning off fans [{}/{}]", is_fan_on, upper_temp_gauge.temp_f.get());
-                    sensor_manager.auto_switch_off("fan").await?;
-                    sensor_manager.auto_switch_off("case_fan").await?;
-                }
+    // Turn scheduled switch devices on and off
+    tokio::spawn(scheduler::start_scheduler_loop(sensor_manager.clone()));
 
-                // Check if too cold and turn on heat
-                let is_heat_on = sensor_manager.is_switch_on("heater").await?;
-                let mut is_cold = is_heat_on || inside_average_f <= 50.00;
+    // Reload the config on SIGHUP or when the file changes
+    tokio::spawn(reload::start_reload_loop(reloader));
 
-                if inside_average_f >= 60.0 {
-                    is_cold = false;
-                }
+    // Publish to and take commands from an mqtt broker
+    if let Some(mqtt_config) = gha_config.mqtt.clone() {
+        tokio::spawn(mqtt::start_mqtt_loop(sensor_manager.clone(), mqtt_config));
+    }
 
-                if is_cold {
-                    // info!("It's cold, turning on heater [{}/{}]", is_heat_on, lower_temp_gauge.temp_f.get());
-                    sensor_manager.auto_switch_on("heater").await?;
-                } else {
-                    // info!("It's warm enough, turning off heater [{}/{}]", is_heat_on, lower_temp_gauge.temp_f.get());
-                    sensor_manager.auto_switch_off("heater").await?;
-                }
+    // Start warp http server
+    let addr: Ipv4Addr = Ipv4Addr::from_str(sensor_manager.listen_host().await.as_str()).unwrap();
+    let port = sensor_manager.listen_port().await;
+    // Bind before telling systemd the agent is ready
+    let server = match gha_config.tls_paths() {
+        Some((cert_path, key_path)) => {
+            if let Some(redirect_port) = gha_config.tls_redirect_port {
+                // Send plain HTTP clients to HTTPS
+                let redirect = tls::redirect_routes(port).recover(handle_rejection);
+                tokio::spawn(warp::serve(redirect).bind((addr, redirect_port)));
             }
+            let service = warp::service(routes);
+            tls::bind_tls(service, (addr, port).into(), cert_path, key_path)
+                .await?
+                .boxed()
+        }
+        None => {
+            let (_, server) = warp::serve(routes)
+                .try_bind_ephemeral((addr, port))
+                .map_err(|e| {
+                    GHAError::from_string(format!("Unable to listen on {}:{}: {}", addr, port, e))
+                })?;
+            server.map(Ok).boxed()
+        }
+    };
+    notifier.ready(&sensor_manager.health_status().await);
+    if notifier.is_enabled() {
+        tokio::spawn(systemd::start_notify_loop(sensor_manager.clone(), notifier.clone()));
+    }
 
-            tokio::time::sleep(Duration::from_millis(delay)).await;
+    // Stop serving on a signal, or drive to safe states anyway when the server fails
+    let result = tokio::select! {
+        result = server => result,
+        signal = shutdown_signal.recv() => {
+            info!("Received {}, shutting down", signal);
+            Ok(())
         }
-    })
-    .await?
+    };
+    notifier.stopping();
+    sensor_manager.shutdown().await?;
+    info!("Shutdown complete, switch devices are in their safe states");
+    result
 }
 
 #[cfg(test)]
@@ -233,9 +324,13 @@ mod test {
     use std::collections::HashMap;
     use std::hash::{Hash, Hasher};
 
-    use prometheus::core::{AtomicF64, GenericGauge};
+    use chrono::TimeDelta;
     use prometheus::{Gauge, Opts, Registry};
 
+    use crate::config::{
+        parse_duration, GpioBackendKind, GpioLine, Schedule, ScheduleWindow, SensorMetric,
+        SwitchLevel,
+    };
     use crate::GHAConfig;
 
     #[test]
@@ -258,6 +353,222 @@ mod test {
         println!("config");
     }
 
+    #[test]
+    fn test_gha_config_monitors() {
+        let yaml_file_str = std::fs::read("test_gha.yaml").unwrap();
+        let file_conf: GHAConfig = serde_yaml::from_slice(yaml_file_str.as_slice()).unwrap();
+        let mut conf: GHAConfig = serde_merge::omerge(GHAConfig::default(), file_conf).unwrap();
+        assert_eq!(conf.monitors().len(), 2);
+        let source = conf.monitor_source("inside_average_f").unwrap();
+        assert_eq!(source.aggregate.apply(&[80.0, 90.0]), Some(85.0));
+        assert!(conf.validate().is_ok());
+
+        assert_eq!(conf.fail_safes()[0].stale_after_secs(), 600.0);
+
+        conf.monitors.as_mut().unwrap()[0].switch_devices.push("missing_fan".to_string());
+        conf.monitors.as_mut().unwrap()[1].source = "missing_source".to_string();
+        conf.fail_safes.as_mut().unwrap()[0].stale_after = "soon".to_string();
+        let mut other_fail_safe = conf.fail_safes()[0].clone();
+        other_fail_safe.name = "outside_stale".to_string();
+        other_fail_safe.stale_after = "1h".to_string();
+        other_fail_safe.switch_devices.insert("fan".to_string(), SwitchLevel::Off);
+        conf.fail_safes.as_mut().unwrap().push(other_fail_safe);
+        conf.switch_devices.as_mut().unwrap()[1].schedules = Some(vec![Schedule {
+            window: ScheduleWindow::Daily {
+                start: "06:00".to_string(),
+                end: "06:10".to_string(),
+            },
+        }]);
+        let err = conf.validate().unwrap_err().to_string();
+        assert!(err.contains("monitors[0].switch_devices[2]: unknown switch device missing_fan"));
+        assert!(err.contains("monitors[1].source: unknown monitor source missing_source"));
+        assert!(err.contains("fail_safes[0].stale_after: missing number before s in soon"));
+        assert!(err.contains(
+            "fail_safes[1].switch_devices.fan: fan is held at another level by fail_safes[0]"
+        ));
+        // Holding a switch at the same level is fine
+        assert!(!err.contains("fail_safes[1].switch_devices.heater"));
+        assert!(err.contains("monitors[1].switch_devices[0]: heater is also switched by its schedules"));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90s"), Ok(TimeDelta::seconds(90)));
+        assert_eq!(parse_duration("2m"), Ok(TimeDelta::minutes(2)));
+        assert_eq!(parse_duration("1h30m"), Ok(TimeDelta::minutes(90)));
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("0m").is_err());
+        assert!(parse_duration("5x").is_err());
+    }
+
+    #[test]
+    fn test_gha_config_validation() {
+        let yaml_file_str = std::fs::read("test_gha.yaml").unwrap();
+        let file_conf: GHAConfig = serde_yaml::from_slice(yaml_file_str.as_slice()).unwrap();
+        let mut conf: GHAConfig = serde_merge::omerge(GHAConfig::default(), file_conf).unwrap();
+        assert!(conf.validate().is_ok());
+
+        conf.dht_configs[1].gpio_pin = Some(18);
+        conf.dht_configs[2].name = "inside-upper".to_string();
+        let switch_devices = conf.switch_devices.as_mut().unwrap();
+        switch_devices[1].gpio_pin = Some(16);
+        switch_devices[2].name = "fan".to_string();
+        conf.cors_origins = vec!["http://localhost:8080".to_string(), String::new()];
+        let err = conf.validate().unwrap_err().to_string();
+        assert!(err.contains("dht_configs[2].name: \"inside-upper\" must start with a letter"));
+        assert!(err.contains("switch_devices[0].gpio_pin: pin 18 is already used by dht_configs[1]"));
+        assert!(err.contains("switch_devices[1].gpio_pin: pin 16 is already used by dht_board_pin"));
+        assert!(err.contains("switch_devices[2].name: fan is also the name of switch_devices[0]"));
+        assert!(err.contains("cors_origins[1]: empty origin"));
+        // The monitor source now references a sensor name that doesn't exist
+        assert!(err.contains("monitor_sources[0].avg.names[0]: unknown sensor inside_upper"));
+        assert!(err.contains("monitors[0].switch_devices[1]: unknown switch device case_fan"));
+        assert!(err.contains("7 problem(s)"));
+    }
+
+    #[test]
+    fn test_gha_config_gpio_lines() {
+        let yaml_file_str = std::fs::read("test_gha.yaml").unwrap();
+        let file_conf: GHAConfig = serde_yaml::from_slice(yaml_file_str.as_slice()).unwrap();
+        let mut conf: GHAConfig = serde_merge::omerge(GHAConfig::default(), file_conf).unwrap();
+        conf.gpio_backend = Some(GpioBackendKind::Cdev);
+        let switch_devices = conf.switch_devices.as_mut().unwrap();
+        switch_devices[0] = serde_yaml::from_str(
+            "{name: fan, gpio_line: {chip: /dev/gpiochip1, offset: 3}}",
+        )
+        .unwrap();
+        switch_devices[1] = serde_yaml::from_str("{name: heater, gpio_line: {offset: 7}}").unwrap();
+        assert!(conf.validate().is_ok());
+        let switch_devices = conf.switch_devices.as_ref().unwrap();
+        assert_eq!(switch_devices[0].pin(), 1003);
+        assert_eq!(switch_devices[1].pin(), 7);
+        assert_eq!(conf.gpio_lines()[&1003].offset, 3);
+
+        let switch_devices = conf.switch_devices.as_mut().unwrap();
+        switch_devices[0].gpio_pin = Some(5);
+        switch_devices[1].gpio_line = None;
+        switch_devices[2].gpio_line = Some(GpioLine {
+            chip: Some("pinctrl".to_string()),
+            offset: 4,
+        });
+        switch_devices[2].gpio_pin = None;
+        let err = conf.validate().unwrap_err().to_string();
+        assert!(err.contains("switch_devices[0].gpio_line: set either gpio_pin or gpio_line"));
+        assert!(err.contains("switch_devices[1].gpio_pin: gpio_pin or gpio_line is required"));
+        assert!(err.contains(
+            "switch_devices[2].gpio_line.chip: \"pinctrl\" isn't a gpiochipN name or /dev/gpiochipN path"
+        ));
+
+        conf.gpio_backend = None;
+        let err = conf.validate().unwrap_err().to_string();
+        assert!(err.contains("switch_devices[2].gpio_line: lines need gpio_backend: cdev"));
+    }
+
+    #[test]
+    fn test_gha_config_sensors() {
+        let yaml_file_str = std::fs::read("test_gha.yaml").unwrap();
+        let file_conf: GHAConfig = serde_yaml::from_slice(yaml_file_str.as_slice()).unwrap();
+        let mut conf: GHAConfig = serde_merge::omerge(GHAConfig::default(), file_conf).unwrap();
+        conf.sensors = serde_yaml::from_str(
+            "- {type: dht, gpio_pin: 5, name: bench, temp_offset: -0.5}",
+        )
+        .unwrap();
+        assert!(conf.validate().is_ok());
+        let names: Vec<String> = conf
+            .sensor_configs()
+            .iter()
+            .map(|sensor_config| sensor_config.name().to_string())
+            .collect();
+        assert_eq!(names, ["outside", "inside_lower", "inside_upper", "bench"]);
+        assert_eq!(conf.gpio_lines().len(), 0);
+
+        conf.sensors.as_mut().unwrap().push(
+            serde_yaml::from_str("{type: ds18b20, name: tank, rom_id: 28-0316a2795aff}").unwrap(),
+        );
+        conf.sensors.as_mut().unwrap().push(
+            serde_yaml::from_str("{type: bme280, name: weather, address: 0x77}").unwrap(),
+        );
+        conf.sensors
+            .as_mut()
+            .unwrap()
+            .push(serde_yaml::from_str("{type: sht31, name: leaf, humidity_offset: 2}").unwrap());
+        // Channels of one ADS1115 share its address
+        conf.sensors.as_mut().unwrap().push(
+            serde_yaml::from_str("{type: ads1115, name: bed_1, channel: 0, dry: 20000, wet: 8000}")
+                .unwrap(),
+        );
+        conf.sensors.as_mut().unwrap().push(
+            serde_yaml::from_str(
+                "{type: ads1115, name: bed_2, channel: 1, dry: 19000, wet: 7500, samples: 4}",
+            )
+            .unwrap(),
+        );
+        conf.monitor_sources.as_mut().unwrap().push(
+            serde_yaml::from_str(
+                "{name: beds_dry, min: {type: ads1115, metric: moisture, names: [bed_1, bed_2]}}",
+            )
+            .unwrap(),
+        );
+        assert!(conf.validate().is_ok());
+        let sensors = conf.sensors.as_ref().unwrap();
+        assert_eq!(sensors[2].i2c_address(), Some((1, 0x77)));
+        assert_eq!(sensors[2].measures().len(), 4);
+        assert_eq!(sensors[3].i2c_address(), Some((1, 0x44)));
+        assert_eq!(sensors[3].humidity_offset(), Some(2.0));
+        assert_eq!(sensors[5].i2c_address(), Some((1, 0x48)));
+        assert_eq!(sensors[5].measures(), [SensorMetric::Moisture]);
+
+        // The DS18B20 is read without a dht board
+        conf.dht_board_pin = None;
+        assert!(conf.is_reading_sensors());
+        assert!(!conf.is_sensor_read(&conf.sensors.as_ref().unwrap()[0]));
+
+        conf.sensors.as_mut().unwrap()[0] = serde_yaml::from_str(
+            "{type: dht, gpio_pin: 17, name: outside}",
+        )
+        .unwrap();
+        conf.sensors.as_mut().unwrap().push(
+            serde_yaml::from_str("{type: ds18b20, name: soil, rom_id: 28-0316a2795aff}").unwrap(),
+        );
+        conf.sensors.as_mut().unwrap().push(
+            serde_yaml::from_str("{type: ds18b20, name: water, rom_id: 0316a2795aff}").unwrap(),
+        );
+        conf.sensors.as_mut().unwrap().push(
+            serde_yaml::from_str("{type: bmp280, name: barometer, bus: 1, address: 0x77}").unwrap(),
+        );
+        conf.sensors.as_mut().unwrap().push(
+            serde_yaml::from_str("{type: sht31, name: canopy, address: 0x80}").unwrap(),
+        );
+        conf.sensors.as_mut().unwrap().push(
+            serde_yaml::from_str("{type: ads1115, name: bed_3, channel: 1, dry: 20000, wet: 8000}")
+                .unwrap(),
+        );
+        conf.sensors.as_mut().unwrap().push(
+            serde_yaml::from_str(
+                "{type: ads1115, name: bed_4, address: 0x44, channel: 4, dry: 100, wet: 100}",
+            )
+            .unwrap(),
+        );
+        conf.monitor_sources.as_mut().unwrap()[0] = serde_yaml::from_str(
+            "{name: inside_pressure, avg: {metric: pressure, names: [inside_upper]}}",
+        )
+        .unwrap();
+        let err = conf.validate().unwrap_err().to_string();
+        assert!(err.contains("sensors[0].name: outside is also the name of dht_configs[0]"));
+        assert!(err.contains("sensors[0].gpio_pin: pin 17 is already used by dht_configs[0]"));
+        assert!(err.contains("monitor_sources[0].avg.names[0]: inside_upper doesn't measure Pressure"));
+        assert!(err.contains("sensors[6].rom_id: 28-0316a2795aff is also used by sensors[1]"));
+        assert!(err.contains(
+            "sensors[7].rom_id: \"0316a2795aff\" isn't a DS18B20 ROM id like 28-0316a2795aff"
+        ));
+        assert!(err.contains("sensors[8].address: 0x77 on bus 1 is also used by sensors[2]"));
+        assert!(err.contains("sensors[9].address: 0x80 isn't a 7 bit I2C address"));
+        assert!(err.contains("sensors[10].channel: 1 is also read by sensors[5]"));
+        assert!(err.contains("sensors[11].channel: 4 isn't a channel from 0 to 3"));
+        assert!(err.contains("sensors[11].wet: 100 is also the dry value"));
+        assert!(err.contains("sensors[11].address: 0x44 on bus 1 is also used by sensors[3]"));
+    }
+
     #[test]
     fn test_hash() {
         let data = "wat";
@@ -273,7 +584,7 @@ mod test {
         let registry = Registry::new();
         let test_gauge_1_name = "test_gauge_1";
         let test_gauge_1 = Gauge::with_opts(Opts::new(
-            format!("{}", test_gauge_1_name),
+            test_gauge_1_name,
             format!("{} test gauge 1 desc", test_gauge_1_name),
         ))
         .unwrap();
@@ -281,7 +592,7 @@ mod test {
 
         let test_gauge_2_name = "test_gauge_2";
         let test_gauge_2 = Gauge::with_opts(Opts::new(
-            format!("{}", test_gauge_2_name),
+            test_gauge_2_name,
             format!("{} test gauge 2 desc", test_gauge_2_name),
         ))
         .unwrap();
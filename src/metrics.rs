@@ -0,0 +1,620 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Gauge, GaugeVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::config::{MetricsStyle, SensorMetric};
+use crate::sensor::{SensorError, SensorErrorKind};
+
+/// Labels of the sensor families, `pin` is empty for sensors on a bus
+const SENSOR_LABELS: [&str; 2] = ["sensor", "pin"];
+
+/// `kind` label values of `greenhouse_sensor_read_errors_total`
+const READ_ERROR_KINDS: [&str; 3] = ["checksum", "timeout", "out_of_bounds"];
+
+/// Switch device `mode` label values, auto while monitors and schedules drive the switch
+const SWITCH_MODES: [&str; 2] = ["auto", "manual"];
+
+/// Family name, help and unit of a sensor value in the labeled style
+fn value_family(metric: SensorMetric) -> (&'static str, &'static str, &'static str) {
+    match metric {
+        SensorMetric::TempC => (
+            "greenhouse_temperature_celsius",
+            "Temperature in degrees Celsius",
+            "celsius",
+        ),
+        SensorMetric::TempF => (
+            "greenhouse_temperature_fahrenheit",
+            "Temperature in degrees Fahrenheit",
+            "fahrenheit",
+        ),
+        SensorMetric::Humidity => (
+            "greenhouse_relative_humidity_percent",
+            "Relative humidity in percent",
+            "percent",
+        ),
+        SensorMetric::Pressure => (
+            "greenhouse_pressure_hectopascals",
+            "Barometric pressure in hectopascals",
+            "hectopascals",
+        ),
+        SensorMetric::Moisture => (
+            "greenhouse_soil_moisture_percent",
+            "Soil moisture in percent, from dry at 0 to wet at 100",
+            "percent",
+        ),
+        SensorMetric::Light => ("greenhouse_illuminance_lux", "Illuminance in lux", "lux"),
+        SensorMetric::Co2 => (
+            "greenhouse_co2_ppm",
+            "CO2 concentration in parts per million",
+            "ppm",
+        ),
+    }
+}
+
+/// Name suffix and help of a sensor value in the legacy style
+fn legacy_value(metric: SensorMetric) -> (&'static str, &'static str) {
+    match metric {
+        SensorMetric::TempC => ("c", "gauge celsius"),
+        SensorMetric::TempF => ("f", "gauge fahrenheit"),
+        SensorMetric::Humidity => ("h", "gauge humidity"),
+        SensorMetric::Pressure => ("hpa", "gauge pressure in hectopascals"),
+        SensorMetric::Moisture => ("moisture", "gauge soil moisture percent"),
+        SensorMetric::Light => ("lux", "gauge illuminance in lux"),
+        SensorMetric::Co2 => ("co2", "gauge co2 ppm"),
+    }
+}
+
+/// The Prometheus registry served on `/metrics`, with sensor, switch and fail-safe metrics
+/// named by the configured `metrics_style`. Sensor health metrics are labeled in both.
+#[derive(Debug, Clone)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    health: Arc<HealthFamilies>,
+    /// None in the legacy style
+    families: Option<Arc<Families>>,
+}
+
+impl Metrics {
+    pub(crate) fn new(style: MetricsStyle) -> Self {
+        let registry = Registry::new();
+        // The family names are fixed, so registering them can't collide
+        let health = Arc::new(HealthFamilies::register(&registry).unwrap());
+        let families = match style {
+            MetricsStyle::Labeled => Some(Arc::new(Families::register(&registry).unwrap())),
+            MetricsStyle::Legacy => None,
+        };
+        Self {
+            registry,
+            health,
+            families,
+        }
+    }
+
+    /// Create the metrics of a sensor measuring `measures`, legacy metrics are exported once
+    /// registered
+    pub(crate) fn sensor(
+        &self,
+        name: &str,
+        pin: Option<u32>,
+        measures: &[SensorMetric],
+    ) -> SensorMetrics {
+        match &self.families {
+            Some(families) => SensorMetrics::labeled(families, &self.health, name, pin, measures),
+            None => SensorMetrics::legacy(&self.health, name, pin, measures),
+        }
+    }
+
+    pub(crate) fn register_sensor(&self, sensor_metrics: &SensorMetrics) -> prometheus::Result<()> {
+        if self.families.is_none() {
+            for collector in sensor_metrics.collectors() {
+                self.registry.register(collector)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop exporting the metrics of a removed sensor
+    pub(crate) fn remove_sensor(&self, sensor_metrics: &SensorMetrics) {
+        self.health.remove_sensor(sensor_metrics);
+        match &self.families {
+            Some(families) => families.remove_sensor(sensor_metrics),
+            None => {
+                for collector in sensor_metrics.collectors() {
+                    // Metrics that never got registered fail to unregister, which is fine
+                    let _ = self.registry.unregister(collector);
+                }
+            }
+        }
+    }
+
+    /// Create the on/off metric of a switch device, a legacy gauge is exported once registered
+    pub(crate) fn switch(&self, name: &str) -> SwitchMetric {
+        match &self.families {
+            Some(families) => SwitchMetric::Labeled {
+                name: name.to_string(),
+                families: families.clone(),
+            },
+            None => SwitchMetric::Legacy(legacy_gauge(
+                format!("{}_state", name),
+                format!("{} switch state", name),
+            )),
+        }
+    }
+
+    pub(crate) fn register_switch(&self, switch_metric: &SwitchMetric) -> prometheus::Result<()> {
+        match switch_metric {
+            SwitchMetric::Legacy(gauge) => self.registry.register(Box::new(gauge.clone())),
+            SwitchMetric::Labeled { .. } => Ok(()),
+        }
+    }
+
+    /// Stop exporting the metric of a removed switch device
+    pub(crate) fn remove_switch(&self, switch_metric: &SwitchMetric) {
+        match switch_metric {
+            SwitchMetric::Labeled { name, families } => {
+                for mode in SWITCH_MODES {
+                    let _ = families.switch_on.remove_label_values(&[name, mode]);
+                }
+            }
+            SwitchMetric::Legacy(gauge) => {
+                let _ = self.registry.unregister(Box::new(gauge.clone()));
+            }
+        }
+    }
+
+    /// Create and register the gauge that is 1 while a fail-safe holds its switches
+    pub(crate) fn fail_safe(&self, name: &str) -> prometheus::Result<Gauge> {
+        match &self.families {
+            Some(families) => Ok(families.fail_safe_active.with_label_values(&[name])),
+            None => {
+                let gauge = legacy_gauge(
+                    format!("{}_fail_safe_active", name),
+                    format!("{} fail-safe is holding its switches", name),
+                );
+                self.registry.register(Box::new(gauge.clone()))?;
+                Ok(gauge)
+            }
+        }
+    }
+
+    /// Stop exporting the gauge of a removed fail-safe
+    pub(crate) fn remove_fail_safe(&self, name: &str, gauge: &Gauge) {
+        match &self.families {
+            Some(families) => {
+                let _ = families.fail_safe_active.remove_label_values(&[name]);
+            }
+            None => {
+                let _ = self.registry.unregister(Box::new(gauge.clone()));
+            }
+        }
+    }
+
+    /// Metrics in the Prometheus text format
+    pub(crate) fn text(&self) -> String {
+        let mut buffer = vec![];
+        let encoder = TextEncoder::new();
+        let metric_families = self.registry.gather();
+        encoder.encode(&metric_families, &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        if self.families.is_none() {
+            return text;
+        }
+
+        // Units are written as `# UNIT` lines, which the Prometheus text format treats as
+        // comments
+        let mut units = vec![("greenhouse_sensor_seconds_since_good_reading", "seconds")];
+        units.extend(SensorMetric::ALL.iter().map(|&metric| {
+            let (name, _, unit) = value_family(metric);
+            (name, unit)
+        }));
+        let mut with_units = String::with_capacity(text.len());
+        for line in text.lines() {
+            with_units.push_str(line);
+            with_units.push('\n');
+            let name = line
+                .strip_prefix("# TYPE ")
+                .and_then(|rest| rest.split(' ').next());
+            let unit = units.iter().find(|(unit_name, _)| Some(*unit_name) == name);
+            if let Some((name, unit)) = unit {
+                with_units.push_str(&format!("# UNIT {} {}\n", name, unit));
+            }
+        }
+        with_units
+    }
+}
+
+/// Config validation keeps names to valid metric name characters
+fn legacy_gauge(name: String, help: String) -> Gauge {
+    Gauge::with_opts(Opts::new(name, help)).unwrap()
+}
+
+fn register_gauge_vec(
+    registry: &Registry,
+    name: &str,
+    help: &str,
+    labels: &[&str],
+) -> prometheus::Result<GaugeVec> {
+    let gauge = GaugeVec::new(Opts::new(name, help), labels)?;
+    registry.register(Box::new(gauge.clone()))?;
+    Ok(gauge)
+}
+
+fn register_counter_vec(
+    registry: &Registry,
+    name: &str,
+    help: &str,
+    labels: &[&str],
+) -> prometheus::Result<IntCounterVec> {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels)?;
+    registry.register(Box::new(counter.clone()))?;
+    Ok(counter)
+}
+
+/// Sensor read health families, labeled by sensor and pin in both styles
+#[derive(Debug)]
+struct HealthFamilies {
+    read_attempts: IntCounterVec,
+    read_errors: IntCounterVec,
+    board_power_cycles: IntCounterVec,
+    seconds_since_good_reading: GaugeVec,
+}
+
+impl HealthFamilies {
+    fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let gauge = |name: &str, help: &str, labels: &[&str]| {
+            register_gauge_vec(registry, name, help, labels)
+        };
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            register_counter_vec(registry, name, help, labels)
+        };
+        Ok(Self {
+            read_attempts: counter(
+                "greenhouse_sensor_read_attempts_total",
+                "Sensor read attempts",
+                &SENSOR_LABELS,
+            )?,
+            read_errors: counter(
+                "greenhouse_sensor_read_errors_total",
+                "Failed sensor reads by kind: checksum, timeout or out_of_bounds",
+                &["sensor", "pin", "kind"],
+            )?,
+            board_power_cycles: counter(
+                "greenhouse_sensor_board_power_cycles_total",
+                "Sensor board restarts after a sensor had no good reading",
+                &SENSOR_LABELS,
+            )?,
+            seconds_since_good_reading: gauge(
+                "greenhouse_sensor_seconds_since_good_reading",
+                "Seconds since the sensor's last good reading",
+                &SENSOR_LABELS,
+            )?,
+        })
+    }
+
+    fn remove_sensor(&self, sensor_metrics: &SensorMetrics) {
+        let pin = pin_label(sensor_metrics.pin);
+        let labels = [sensor_metrics.sensor.as_str(), pin.as_str()];
+        let _ = self.read_attempts.remove_label_values(&labels);
+        let _ = self.board_power_cycles.remove_label_values(&labels);
+        let _ = self.seconds_since_good_reading.remove_label_values(&labels);
+        for kind in READ_ERROR_KINDS {
+            let _ = self
+                .read_errors
+                .remove_label_values(&[&sensor_metrics.sensor, &pin, kind]);
+        }
+    }
+}
+
+/// Labeled metric families of the labeled style
+#[derive(Debug)]
+pub(crate) struct Families {
+    values: BTreeMap<SensorMetric, GaugeVec>,
+    switch_on: GaugeVec,
+    fail_safe_active: GaugeVec,
+}
+
+impl Families {
+    fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let gauge = |name: &str, help: &str, labels: &[&str]| {
+            register_gauge_vec(registry, name, help, labels)
+        };
+        let mut values = BTreeMap::new();
+        for metric in SensorMetric::ALL {
+            let (name, help, _) = value_family(metric);
+            values.insert(metric, gauge(name, help, &SENSOR_LABELS)?);
+        }
+        Ok(Self {
+            values,
+            switch_on: gauge(
+                "greenhouse_switch_on",
+                "1 while the switch device is on, mode is auto while monitors and schedules \
+                 drive it",
+                &["switch", "mode"],
+            )?,
+            fail_safe_active: gauge(
+                "greenhouse_fail_safe_active",
+                "1 while the fail-safe holds its switch devices",
+                &["fail_safe"],
+            )?,
+        })
+    }
+
+    fn remove_sensor(&self, sensor_metrics: &SensorMetrics) {
+        let pin = pin_label(sensor_metrics.pin);
+        let labels = [sensor_metrics.sensor.as_str(), pin.as_str()];
+        for metric in sensor_metrics.values.keys() {
+            let _ = self.values[metric].remove_label_values(&labels);
+        }
+    }
+}
+
+fn pin_label(pin: Option<u32>) -> String {
+    pin.map(|pin| pin.to_string()).unwrap_or_default()
+}
+
+/// Values and read counters of a sensor
+#[derive(Debug, Clone)]
+pub(crate) struct SensorMetrics {
+    sensor: String,
+    pin: Option<u32>,
+    values: BTreeMap<SensorMetric, Gauge>,
+    pub(crate) read_attempts: IntCounter,
+    pub(crate) checksum_errors: IntCounter,
+    pub(crate) timeouts: IntCounter,
+    pub(crate) out_of_bounds: IntCounter,
+    pub(crate) board_power_cycles: IntCounter,
+    pub(crate) seconds_since_good_reading: Gauge,
+}
+
+impl SensorMetrics {
+    fn labeled(
+        families: &Families,
+        health: &HealthFamilies,
+        name: &str,
+        pin: Option<u32>,
+        measures: &[SensorMetric],
+    ) -> Self {
+        let pin_value = pin_label(pin);
+        let labels = [name, pin_value.as_str()];
+        let values = measures
+            .iter()
+            .map(|metric| (*metric, families.values[metric].with_label_values(&labels)))
+            .collect();
+        Self::with_health(health, name, pin, values)
+    }
+
+    /// Unregistered value gauges named after the sensor
+    fn legacy(
+        health: &HealthFamilies,
+        name: &str,
+        pin: Option<u32>,
+        measures: &[SensorMetric],
+    ) -> Self {
+        let values = measures
+            .iter()
+            .map(|&metric| {
+                let (suffix, help) = legacy_value(metric);
+                let gauge = legacy_gauge(
+                    format!("{}_{}", name, suffix),
+                    format!("{} {}", name, help),
+                );
+                (metric, gauge)
+            })
+            .collect();
+        Self::with_health(health, name, pin, values)
+    }
+
+    fn with_health(
+        health: &HealthFamilies,
+        name: &str,
+        pin: Option<u32>,
+        values: BTreeMap<SensorMetric, Gauge>,
+    ) -> Self {
+        let pin_value = pin_label(pin);
+        let labels = [name, pin_value.as_str()];
+        let read_errors =
+            |kind: &str| health.read_errors.with_label_values(&[name, &pin_value, kind]);
+        Self {
+            sensor: name.to_string(),
+            pin,
+            values,
+            read_attempts: health.read_attempts.with_label_values(&labels),
+            checksum_errors: read_errors("checksum"),
+            timeouts: read_errors("timeout"),
+            out_of_bounds: read_errors("out_of_bounds"),
+            board_power_cycles: health.board_power_cycles.with_label_values(&labels),
+            seconds_since_good_reading: health
+                .seconds_since_good_reading
+                .with_label_values(&labels),
+        }
+    }
+
+    /// Legacy value gauges, which are registered one by one
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        self.values
+            .values()
+            .map(|gauge| Box::new(gauge.clone()) as Box<dyn Collector>)
+            .collect()
+    }
+
+    /// Whether these are the metrics of a sensor on `pin` measuring `measures`
+    pub(crate) fn is_for(&self, pin: Option<u32>, measures: &[SensorMetric]) -> bool {
+        self.pin == pin
+            && self.values.len() == measures.len()
+            && measures.iter().all(|metric| self.values.contains_key(metric))
+    }
+
+    /// Carry the values and counts of the metrics a changed sensor replaces
+    pub(crate) fn copy_from(&self, other: &SensorMetrics) {
+        for (metric, gauge) in &self.values {
+            if let Some(other_gauge) = other.values.get(metric) {
+                gauge.set(other_gauge.get());
+            }
+        }
+        self.read_attempts.inc_by(other.read_attempts.get());
+        self.checksum_errors.inc_by(other.checksum_errors.get());
+        self.timeouts.inc_by(other.timeouts.get());
+        self.out_of_bounds.inc_by(other.out_of_bounds.get());
+        self.board_power_cycles
+            .inc_by(other.board_power_cycles.get());
+        self.seconds_since_good_reading
+            .set(other.seconds_since_good_reading.get());
+    }
+
+    /// Latest value of `metric`, NaN for metrics the sensor doesn't measure
+    pub(crate) fn value(&self, metric: SensorMetric) -> f64 {
+        self.values
+            .get(&metric)
+            .map(|gauge| gauge.get())
+            .unwrap_or(f64::NAN)
+    }
+
+    pub(crate) fn set_value(&self, metric: SensorMetric, value: f64) {
+        if let Some(gauge) = self.values.get(&metric) {
+            gauge.set(value);
+        }
+    }
+
+    pub(crate) fn record_read_error(&self, e: &SensorError) {
+        match e {
+            SensorError::CheckSum(_, _) => self.checksum_errors.inc(),
+            SensorError::ValueBounds(_) => self.out_of_bounds.inc(),
+            SensorError::KindMsg(SensorErrorKind::ReadTimeout, _)
+            | SensorError::KindMsgCause(SensorErrorKind::ReadTimeout, _, _) => self.timeouts.inc(),
+            _ => {}
+        }
+    }
+}
+
+/// On/off state of a switch device
+#[derive(Debug, Clone)]
+pub(crate) enum SwitchMetric {
+    Labeled {
+        name: String,
+        families: Arc<Families>,
+    },
+    Legacy(Gauge),
+}
+
+impl SwitchMetric {
+    /// `auto` is whether monitors and schedules drive the switch, the labeled style moves
+    /// the value between `mode` labels when it changes
+    pub(crate) fn set(&self, is_on: bool, auto: bool) {
+        let value = if is_on { 1.0 } else { 0.0 };
+        match self {
+            SwitchMetric::Labeled { name, families } => {
+                let (mode, other_mode) = if auto {
+                    ("auto", "manual")
+                } else {
+                    ("manual", "auto")
+                };
+                let _ = families.switch_on.remove_label_values(&[name, other_mode]);
+                families
+                    .switch_on
+                    .with_label_values(&[name, mode])
+                    .set(value);
+            }
+            SwitchMetric::Legacy(gauge) => gauge.set(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{MetricsStyle, SensorMetric};
+    use crate::metrics::Metrics;
+
+    const DHT: [SensorMetric; 3] = [SensorMetric::TempC, SensorMetric::TempF, SensorMetric::Humidity];
+
+    #[test]
+    fn test_labeled_metrics() {
+        let metrics = Metrics::new(MetricsStyle::Labeled);
+        let outside = metrics.sensor("outside", Some(17), &DHT);
+        metrics.register_sensor(&outside).unwrap();
+        outside.set_value(SensorMetric::TempC, 21.5);
+        outside.read_attempts.inc();
+        let fan = metrics.switch("fan");
+        metrics.register_switch(&fan).unwrap();
+        fan.set(true, true);
+        metrics.fail_safe("inside_stale").unwrap().set(1.0);
+
+        let text = metrics.text();
+        assert!(text.contains("greenhouse_temperature_celsius{pin=\"17\",sensor=\"outside\"} 21.5"));
+        assert!(text.contains("# UNIT greenhouse_temperature_celsius celsius"));
+        assert!(
+            text.contains("greenhouse_sensor_read_attempts_total{pin=\"17\",sensor=\"outside\"} 1")
+        );
+        assert!(text.contains("greenhouse_switch_on{mode=\"auto\",switch=\"fan\"} 1"));
+        assert!(text.contains("greenhouse_fail_safe_active{fail_safe=\"inside_stale\"} 1"));
+        assert!(!text.contains("greenhouse_pressure_hectopascals"));
+
+        // An overridden switch is manual, its auto series goes away
+        fan.set(false, false);
+        let text = metrics.text();
+        assert!(text.contains("greenhouse_switch_on{mode=\"manual\",switch=\"fan\"} 0"));
+        assert!(!text.contains("mode=\"auto\""));
+
+        // A sensor named like a switch doesn't collide
+        assert!(metrics
+            .register_sensor(&metrics.sensor("fan", Some(27), &DHT))
+            .is_ok());
+
+        // A sensor moved to another pin keeps its values
+        assert!(outside.is_for(Some(17), &DHT));
+        assert!(!outside.is_for(Some(22), &DHT));
+        metrics.remove_sensor(&outside);
+        let moved = metrics.sensor("outside", Some(22), &DHT);
+        moved.copy_from(&outside);
+        assert_eq!(moved.value(SensorMetric::TempC), 21.5);
+        let text = metrics.text();
+        assert!(text.contains("greenhouse_temperature_celsius{pin=\"22\",sensor=\"outside\"} 21.5"));
+        assert!(!text.contains("pin=\"17\""));
+
+        metrics.remove_sensor(&moved);
+        assert!(!metrics.text().contains("sensor=\"outside\""));
+
+        // Sensors on a bus have an empty pin
+        let bus = metrics.sensor("tank", None, &[SensorMetric::Pressure]);
+        bus.set_value(SensorMetric::Pressure, 1013.2);
+        assert!(metrics
+            .text()
+            .contains("greenhouse_pressure_hectopascals{pin=\"\",sensor=\"tank\"} 1013.2"));
+        assert!(bus.value(SensorMetric::Humidity).is_nan());
+    }
+
+    #[test]
+    fn test_legacy_metrics() {
+        let metrics = Metrics::new(MetricsStyle::Legacy);
+        let outside = metrics.sensor("outside", Some(17), &DHT);
+        metrics.register_sensor(&outside).unwrap();
+        outside.set_value(SensorMetric::TempF, 70.7);
+        let fan = metrics.switch("fan");
+        metrics.register_switch(&fan).unwrap();
+        fan.set(true, true);
+
+        let text = metrics.text();
+        assert!(text.contains("outside_f 70.7"));
+        assert!(text.contains("fan_state 1"));
+        // Health metrics are labeled in both styles
+        outside.read_attempts.inc();
+        let text = metrics.text();
+        assert!(
+            text.contains("greenhouse_sensor_read_attempts_total{pin=\"17\",sensor=\"outside\"} 1")
+        );
+        assert!(!text.contains("outside_read_attempts_total"));
+        assert!(!text.contains("# UNIT"));
+        assert!(metrics
+            .register_sensor(&metrics.sensor("outside", Some(17), &DHT))
+            .is_err());
+
+        metrics.remove_sensor(&outside);
+        assert!(!metrics.text().contains("outside_f"));
+        assert!(metrics
+            .register_sensor(&metrics.sensor("outside", Some(17), &DHT))
+            .is_ok());
+    }
+}
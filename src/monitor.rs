@@ -0,0 +1,386 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use log::{error, info, warn};
+use prometheus::Gauge;
+
+use crate::config::{FailSafe, GHAConfig, Monitor, MonitorSource, SwitchLevel};
+use crate::error::GHAError;
+use crate::sensor_manager::SensorManager;
+
+/// Evaluates the configured `fail_safes` and `monitors` on every tick and drives their
+/// switch devices.
+#[derive(Debug, Clone)]
+pub(crate) struct MonitorEngine {
+    sensor_manager: SensorManager,
+    // Whether each monitor is currently active, by monitor name
+    active: BTreeMap<String, bool>,
+    // 1 while a fail-safe is active, by fail-safe name
+    fail_safe_gauges: BTreeMap<String, Gauge>,
+}
+
+impl MonitorEngine {
+    pub(crate) fn new(sensor_manager: SensorManager) -> Self {
+        Self {
+            sensor_manager,
+            active: BTreeMap::new(),
+            fail_safe_gauges: BTreeMap::new(),
+        }
+    }
+
+    /// Switches that fail to change are logged and skipped, so one broken switch doesn't hold
+    /// up the others, and returned together as the error
+    pub(crate) async fn tick(&mut self) -> Result<(), GHAError> {
+        let config = self.sensor_manager.config().await?;
+        let mut held = BTreeSet::new();
+        let mut errors = Vec::new();
+        if let Err(e) = self.enforce_fail_safes(&config, &mut held).await {
+            errors.push(e.to_string());
+        }
+        for monitor in config.monitors() {
+            let source = match config.monitor_source(&monitor.source) {
+                Some(source) => source,
+                None => {
+                    warn!("monitor {} has no source {}", monitor.name, monitor.source);
+                    continue;
+                }
+            };
+            match self.source_value(source).await {
+                Some(value) => {
+                    if let Err(e) = self.evaluate(monitor, value, &held).await {
+                        errors.push(e.to_string());
+                    }
+                }
+                None => warn!(
+                    "monitor {} skipped, source {} has uninitialized or stale sensors",
+                    monitor.name, source.name
+                ),
+            }
+        }
+        switch_errors(errors)
+    }
+
+    /// Aggregate value of the source, None unless all of its sensors have a fresh reading
+    async fn source_value(&self, source: &MonitorSource) -> Option<f64> {
+        let metrics = source.aggregate.metrics();
+        let mut values = Vec::with_capacity(metrics.names.len());
+        for name in &metrics.names {
+            let gauge = self.sensor_manager.sensor_gauge_by_name(name).await?;
+            if !gauge.is_initialized() || gauge.is_stale() {
+                return None;
+            }
+            values.push(gauge.metric(metrics.metric));
+        }
+        source.aggregate.apply(values.as_slice())
+    }
+
+    /// Drive the switches of active fail-safes to their safe levels, adding the names of the
+    /// switches they hold to `held`, also the ones that failed to switch
+    async fn enforce_fail_safes(
+        &mut self,
+        config: &GHAConfig,
+        held: &mut BTreeSet<String>,
+    ) -> Result<(), GHAError> {
+        self.remove_fail_safe_gauges(config);
+        let mut errors = Vec::new();
+        for fail_safe in config.fail_safes() {
+            let seconds = self.seconds_since_good_readings(config, fail_safe).await;
+            let is_active = fail_safe.is_tripped(&seconds);
+            let gauge = self.fail_safe_gauge(&fail_safe.name);
+            let was_active = gauge.get() > 0.0;
+            if is_active != was_active {
+                if is_active {
+                    warn!(
+                        "fail_safe {} entered, a sensor of {:?} has no good reading for over {}, \
+                         holding {:?}",
+                        fail_safe.name,
+                        fail_safe.sources,
+                        fail_safe.stale_after,
+                        fail_safe.switch_devices
+                    );
+                } else {
+                    info!(
+                        "fail_safe {} exited, returning its switches to their monitors",
+                        fail_safe.name
+                    );
+                }
+                gauge.set(if is_active { 1.0 } else { 0.0 });
+            }
+            if !is_active {
+                continue;
+            }
+
+            for (switch_name, level) in &fail_safe.switch_devices {
+                // An earlier fail-safe already holds this switch
+                if !held.insert(switch_name.clone()) {
+                    continue;
+                }
+                let switched = match level {
+                    SwitchLevel::On => self.sensor_manager.switch_on(switch_name).await,
+                    SwitchLevel::Off => self.sensor_manager.switch_off(switch_name).await,
+                };
+                if let Err(e) = switched {
+                    error!("fail_safe {} unable to switch {}: {}", fail_safe.name, switch_name, e);
+                    errors.push(format!("{}: {}", switch_name, e));
+                }
+            }
+        }
+        switch_errors(errors)
+    }
+
+    /// Seconds since the last good reading of every sensor of the fail-safe's sources
+    async fn seconds_since_good_readings(
+        &self,
+        config: &GHAConfig,
+        fail_safe: &FailSafe,
+    ) -> Vec<f64> {
+        let mut seconds = Vec::new();
+        for source in &fail_safe.sources {
+            let source = match config.monitor_source(source) {
+                Some(source) => source,
+                None => continue,
+            };
+            for name in &source.aggregate.metrics().names {
+                // Sensors removed by a config reload are left out
+                if let Some(gauge) = self.sensor_manager.sensor_gauge_by_name(name).await {
+                    seconds.push(gauge.seconds_since_good_reading());
+                }
+            }
+        }
+        seconds
+    }
+
+    fn fail_safe_gauge(&mut self, name: &str) -> Gauge {
+        let metrics = &self.sensor_manager.metrics;
+        self.fail_safe_gauges
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                metrics.fail_safe(name).unwrap_or_else(|e| {
+                    error!("Unable to register fail_safe {} gauge: {}", name, e);
+                    Gauge::new(format!("{}_fail_safe_active", name), "unregistered").unwrap()
+                })
+            })
+            .clone()
+    }
+
+    /// Stop exporting the gauges of fail-safes removed by a config reload
+    fn remove_fail_safe_gauges(&mut self, config: &GHAConfig) {
+        let metrics = &self.sensor_manager.metrics;
+        self.fail_safe_gauges.retain(|name, gauge| {
+            let is_configured = config.fail_safes().iter().any(|f| &f.name == name);
+            if !is_configured {
+                metrics.remove_fail_safe(name, gauge);
+            }
+            is_configured
+        });
+    }
+
+    async fn any_switch_on(&self, monitor: &Monitor) -> bool {
+        for switch_name in &monitor.switch_devices {
+            match self.sensor_manager.is_switch_on(switch_name).await {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(e) => warn!("Unable to read the state of {}: {}", switch_name, e),
+            }
+        }
+        false
+    }
+
+    async fn evaluate(
+        &mut self,
+        monitor: &Monitor,
+        value: f64,
+        held: &BTreeSet<String>,
+    ) -> Result<(), GHAError> {
+        let was_active = match self.active.get(&monitor.name) {
+            Some(&was_active) => was_active,
+            // After a restart the switches are still on if the monitor was active, so a
+            // value inside the hysteresis band keeps them on
+            None => self.any_switch_on(monitor).await,
+        };
+        let is_active = monitor.threshold.is_active(was_active, value);
+        info!("monitor {}: {} = {}, active {}", monitor.name, monitor.source, value, is_active);
+        if is_active != was_active {
+            info!(
+                "monitor {} {}, turning {} {:?}",
+                monitor.name,
+                if is_active { "activated" } else { "deactivated" },
+                if is_active { "on" } else { "off" },
+                monitor.switch_devices
+            );
+        }
+        self.active.insert(monitor.name.clone(), is_active);
+
+        let mut errors = Vec::new();
+        for switch_name in &monitor.switch_devices {
+            if held.contains(switch_name) {
+                continue;
+            }
+            let switched = if is_active {
+                self.sensor_manager.auto_switch_on(switch_name).await
+            } else {
+                self.sensor_manager.auto_switch_off(switch_name).await
+            };
+            if let Err(e) = switched {
+                error!("monitor {} unable to switch {}: {}", monitor.name, switch_name, e);
+                errors.push(format!("{}: {}", switch_name, e));
+            }
+        }
+        switch_errors(errors)
+    }
+}
+
+/// One error for all the switches that failed to change
+fn switch_errors(errors: Vec<String>) -> Result<(), GHAError> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(GHAError::from_string(format!("unable to switch {}", errors.join(", "))))
+    }
+}
+
+pub(crate) async fn start_monitor_loop(sensor_manager: SensorManager) -> Result<(), GHAError> {
+    let delay: u64 = 10_000;
+    let mut engine = MonitorEngine::new(sensor_manager.clone());
+    tokio::spawn(async move {
+        while !sensor_manager.is_shutting_down() {
+            // Update the pin state gauges
+            sensor_manager.update_pin_state_gauges().await;
+
+            if let Err(e) = engine.tick().await {
+                error!("Error evaluating monitors: {}", e);
+            }
+            sensor_manager.record_monitor_progress();
+
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        info!("Monitor loop stopped");
+        Ok(())
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use crate::config::{FailSafe, Monitor, SwitchLevel, Threshold, ThresholdDirection};
+    use crate::monitor::MonitorEngine;
+    use crate::test_util::{sensor_manager, simulated_config};
+
+    #[test]
+    fn test_fail_safe_tripped() {
+        let fail_safe = FailSafe {
+            name: "inside_stale".to_string(),
+            sources: vec!["inside_average_f".to_string()],
+            stale_after: "10m".to_string(),
+            switch_devices: BTreeMap::from([
+                ("heater".to_string(), SwitchLevel::Off),
+                ("fan".to_string(), SwitchLevel::On),
+            ]),
+        };
+        assert!(!fail_safe.is_tripped(&[]));
+        assert!(!fail_safe.is_tripped(&[5.0, 600.0]));
+        assert!(fail_safe.is_tripped(&[5.0, 601.0]));
+    }
+
+    #[test]
+    fn test_upper_threshold_hysteresis() {
+        let threshold = Threshold {
+            direction: ThresholdDirection::Upper,
+            upper: 90.0,
+            lower: 85.0,
+        };
+        assert!(!threshold.is_active(false, 88.0));
+        assert!(threshold.is_active(false, 90.1));
+        assert!(threshold.is_active(true, 88.0));
+        assert!(!threshold.is_active(true, 85.0));
+        assert!(!threshold.is_active(false, 70.0));
+    }
+
+    #[test]
+    fn test_lower_threshold_hysteresis() {
+        let threshold = Threshold {
+            direction: ThresholdDirection::Lower,
+            upper: 60.0,
+            lower: 50.0,
+        };
+        assert!(!threshold.is_active(false, 55.0));
+        assert!(threshold.is_active(false, 50.0));
+        assert!(threshold.is_active(true, 59.9));
+        assert!(!threshold.is_active(true, 60.0));
+        assert!(threshold.is_active(false, 20.0));
+    }
+
+    #[tokio::test]
+    async fn test_monitor_starts_from_switch_state() {
+        let sensor_manager = sensor_manager();
+        let monitor = Monitor {
+            name: "too_hot".to_string(),
+            source: "inside_average_f".to_string(),
+            switch_devices: vec!["fan".to_string()],
+            threshold: Threshold {
+                direction: ThresholdDirection::Upper,
+                upper: 90.0,
+                lower: 85.0,
+            },
+        };
+
+        // The fan was left on before a restart, inside the band it stays on
+        sensor_manager.switch_on("fan").await.unwrap();
+        let mut engine = MonitorEngine::new(sensor_manager.clone());
+        engine.evaluate(&monitor, 88.0, &BTreeSet::new()).await.unwrap();
+        assert!(sensor_manager.is_switch_on("fan").await.unwrap());
+
+        sensor_manager.switch_off("fan").await.unwrap();
+        let mut engine = MonitorEngine::new(sensor_manager.clone());
+        engine.evaluate(&monitor, 88.0, &BTreeSet::new()).await.unwrap();
+        assert!(!sensor_manager.is_switch_on("fan").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_broken_switch_does_not_stop_the_others() {
+        let sensor_manager = sensor_manager();
+        let monitor = Monitor {
+            name: "too_hot".to_string(),
+            source: "inside_average_f".to_string(),
+            switch_devices: vec!["pump".to_string(), "fan".to_string()],
+            threshold: Threshold {
+                direction: ThresholdDirection::Upper,
+                upper: 90.0,
+                lower: 85.0,
+            },
+        };
+        let mut engine = MonitorEngine::new(sensor_manager.clone());
+        let e = engine
+            .evaluate(&monitor, 95.0, &BTreeSet::new())
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("pump"));
+        assert!(sensor_manager.is_switch_on("fan").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_removed_fail_safe_gauge_is_dropped() {
+        let sensor_manager = sensor_manager();
+        let mut config = simulated_config();
+        config.fail_safes = Some(vec![FailSafe {
+            name: "inside_stale".to_string(),
+            sources: vec!["inside_average_f".to_string()],
+            stale_after: "10m".to_string(),
+            switch_devices: BTreeMap::from([("fan".to_string(), SwitchLevel::On)]),
+        }]);
+        let mut engine = MonitorEngine::new(sensor_manager.clone());
+        engine.enforce_fail_safes(&config, &mut BTreeSet::new()).await.unwrap();
+        assert!(sensor_manager.metrics.text().contains("inside_stale_fail_safe_active 0"));
+
+        let removed = simulated_config();
+        engine.enforce_fail_safes(&removed, &mut BTreeSet::new()).await.unwrap();
+        assert!(engine.fail_safe_gauges.is_empty());
+        assert!(!sensor_manager.metrics.text().contains("inside_stale"));
+
+        // Added back by a later reload, the gauge registers again
+        engine.enforce_fail_safes(&config, &mut BTreeSet::new()).await.unwrap();
+        assert!(sensor_manager.metrics.text().contains("inside_stale_fail_safe_active 0"));
+    }
+}
@@ -0,0 +1,609 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use log::{debug, error, info, warn};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+
+use crate::config::{AuthConfig, ConfigChanges, GHAConfig, MqttConfig, SensorMetric};
+use crate::error::GHAError;
+use crate::events::LiveEvent;
+use crate::sensor_manager::{SensorManager, SwitchUpdate};
+
+/// Longest wait between reconnect attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A command received on one of the command topics
+#[derive(Debug, Clone, PartialEq)]
+enum MqttCommand {
+    Switch { name: String, on: bool },
+    OverrideAuto { name: String, on: bool },
+}
+
+/// State, command and discovery topics of the agent
+#[derive(Debug, Clone)]
+struct MqttTopics {
+    prefix: String,
+    node_id: String,
+    discovery_prefix: Option<String>,
+    /// Whether the command topics are subscribed to
+    commands: bool,
+}
+
+impl MqttTopics {
+    fn new(config: &MqttConfig, auth: &AuthConfig) -> Self {
+        Self {
+            prefix: config.topic_prefix().to_string(),
+            node_id: config.client_id().to_string(),
+            discovery_prefix: config
+                .is_discovery()
+                .then(|| config.discovery_prefix().to_string()),
+            commands: config.is_commands(auth),
+        }
+    }
+
+    /// online while connected, the broker publishes offline when the agent goes away
+    fn availability(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    fn sensor_state(&self, sensor: &str) -> String {
+        format!("{}/sensor/{}/state", self.prefix, sensor)
+    }
+
+    fn switch_state(&self, switch: &str) -> String {
+        format!("{}/switch/{}/state", self.prefix, switch)
+    }
+
+    fn switch_command(&self, switch: &str) -> String {
+        format!("{}/switch/{}/set", self.prefix, switch)
+    }
+
+    fn override_state(&self, switch: &str) -> String {
+        format!("{}/switch/{}/override", self.prefix, switch)
+    }
+
+    fn override_command(&self, switch: &str) -> String {
+        format!("{}/switch/{}/override/set", self.prefix, switch)
+    }
+
+    /// Parse a message on a command topic, None when commands are off
+    fn command(&self, topic: &str, payload: &[u8]) -> Option<MqttCommand> {
+        if !self.commands {
+            return None;
+        }
+        let on = parse_on_off(payload)?;
+        let rest = topic.strip_prefix(&self.prefix)?.strip_prefix("/switch/")?;
+        if let Some(name) = rest.strip_suffix("/override/set") {
+            Some(MqttCommand::OverrideAuto {
+                name: name.to_string(),
+                on,
+            })
+        } else {
+            rest.strip_suffix("/set").map(|name| MqttCommand::Switch {
+                name: name.to_string(),
+                on,
+            })
+        }
+    }
+
+    /// Retained message for a live event, None for events that aren't published
+    fn event_message(&self, event: &LiveEvent) -> Option<(String, String)> {
+        let message = match event {
+            LiveEvent::Reading { sensor, values, .. } => {
+                (self.sensor_state(sensor), json!(values).to_string())
+            }
+            LiveEvent::PinState {
+                name, pin_state, ..
+            } => (self.switch_state(name), on_off(*pin_state > 0)),
+            LiveEvent::OverrideAuto {
+                name,
+                override_auto,
+                ..
+            } => (self.override_state(name), on_off(*override_auto)),
+            LiveEvent::ConfigReloaded(_) => return None,
+        };
+        Some(message)
+    }
+
+    /// Discovery topics of the sensors and switches a config reload removed
+    fn removed_discovery(&self, changes: &ConfigChanges) -> Vec<String> {
+        let discovery_prefix = match &self.discovery_prefix {
+            Some(discovery_prefix) => discovery_prefix,
+            None => return Vec::new(),
+        };
+        let mut topics = Vec::new();
+        // The removed sensor's config is gone, so every metric it could have had is cleared
+        for name in &changes.sensors_removed {
+            for metric in SensorMetric::ALL {
+                if discovery_class(metric).is_some() {
+                    topics.push(format!(
+                        "{}/sensor/{}_{}_{}/config",
+                        discovery_prefix,
+                        self.node_id,
+                        name,
+                        metric.name()
+                    ));
+                }
+            }
+        }
+        for name in &changes.switches_removed {
+            topics.push(format!(
+                "{}/{}/{}_{}/config",
+                discovery_prefix,
+                self.switch_component(),
+                self.node_id,
+                name
+            ));
+            topics.push(format!(
+                "{}/binary_sensor/{}_{}_override/config",
+                discovery_prefix, self.node_id, name
+            ));
+        }
+        topics
+    }
+
+    /// Switches that can't be commanded are announced as read only binary sensors
+    fn switch_component(&self) -> &'static str {
+        if self.commands {
+            "switch"
+        } else {
+            "binary_sensor"
+        }
+    }
+
+    /// Home Assistant discovery topics and payloads for the configured sensors and switches
+    fn discovery(&self, config: &GHAConfig) -> Vec<(String, String)> {
+        let discovery_prefix = match &self.discovery_prefix {
+            Some(discovery_prefix) => discovery_prefix,
+            None => return Vec::new(),
+        };
+        let device = json!({
+            "identifiers": [self.node_id],
+            "name": "Greenhouse Agent",
+            "model": "greenhouse-agent",
+        });
+        let mut messages = Vec::new();
+        for sensor_config in config.sensor_configs() {
+            let name = sensor_config.name();
+            for &metric in sensor_config.measures() {
+                let (device_class, unit) = match discovery_class(metric) {
+                    Some(class) => class,
+                    None => continue,
+                };
+                let metric = metric.name();
+                let unique_id = format!("{}_{}_{}", self.node_id, name, metric);
+                let payload = json!({
+                    "name": format!("{} {}", name, device_class),
+                    "unique_id": unique_id,
+                    "state_topic": self.sensor_state(name),
+                    "value_template": format!("{{{{ value_json.{} }}}}", metric),
+                    "device_class": device_class,
+                    "unit_of_measurement": unit,
+                    "state_class": "measurement",
+                    "availability_topic": self.availability(),
+                    "device": device,
+                });
+                messages.push((
+                    format!("{}/sensor/{}/config", discovery_prefix, unique_id),
+                    payload.to_string(),
+                ));
+            }
+        }
+        for switch_device in config.switch_devices.iter().flatten() {
+            let name = &switch_device.name;
+            let unique_id = format!("{}_{}", self.node_id, name);
+            let mut payload = json!({
+                "name": name,
+                "unique_id": unique_id,
+                "state_topic": self.switch_state(name),
+                "availability_topic": self.availability(),
+                "device": device,
+            });
+            if self.commands {
+                payload["command_topic"] = json!(self.switch_command(name));
+            }
+            messages.push((
+                format!(
+                    "{}/{}/{}/config",
+                    discovery_prefix,
+                    self.switch_component(),
+                    unique_id
+                ),
+                payload.to_string(),
+            ));
+            if switch_device.auto.unwrap_or(false) {
+                let unique_id = format!("{}_{}_override", self.node_id, name);
+                let payload = json!({
+                    "name": format!("{} override", name),
+                    "unique_id": unique_id,
+                    "state_topic": self.override_state(name),
+                    "availability_topic": self.availability(),
+                    "device": device,
+                });
+                messages.push((
+                    format!("{}/binary_sensor/{}/config", discovery_prefix, unique_id),
+                    payload.to_string(),
+                ));
+            }
+        }
+        messages
+    }
+}
+
+/// Home Assistant device class and unit of a sensor metric, None for metrics that aren't
+/// discovered. Home Assistant converts temperatures itself, so only `temp_c` is.
+fn discovery_class(metric: SensorMetric) -> Option<(&'static str, &'static str)> {
+    match metric {
+        SensorMetric::TempC => Some(("temperature", "°C")),
+        SensorMetric::TempF => None,
+        SensorMetric::Humidity => Some(("humidity", "%")),
+        SensorMetric::Pressure => Some(("pressure", "hPa")),
+        SensorMetric::Moisture => Some(("moisture", "%")),
+        SensorMetric::Light => Some(("illuminance", "lx")),
+        SensorMetric::Co2 => Some(("carbon_dioxide", "ppm")),
+    }
+}
+
+fn on_off(on: bool) -> String {
+    if on { "ON" } else { "OFF" }.to_string()
+}
+
+fn parse_on_off(payload: &[u8]) -> Option<bool> {
+    match String::from_utf8_lossy(payload).trim().to_ascii_lowercase().as_str() {
+        "on" | "1" | "true" => Some(true),
+        "off" | "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Publishes readings and switch changes to the broker and handles commands from it
+#[derive(Clone)]
+struct MqttBridge {
+    sensor_manager: SensorManager,
+    client: AsyncClient,
+    topics: MqttTopics,
+}
+
+impl MqttBridge {
+    /// Announce the agent and its current state after every (re)connect
+    async fn on_connect(&self) -> Result<(), GHAError> {
+        let client = &self.client;
+        let topics = &self.topics;
+        client
+            .publish(topics.availability(), QoS::AtLeastOnce, true, "online")
+            .await
+            .map_err(mqtt_error)?;
+
+        let config = self.sensor_manager.config().await?;
+        for (topic, payload) in topics.discovery(&config) {
+            client
+                .publish(topic, QoS::AtLeastOnce, true, payload)
+                .await
+                .map_err(mqtt_error)?;
+        }
+
+        let switches = self.sensor_manager.switch_manager().switches_state().await?;
+        for switch_state in switches.switches() {
+            let name = switch_state.name();
+            if topics.commands {
+                client
+                    .subscribe(topics.switch_command(name), QoS::AtLeastOnce)
+                    .await
+                    .map_err(mqtt_error)?;
+                client
+                    .subscribe(topics.override_command(name), QoS::AtLeastOnce)
+                    .await
+                    .map_err(mqtt_error)?;
+            }
+            if let Some(pin_state) = switch_state.pin_state() {
+                let payload = on_off(pin_state > 0);
+                client
+                    .publish(topics.switch_state(name), QoS::AtLeastOnce, true, payload)
+                    .await
+                    .map_err(mqtt_error)?;
+            }
+            client
+                .publish(
+                    topics.override_state(name),
+                    QoS::AtLeastOnce,
+                    true,
+                    on_off(switch_state.override_auto()),
+                )
+                .await
+                .map_err(mqtt_error)?;
+        }
+        Ok(())
+    }
+
+    /// Apply a command the same way as `PUT /api/v1/switches/{name}`
+    async fn handle_command(&self, command: MqttCommand) -> Result<(), GHAError> {
+        info!("mqtt command: {:?}", command);
+        let (name, update) = match command {
+            MqttCommand::Switch { name, on } => (
+                name,
+                SwitchUpdate {
+                    state: Some(u32::from(on)),
+                    override_auto: None,
+                },
+            ),
+            MqttCommand::OverrideAuto { name, on } => (
+                name,
+                SwitchUpdate {
+                    state: None,
+                    override_auto: Some(on),
+                },
+            ),
+        };
+        let switch_state = self.sensor_manager.switch_manager().find_switch(&name).await?;
+        self.sensor_manager
+            .update_switch(switch_state.pin_num(), update)
+            .await?;
+        Ok(())
+    }
+
+    /// Drop the topics of removed sensors and switches and announce the reloaded config
+    async fn on_config_reloaded(&self, changes: &ConfigChanges) -> Result<(), GHAError> {
+        let client = &self.client;
+        for name in changes.switches_removed.iter().filter(|_| self.topics.commands) {
+            client
+                .unsubscribe(self.topics.switch_command(name))
+                .await
+                .map_err(mqtt_error)?;
+            client
+                .unsubscribe(self.topics.override_command(name))
+                .await
+                .map_err(mqtt_error)?;
+        }
+        // An empty retained config removes the entity from Home Assistant
+        for topic in self.topics.removed_discovery(changes) {
+            client
+                .publish(topic, QoS::AtLeastOnce, true, "")
+                .await
+                .map_err(mqtt_error)?;
+        }
+        self.on_connect().await
+    }
+
+    /// Publish live events until the event bus closes
+    async fn forward_events(self) {
+        let mut events = Box::pin(self.sensor_manager.events().subscribe());
+        while let Some(event) = events.next().await {
+            if let LiveEvent::ConfigReloaded(changes) = &event {
+                if let Err(e) = self.on_config_reloaded(changes).await {
+                    warn!("Unable to announce the reloaded config to mqtt: {}", e);
+                }
+                continue;
+            }
+            let (topic, payload) = match self.topics.event_message(&event) {
+                Some(message) => message,
+                None => continue,
+            };
+            if let Err(e) = self
+                .client
+                .publish(topic, QoS::AtLeastOnce, true, payload)
+                .await
+            {
+                warn!("Unable to publish mqtt message: {}", e);
+            }
+        }
+    }
+}
+
+fn mqtt_error(e: rumqttc::ClientError) -> GHAError {
+    GHAError::from_string(format!("mqtt: {}", e))
+}
+
+pub(crate) async fn start_mqtt_loop(
+    sensor_manager: SensorManager,
+    config: MqttConfig,
+) -> Result<(), GHAError> {
+    let auth = sensor_manager.config().await?.auth();
+    let topics = MqttTopics::new(&config, &auth);
+    if topics.commands {
+        warn!(
+            "Taking switch commands from mqtt, anyone the broker lets publish to {}/switch/+/set \
+             can switch pins",
+            topics.prefix
+        );
+    }
+    let mut options = MqttOptions::new(config.client_id(), config.host.clone(), config.port());
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        topics.availability(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    let (client, mut event_loop) = AsyncClient::new(options, 64);
+    let bridge = MqttBridge {
+        sensor_manager,
+        client,
+        topics,
+    };
+    tokio::spawn(bridge.clone().forward_events());
+
+    info!("Connecting to mqtt broker {}:{}", config.host, config.port());
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to mqtt broker {}:{}", config.host, config.port());
+                backoff = Duration::from_secs(1);
+                // Publishing waits on the event loop, so it can't happen inline here
+                let bridge = bridge.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = bridge.on_connect().await {
+                        error!("Error announcing to mqtt broker: {}", e);
+                    }
+                });
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                match bridge.topics.command(&publish.topic, &publish.payload) {
+                    Some(command) => {
+                        let bridge = bridge.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = bridge.handle_command(command).await {
+                                error!("Error handling mqtt command: {}", e);
+                            }
+                        });
+                    }
+                    None => warn!("Ignoring mqtt message on {}", publish.topic),
+                }
+            }
+            Ok(event) => debug!("mqtt: {:?}", event),
+            Err(e) => {
+                warn!("mqtt connection error, retrying in {:?}: {}", backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rumqttc::{AsyncClient, MqttOptions};
+
+    use crate::config::{AuthConfig, ConfigChanges, GHAConfig, MqttConfig, Role};
+    use crate::events::LiveEvent;
+    use crate::mqtt::{MqttBridge, MqttCommand, MqttTopics};
+    use crate::test_util::{api_token, sensor_manager, switch_device};
+
+    #[test]
+    fn test_mqtt_topics() {
+        let topics = MqttTopics::new(
+            &MqttConfig {
+                host: "localhost".to_string(),
+                ..MqttConfig::default()
+            },
+            &AuthConfig::default(),
+        );
+        assert_eq!(
+            topics.command("greenhouse/switch/fan/set", b"ON"),
+            Some(MqttCommand::Switch {
+                name: "fan".to_string(),
+                on: true
+            })
+        );
+        assert_eq!(
+            topics.command("greenhouse/switch/heater/override/set", b"off"),
+            Some(MqttCommand::OverrideAuto {
+                name: "heater".to_string(),
+                on: false
+            })
+        );
+        assert_eq!(topics.command("greenhouse/switch/fan/set", b"maybe"), None);
+        assert_eq!(topics.command("other/switch/fan/set", b"ON"), None);
+
+        let event = LiveEvent::PinState {
+            name: "fan".to_string(),
+            pin_num: 18,
+            pin_state: 1,
+        };
+        assert_eq!(
+            topics.event_message(&event),
+            Some(("greenhouse/switch/fan/state".to_string(), "ON".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_mqtt_discovery() {
+        let topics = MqttTopics::new(
+            &MqttConfig {
+                host: "localhost".to_string(),
+                ..MqttConfig::default()
+            },
+            &AuthConfig::default(),
+        );
+        let yaml_file_str = std::fs::read("test_gha.yaml").unwrap();
+        let mut config: GHAConfig = serde_yaml::from_slice(yaml_file_str.as_slice()).unwrap();
+        config.switch_devices = Some(vec![switch_device(18, "fan")]);
+
+        let messages = topics.discovery(&config);
+        // temperature and humidity for 3 sensors, a switch and its override
+        assert_eq!(messages.len(), 8);
+        let (topic, payload) = &messages[6];
+        assert_eq!(topic, "homeassistant/switch/greenhouse-agent_fan/config");
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["command_topic"], "greenhouse/switch/fan/set");
+        assert_eq!(
+            messages[7].0,
+            "homeassistant/binary_sensor/greenhouse-agent_fan_override/config"
+        );
+        let payload: serde_json::Value = serde_json::from_str(&messages[0].1).unwrap();
+        assert_eq!(payload["value_template"], "{{ value_json.temp_c }}");
+
+        // Removing everything clears every discovery topic
+        let changes = ConfigChanges {
+            sensors_removed: config
+                .sensor_configs()
+                .iter()
+                .map(|sensor_config| sensor_config.name().to_string())
+                .collect(),
+            switches_removed: vec!["fan".to_string()],
+            ..ConfigChanges::default()
+        };
+        let removed_topics = topics.removed_discovery(&changes);
+        for (topic, _) in &messages {
+            assert!(removed_topics.contains(topic), "{} isn't cleared", topic);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mqtt_commands() {
+        let sensor_manager = sensor_manager();
+        let mqtt_config = MqttConfig {
+            host: "localhost".to_string(),
+            ..MqttConfig::default()
+        };
+        let options = MqttOptions::new("test", "localhost", 1883);
+        let (client, _event_loop) = AsyncClient::new(options, 8);
+        let bridge = MqttBridge {
+            sensor_manager: sensor_manager.clone(),
+            client,
+            topics: MqttTopics::new(&mqtt_config, &AuthConfig::default()),
+        };
+
+        let command = bridge.topics.command("greenhouse/switch/fan/set", b"ON").unwrap();
+        bridge.handle_command(command).await.unwrap();
+        assert!(sensor_manager.is_switch_on("fan").await.unwrap());
+        let command = bridge
+            .topics
+            .command("greenhouse/switch/fan/override/set", b"ON")
+            .unwrap();
+        bridge.handle_command(command).await.unwrap();
+        let fan = sensor_manager.switch_manager().find_switch("fan").await.unwrap();
+        assert!(fan.override_auto());
+        assert!(bridge
+            .handle_command(MqttCommand::Switch {
+                name: "missing".to_string(),
+                on: true
+            })
+            .await
+            .is_err());
+
+        // Commands are off by default once the http api needs tokens
+        let auth = AuthConfig {
+            tokens: Some(vec![api_token(
+                "grafana",
+                Role::ReadOnly,
+                "cace491b69555e8d0f77747d47ae54e31ce4cc322fe51a7bdcf64402f3676ebf",
+            )]),
+            public_metrics: None,
+        };
+        let topics = MqttTopics::new(&mqtt_config, &auth);
+        assert_eq!(topics.command("greenhouse/switch/fan/set", b"OFF"), None);
+        let topics = MqttTopics::new(
+            &MqttConfig {
+                commands: Some(true),
+                ..mqtt_config
+            },
+            &auth,
+        );
+        assert!(topics.command("greenhouse/switch/fan/set", b"OFF").is_some());
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::{BootState, SwitchDevice};
+use crate::error::GHAError;
+
+/// Last known override flag and commanded level of a switch device
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PersistedSwitch {
+    pub(crate) pin_num: u32,
+    pub(crate) override_auto: bool,
+    pub(crate) pin_state: Option<u32>,
+}
+
+/// Switch device state saved to `state_file` on every change, keyed by switch name, so
+/// overrides and relay levels survive a restart.
+#[derive(Debug, Clone)]
+pub(crate) struct SwitchStateStore {
+    path: Option<PathBuf>,
+    // Switch device name by pin number, replaced when the config is reloaded
+    pin_names: Arc<RwLock<BTreeMap<u32, String>>>,
+    // State read from the file at startup
+    restored: Arc<BTreeMap<String, PersistedSwitch>>,
+    switches: Arc<Mutex<BTreeMap<String, PersistedSwitch>>>,
+}
+
+impl SwitchStateStore {
+    /// Load the state file, starting empty if it doesn't exist or can't be read. A store
+    /// without a path keeps state in memory only.
+    pub(crate) fn load(path: Option<&str>, switch_devices: &[SwitchDevice]) -> Self {
+        let path = path.map(PathBuf::from);
+        let switches = match &path {
+            Some(path) => match read_state_file(path) {
+                Ok(switches) => switches,
+                Err(e) => {
+                    warn!("Unable to read state file {}: {}", path.display(), e);
+                    BTreeMap::new()
+                }
+            },
+            None => BTreeMap::new(),
+        };
+        let pin_names = Arc::new(RwLock::new(pin_names(switch_devices)));
+        Self {
+            path,
+            pin_names,
+            restored: Arc::new(switches.clone()),
+            switches: Arc::new(Mutex::new(switches)),
+        }
+    }
+
+    /// State saved for the switch device by a previous run
+    pub(crate) fn restored(&self, name: &str) -> Option<PersistedSwitch> {
+        self.restored.get(name).cloned()
+    }
+
+    /// Level to drive the switch device's pin to at boot, per its `boot_state`
+    pub(crate) fn boot_level(&self, switch_device: &SwitchDevice) -> Option<bool> {
+        match switch_device.boot_state.unwrap_or(BootState::Restore) {
+            BootState::Restore => self
+                .restored(&switch_device.name)
+                .and_then(|switch| switch.pin_state)
+                .map(|pin_state| pin_state > 0),
+            BootState::Off => Some(false),
+            BootState::On => Some(true),
+        }
+    }
+
+    /// Persist the switch devices of a reloaded config
+    pub(crate) fn set_switch_devices(&self, switch_devices: &[SwitchDevice]) {
+        *self.pin_names.write().unwrap() = pin_names(switch_devices);
+    }
+
+    pub(crate) async fn record_pin_state(&self, pin_num: u32, is_high: bool) {
+        let pin_state = if is_high { 1 } else { 0 };
+        self.update(pin_num, |switch| switch.pin_state = Some(pin_state)).await
+    }
+
+    pub(crate) async fn record_override_auto(&self, pin_num: u32, value: bool) {
+        self.update(pin_num, |switch| switch.override_auto = value).await
+    }
+
+    async fn update(&self, pin_num: u32, f: impl FnOnce(&mut PersistedSwitch)) {
+        // Only switch devices are persisted, not the dht board pin
+        let name = match self.pin_names.read().unwrap().get(&pin_num) {
+            Some(name) => name.clone(),
+            None => return,
+        };
+        let mut switches = self.switches.lock().await;
+        let switch = switches.entry(name).or_default();
+        let before = switch.clone();
+        switch.pin_num = pin_num;
+        f(switch);
+        if *switch != before {
+            if let Err(e) = self.save(&switches).await {
+                error!("Unable to save switch state: {}", e);
+            }
+        }
+    }
+
+    async fn save(&self, switches: &BTreeMap<String, PersistedSwitch>) -> Result<(), GHAError> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        let json = serde_json::to_vec_pretty(switches)
+            .map_err(|e| GHAError::from_string(e.to_string()))?;
+        tokio::task::spawn_blocking(move || replace_file(&path, &json)).await?
+    }
+}
+
+/// Replace the file at `path` with `bytes` through a synced temp file and rename, then sync
+/// its directory, so a power cut leaves either the old or the new file
+pub(crate) fn replace_file(path: &Path, bytes: &[u8]) -> Result<(), GHAError> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp_file = File::create(&tmp_path)?;
+    tmp_file.write_all(bytes)?;
+    tmp_file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)
+}
+
+/// Sync the directory of `path` so a rename into it is durable
+pub(crate) fn sync_parent_dir(path: &Path) -> Result<(), GHAError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Switch device name by pin number
+pub(crate) fn pin_names(switch_devices: &[SwitchDevice]) -> BTreeMap<u32, String> {
+    switch_devices
+        .iter()
+        .map(|switch_device| (switch_device.pin(), switch_device.name.clone()))
+        .collect()
+}
+
+fn read_state_file(path: &Path) -> Result<BTreeMap<String, PersistedSwitch>, GHAError> {
+    if !path.exists() {
+        info!("No state file at {}, starting fresh", path.display());
+        return Ok(BTreeMap::new());
+    }
+    let bytes = std::fs::read(path)?;
+    serde_json::from_slice(bytes.as_slice()).map_err(|e| GHAError::from_string(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{BootState, SwitchDevice};
+    use crate::persistence::SwitchStateStore;
+    use crate::test_util::{switch_device, TempDir};
+
+    #[tokio::test]
+    async fn test_switch_state_store() {
+        let dir = TempDir::new("state");
+        let path = dir.join("gha_state.json");
+        let switch_devices = vec![
+            switch_device(18, "fan"),
+            SwitchDevice {
+                boot_state: Some(BootState::Off),
+                ..switch_device(23, "heater")
+            },
+            SwitchDevice {
+                boot_state: Some(BootState::On),
+                ..switch_device(24, "misc_ac")
+            },
+        ];
+
+        let store = SwitchStateStore::load(path.to_str(), switch_devices.as_slice());
+        assert_eq!(store.boot_level(&switch_devices[0]), None);
+        store.record_pin_state(18, true).await;
+        store.record_pin_state(23, true).await;
+        store.record_override_auto(23, true).await;
+        // Not a switch device
+        store.record_pin_state(16, true).await;
+
+        let store = SwitchStateStore::load(path.to_str(), switch_devices.as_slice());
+        assert_eq!(store.boot_level(&switch_devices[0]), Some(true));
+        assert_eq!(store.boot_level(&switch_devices[1]), Some(false));
+        assert_eq!(store.boot_level(&switch_devices[2]), Some(true));
+        let heater = store.restored("heater").unwrap();
+        assert!(heater.override_auto);
+        assert_eq!(heater.pin_state, Some(1));
+        assert!(store.restored("misc_ac").is_none());
+    }
+}
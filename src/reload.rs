@@ -0,0 +1,333 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::{error, info, warn};
+use serde_json::{Map, Value};
+use serde_yaml::{Mapping, Value as YamlValue};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Mutex;
+
+use crate::config::{ConfigChanges, GHAConfig};
+use crate::error::GHAError;
+use crate::persistence::sync_parent_dir;
+use crate::sensor_manager::SensorManager;
+
+/// How often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Applies the config file to the running agent on SIGHUP, when the file changes and on
+/// `POST /api/v1/config/reload`, and saves changes from `PATCH /api/v1/config`
+#[derive(Debug, Clone)]
+pub(crate) struct ConfigReloader {
+    path: PathBuf,
+    sensor_manager: SensorManager,
+    /// Modification time of the file when it was last loaded
+    modified: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl ConfigReloader {
+    pub(crate) fn new(path: &Path, sensor_manager: SensorManager) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            sensor_manager,
+            modified: Arc::new(Mutex::new(modified_time(path))),
+        }
+    }
+
+    /// Load, validate and apply the config file, an invalid file leaves the running config
+    /// as it is
+    pub(crate) async fn reload(&self) -> Result<ConfigChanges, GHAError> {
+        let mut modified = self.modified.lock().await;
+        *modified = modified_time(&self.path);
+        let gha_config = GHAConfig::load(&self.path)?;
+        self.sensor_manager.reload_config(gha_config).await
+    }
+
+    /// Merge `patch` over the top level settings of the config file like the file is merged
+    /// over the defaults, then validate, save and apply the result. Lists of named entries
+    /// are merged entry by entry, see `merge_entries`. Only the lines of the patched settings
+    /// are rewritten, so comments elsewhere in the file are kept. The previous file is kept
+    /// as `<file>.bak`.
+    pub(crate) async fn update(
+        &self,
+        patch: Map<String, Value>,
+    ) -> Result<ConfigChanges, GHAError> {
+        let mut modified = self.modified.lock().await;
+        for (key, value) in &patch {
+            if !GHAConfig::is_setting(key) {
+                return Err(GHAError::from_string(format!("unknown setting {}", key)));
+            }
+            if is_redacted(value) {
+                return Err(GHAError::from_string(format!(
+                    "{} has redacted values, send the real values or leave it out",
+                    key
+                )));
+            }
+        }
+
+        let yaml_file_str = std::fs::read_to_string(&self.path)?;
+        let mut document: Mapping = serde_yaml::from_str(&yaml_file_str)?;
+        let mut contents = yaml_file_str.clone();
+        for (key, value) in patch {
+            // A null setting is removed from the file, falling back to its default
+            let value = match (document.get(key.as_str()), serde_yaml::to_value(value)?) {
+                (_, YamlValue::Null) => None,
+                (Some(YamlValue::Sequence(current)), YamlValue::Sequence(entries))
+                    if entries.iter().all(|entry| entry_key(entry).is_some()) =>
+                {
+                    Some(YamlValue::Sequence(merge_entries(current, entries)?))
+                }
+                (_, value) => Some(value),
+            };
+            contents = set_top_level(&contents, &key, value.as_ref())?;
+            match value {
+                Some(value) => document.insert(key.into(), value),
+                None => document.remove(key.as_str()),
+            };
+        }
+        // Files the lines can't be edited in, like flow style ones, are written out whole
+        if serde_yaml::from_str::<Mapping>(&contents).ok().as_ref() != Some(&document) {
+            warn!(
+                "Unable to edit {} in place, rewriting it without its comments",
+                self.path.display()
+            );
+            contents = serde_yaml::to_string(&document)?;
+        }
+        let gha_config: GHAConfig = serde_yaml::from_value(YamlValue::Mapping(document))?;
+        let gha_config = gha_config.with_defaults()?;
+
+        write_with_backup(&self.path, &contents)?;
+        *modified = modified_time(&self.path);
+        info!("Saved the patched config to {}", self.path.display());
+        self.sensor_manager.reload_config(gha_config).await
+    }
+
+    /// Whether the file changed since it was loaded and kept its modification time since the
+    /// last poll, so a file that's still being written isn't loaded half way
+    async fn is_settled_change(&self, last_poll: &mut Option<SystemTime>) -> bool {
+        let modified = modified_time(&self.path);
+        let previous_poll = std::mem::replace(last_poll, modified);
+        modified != *self.modified.lock().await && modified == previous_poll
+    }
+}
+
+/// Whether a value holds secrets redacted by `/config`
+fn is_redacted(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s == "<redacted>",
+        Value::Array(values) => values.iter().any(is_redacted),
+        Value::Object(map) => map.values().any(is_redacted),
+        _ => false,
+    }
+}
+
+/// What an entry of a list setting is matched by, its `name` or else its `gpio_pin`
+fn entry_key(entry: &YamlValue) -> Option<&YamlValue> {
+    let entry = entry.as_mapping()?;
+    entry.get("name").or_else(|| entry.get("gpio_pin"))
+}
+
+/// Merge patched entries into the entries of a list setting. An entry replaces the fields it
+/// sets of the entry with the same key, a null field is unset, `removed: true` drops the
+/// entry and entries with a new key are appended.
+fn merge_entries(
+    current: &[YamlValue],
+    patch: Vec<YamlValue>,
+) -> Result<Vec<YamlValue>, GHAError> {
+    let mut merged = current.to_vec();
+    for entry in patch {
+        let key = entry_key(&entry).cloned();
+        let YamlValue::Mapping(mut fields) = entry else {
+            continue;
+        };
+        let index = merged.iter().position(|current| entry_key(current) == key.as_ref());
+        if fields.remove("removed") == Some(YamlValue::Bool(true)) {
+            match index {
+                Some(index) => {
+                    merged.remove(index);
+                }
+                None => {
+                    return Err(GHAError::from_string(format!(
+                        "no entry {} to remove",
+                        serde_yaml::to_string(&key)?.trim()
+                    )))
+                }
+            }
+            continue;
+        }
+        match index.and_then(|index| merged[index].as_mapping_mut()) {
+            Some(current) => {
+                for (field, value) in fields {
+                    if value.is_null() {
+                        current.remove(&field);
+                    } else {
+                        current.insert(field, value);
+                    }
+                }
+            }
+            None => merged.push(YamlValue::Mapping(fields)),
+        }
+    }
+    Ok(merged)
+}
+
+/// The key of a top level `key: value` line
+fn top_level_key(line: &str) -> Option<&str> {
+    if line.is_empty() || line.starts_with([' ', '\t', '#', '-', '.']) {
+        return None;
+    }
+    let (key, _) = line.split_once(':')?;
+    Some(key.trim().trim_matches(['"', '\'']))
+}
+
+/// Replace the lines of the top level setting `key` in the yaml `contents` with `value`, or
+/// remove them for None, leaving every other line as it is. A new setting is appended.
+fn set_top_level(
+    contents: &str,
+    key: &str,
+    value: Option<&YamlValue>,
+) -> Result<String, GHAError> {
+    let lines: Vec<&str> = contents.lines().collect();
+    let setting = match value {
+        Some(value) => {
+            let mut setting = Mapping::new();
+            setting.insert(key.into(), value.clone());
+            serde_yaml::to_string(&setting)?
+        }
+        None => String::new(),
+    };
+    let start = lines.iter().position(|line| top_level_key(line) == Some(key));
+    let Some(start) = start else {
+        let mut contents = contents.to_string();
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents.push_str(&setting);
+        return Ok(contents);
+    };
+    let mut end = lines[start + 1..]
+        .iter()
+        .position(|line| top_level_key(line).is_some() || line.starts_with("---"))
+        .map_or(lines.len(), |offset| start + 1 + offset);
+    // Blank lines and comments before the next setting belong to it
+    while end > start + 1 && (lines[end - 1].trim().is_empty() || lines[end - 1].starts_with('#'))
+    {
+        end -= 1;
+    }
+
+    let mut edited: String = lines[..start].iter().map(|line| format!("{}\n", line)).collect();
+    edited.push_str(&setting);
+    for line in &lines[end..] {
+        edited.push_str(line);
+        edited.push('\n');
+    }
+    Ok(edited)
+}
+
+/// `path` with `suffix` appended to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Replace the file at `path` through a rename so it's never partly written, after copying
+/// it to `<path>.bak`
+fn write_with_backup(path: &Path, contents: &str) -> Result<(), GHAError> {
+    let tmp_path = with_suffix(path, ".tmp");
+    let mut tmp_file = File::create(&tmp_path)?;
+    tmp_file.write_all(contents.as_bytes())?;
+    tmp_file.sync_all()?;
+    std::fs::copy(path, with_suffix(path, ".bak"))?;
+    std::fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reload the config on SIGHUP and whenever the file is modified, once it's left unchanged
+/// for a poll
+pub(crate) async fn start_reload_loop(reloader: ConfigReloader) -> Result<(), GHAError> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut last_poll = *reloader.modified.lock().await;
+    info!("Reloading {} on SIGHUP or when it changes", reloader.path.display());
+    while !reloader.sensor_manager.is_shutting_down() {
+        let reason = tokio::select! {
+            _ = hangup.recv() => "SIGHUP",
+            _ = tokio::time::sleep(WATCH_INTERVAL) => {
+                if !reloader.is_settled_change(&mut last_poll).await {
+                    continue;
+                }
+                "a change to the file"
+            }
+        };
+        info!("Reloading {} after {}", reloader.path.display(), reason);
+        if let Err(e) = reloader.reload().await {
+            error!("Config not reloaded, keeping the running config: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    use crate::reload::{modified_time, set_top_level, ConfigReloader};
+    use crate::test_util::{sensor_manager, TempDir};
+
+    fn set_modified(file: &File, secs: u64) {
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch_waits_for_writes_to_settle() {
+        let dir = TempDir::new("watch");
+        let path = dir.join("gha.yaml");
+        let file = File::create(&path).unwrap();
+        set_modified(&file, 1_000);
+        let reloader = ConfigReloader::new(&path, sensor_manager());
+        let mut last_poll = modified_time(&path);
+        assert!(!reloader.is_settled_change(&mut last_poll).await);
+
+        // Still being written at the next poll
+        set_modified(&file, 1_005);
+        assert!(!reloader.is_settled_change(&mut last_poll).await);
+        set_modified(&file, 1_010);
+        assert!(!reloader.is_settled_change(&mut last_poll).await);
+        // Unchanged since the last poll
+        assert!(reloader.is_settled_change(&mut last_poll).await);
+
+        // Loaded, so it's no longer a change
+        *reloader.modified.lock().await = modified_time(&path);
+        assert!(!reloader.is_settled_change(&mut last_poll).await);
+    }
+
+    #[test]
+    fn test_set_top_level() {
+        let yaml = "# port\nlisten_port: 6666\n\n# cors\ncors_origins:\n  - a # first\n  - b\n";
+        let origins = serde_yaml::from_str("[c]").unwrap();
+        assert_eq!(
+            set_top_level(yaml, "cors_origins", Some(&origins)).unwrap(),
+            "# port\nlisten_port: 6666\n\n# cors\ncors_origins:\n- c\n"
+        );
+        assert_eq!(
+            set_top_level(yaml, "listen_port", None).unwrap(),
+            "# port\n\n# cors\ncors_origins:\n  - a # first\n  - b\n"
+        );
+        let host = serde_yaml::from_str("127.0.0.1").unwrap();
+        assert_eq!(
+            set_top_level(yaml, "listen_host", Some(&host)).unwrap(),
+            format!("{}listen_host: 127.0.0.1\n", yaml)
+        );
+    }
+}
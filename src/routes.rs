@@ -1,12 +1,22 @@
-use std::any::TypeId;
 use std::collections::hash_map::DefaultHasher;
-use std::fmt::format;
+use std::convert::Infallible;
 use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use futures_util::{SinkExt, StreamExt};
 use include_dir::{include_dir, Dir};
-use log::info;
-use warp::http::{HeaderMap, Uri};
+use log::{debug, info};
+use warp::http::Uri;
+use warp::sse::Event;
+use warp::ws::{Message, WebSocket};
 use warp::{Filter, Rejection, Reply};
 
+use crate::auth::{self, Authenticator};
+use crate::config::Role;
+use crate::error::RequestError;
+use crate::events::EventBus;
+use crate::reload::ConfigReloader;
+use crate::sensor_manager::{SensorManager, SwitchUpdate};
+
 pub(crate) fn static_routes(
     cors_origins: Vec<&str>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
@@ -56,3 +66,569 @@ pub(crate) fn static_routes(
 
     asset_routes.boxed()
 }
+
+/// JSON api for switch devices and sensors under `/api/v1`. Switches are addressed by
+/// name or by gpio pin number.
+pub(crate) fn api_routes(
+    sensor_manager: SensorManager,
+    auth: Authenticator,
+    cors_origins: Vec<&str>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let cors = warp::cors()
+        .allow_origins(cors_origins)
+        .allow_methods(vec!["GET", "POST", "PUT", "DELETE"])
+        .allow_headers(vec!["authorization", "content-type"]);
+
+    let read = auth::require(auth.clone(), Role::ReadOnly);
+    let operate = auth::require(auth, Role::Operator);
+
+    let switch_manager = sensor_manager.switch_manager();
+    let list_switches = warp::path!("api" / "v1" / "switches")
+        .and(warp::get())
+        .and(read.clone())
+        .and_then(move || {
+            let switch_manager = switch_manager.clone();
+            async move {
+                match switch_manager.switches_state().await {
+                    Ok(switches) => Ok(warp::reply::json(&switches)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            }
+        });
+
+    let switch_manager = sensor_manager.switch_manager();
+    let get_switch = warp::path!("api" / "v1" / "switches" / String)
+        .and(warp::get())
+        .and(read.clone())
+        .and_then(move |id: String| {
+            let switch_manager = switch_manager.clone();
+            async move {
+                match switch_manager.find_switch(&id).await {
+                    Ok(switch_state) => Ok(warp::reply::json(&switch_state)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            }
+        });
+
+    let sm = sensor_manager.clone();
+    let put_switch = warp::path!("api" / "v1" / "switches" / String)
+        .and(warp::put())
+        .and(operate)
+        .and(warp::body::content_length_limit(4 * 1024))
+        .and(warp::body::json())
+        .and_then(move |id: String, update: SwitchUpdate| {
+            let sm = sm.clone();
+            async move {
+                let switch_state = sm
+                    .switch_manager()
+                    .find_switch(&id)
+                    .await
+                    .map_err(warp::reject::custom)?;
+                match sm.update_switch(switch_state.pin_num(), update).await {
+                    Ok(switch_state) => Ok(warp::reply::json(&switch_state)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            }
+        });
+
+    let sm = sensor_manager.clone();
+    let list_sensors = warp::path!("api" / "v1" / "sensors")
+        .and(warp::get())
+        .and(read.clone())
+        .and_then(move || {
+            let sm = sm.clone();
+            async move { Ok::<_, Rejection>(warp::reply::json(&sm.sensor_readings().await)) }
+        });
+
+    let sm = sensor_manager;
+    let get_sensor = warp::path!("api" / "v1" / "sensors" / String)
+        .and(warp::get())
+        .and(read.clone())
+        .and_then(move |name: String| {
+            let sm = sm.clone();
+            async move {
+                match sm.sensor_reading(&name).await {
+                    Ok(reading) => Ok(warp::reply::json(&reading)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            }
+        });
+
+    list_switches
+        .or(get_switch)
+        .or(put_switch)
+        .or(list_sensors)
+        .or(get_sensor)
+        .with(cors)
+}
+
+/// `POST /api/v1/config/reload` applies the config file without a restart and
+/// `PATCH /api/v1/config` saves and applies changed settings, both reply with what changed
+pub(crate) fn config_routes(
+    reloader: ConfigReloader,
+    auth: Authenticator,
+    cors_origins: Vec<&str>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let cors = warp::cors()
+        .allow_origins(cors_origins)
+        .allow_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
+        .allow_headers(vec!["authorization", "content-type"]);
+    let operate = auth::require(auth, Role::Operator);
+
+    let config_reloader = reloader.clone();
+    let reload = warp::path!("api" / "v1" / "config" / "reload")
+        .and(warp::post())
+        .and(operate.clone())
+        .and_then(move || {
+            let reloader = config_reloader.clone();
+            async move {
+                match reloader.reload().await {
+                    Ok(changes) => Ok(warp::reply::json(&changes)),
+                    Err(e) => Err(warp::reject::custom(RequestError::InvalidConfig(
+                        e.to_string(),
+                    ))),
+                }
+            }
+        });
+
+    let patch = warp::path!("api" / "v1" / "config")
+        .and(warp::patch())
+        .and(operate)
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json())
+        .and_then(move |patch: serde_json::Map<String, serde_json::Value>| {
+            let reloader = reloader.clone();
+            async move {
+                match reloader.update(patch).await {
+                    Ok(changes) => Ok(warp::reply::json(&changes)),
+                    Err(e) => Err(warp::reject::custom(RequestError::InvalidConfig(
+                        e.to_string(),
+                    ))),
+                }
+            }
+        });
+
+    reload.or(patch).with(cors)
+}
+
+/// Live stream of readings and switch changes as Server-Sent Events on `/api/v1/events`
+/// and as WebSocket text messages on `/api/v1/ws`
+pub(crate) fn live_routes(
+    events: EventBus,
+    auth: Authenticator,
+    cors_origins: Vec<&str>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let cors = warp::cors()
+        .allow_origins(cors_origins)
+        .allow_methods(vec!["GET", "POST", "PUT", "DELETE"])
+        .allow_headers(vec!["authorization", "content-type"]);
+    let read = auth::require(auth, Role::ReadOnly);
+
+    let sse_events = events.clone();
+    let sse = warp::path!("api" / "v1" / "events")
+        .and(warp::get())
+        .and(read.clone())
+        .map(move || {
+            let stream = sse_events.subscribe().map(|event| {
+                Ok::<Event, Infallible>(
+                    Event::default()
+                        .event(event.name())
+                        .data(serde_json::to_string(&event).unwrap()),
+                )
+            });
+            warp::sse::reply(
+                warp::sse::keep_alive()
+                    .interval(Duration::from_secs(15))
+                    .stream(stream),
+            )
+        })
+        .with(cors.clone());
+
+    let ws = warp::path!("api" / "v1" / "ws")
+        .and(read)
+        .and(warp::ws())
+        .map(move |ws: warp::ws::Ws| {
+            let events = events.clone();
+            ws.on_upgrade(move |socket| forward_events(socket, events))
+        })
+        .with(cors);
+
+    sse.or(ws)
+}
+
+/// Send events to a WebSocket client until it goes away
+async fn forward_events(socket: WebSocket, events: EventBus) {
+    let (mut sink, mut incoming) = socket.split();
+    let mut stream = Box::pin(events.subscribe());
+    loop {
+        tokio::select! {
+            event = stream.next() => {
+                let event = match event {
+                    Some(event) => event,
+                    None => break,
+                };
+                let text = serde_json::to_string(&event).unwrap();
+                if sink.send(Message::text(text)).await.is_err() {
+                    break;
+                }
+            }
+            message = incoming.next() => {
+                // Clients don't send anything, only watch for them closing
+                match message {
+                    Some(Ok(message)) if !message.is_close() => {}
+                    _ => break,
+                }
+            }
+        }
+    }
+    debug!("WebSocket client disconnected");
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    use crate::auth::Authenticator;
+    use crate::config::{AuthConfig, GHAConfig, Role};
+    use crate::error::handle_rejection;
+    use crate::events::EventBus;
+    use crate::reload::ConfigReloader;
+    use crate::routes::{api_routes, config_routes, live_routes};
+    use crate::sensor_manager::SensorManager;
+    use crate::test_util::{api_token, sensor_manager, TempDir};
+
+    #[tokio::test]
+    async fn test_api_routes() {
+        let auth = Authenticator::new(&AuthConfig::default());
+        let routes = api_routes(sensor_manager(), auth, Vec::new()).recover(handle_rejection);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/switches")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["switches"].as_array().unwrap().len(), 2);
+
+        // By name and by pin number
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/switches/fan")
+            .json(&serde_json::json!({"state": 1, "override_auto": true}))
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/switches/18")
+            .reply(&routes)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["name"], "fan");
+        assert_eq!(body["pin_state"], 1);
+        assert_eq!(body["override_auto"], true);
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/switches/heater")
+            .json(&serde_json::json!({"state": 2}))
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["code"], 400);
+        assert_eq!(body["message"], "InvalidPinValue 23: 2 must be 0 or 1");
+
+        // An invalid state leaves the override as it was
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/switches/heater")
+            .json(&serde_json::json!({"state": 2, "override_auto": true}))
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/switches/heater")
+            .reply(&routes)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["override_auto"], false);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/switches/missing")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/sensors/outside")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["initialized"], false);
+        assert!(body["temp_c"].is_null());
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/sensors/missing")
+            .reply(&routes)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["code"], 404);
+        assert_eq!(body["message"], "NotFound: sensor missing not found");
+    }
+
+    #[tokio::test]
+    async fn test_api_routes_auth() {
+        let auth = Authenticator::new(&AuthConfig {
+            tokens: Some(vec![api_token(
+                "grafana",
+                Role::ReadOnly,
+                // echo -n grafana | sha256sum
+                "cace491b69555e8d0f77747d47ae54e31ce4cc322fe51a7bdcf64402f3676ebf",
+            )]),
+            public_metrics: None,
+        });
+        let routes = api_routes(sensor_manager(), auth, Vec::new()).recover(handle_rejection);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/switches")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/switches")
+            .header("authorization", "Bearer grafana")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Tokens in the query string aren't accepted
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/switches?access_token=grafana")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/switches/fan")
+            .header("authorization", "Bearer grafana")
+            .json(&serde_json::json!({"state": 1}))
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_live_routes_auth() {
+        let auth = Authenticator::new(&AuthConfig {
+            tokens: Some(vec![api_token(
+                "grafana",
+                Role::ReadOnly,
+                // echo -n grafana | sha256sum
+                "cace491b69555e8d0f77747d47ae54e31ce4cc322fe51a7bdcf64402f3676ebf",
+            )]),
+            public_metrics: None,
+        });
+        let routes = live_routes(EventBus::new(), auth, Vec::new());
+
+        // The UI reads the stream with fetch, sending the token in the authorization header.
+        // The stream doesn't end, so only the response head is read off a served socket.
+        let (addr, server) = warp::serve(routes.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let head = tokio::task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(
+                    b"GET /api/v1/events HTTP/1.1\r\nhost: localhost\r\n\
+                      accept: text/event-stream\r\nauthorization: Bearer grafana\r\n\r\n",
+                )
+                .unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0u8; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            String::from_utf8(head).unwrap().to_lowercase()
+        })
+        .await
+        .unwrap();
+        assert!(head.starts_with("http/1.1 200 ok\r\n"), "{}", head);
+        assert!(head.contains("content-type: text/event-stream\r\n"), "{}", head);
+
+        // Like an EventSource, which can't send the header
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/events?access_token=grafana")
+            .header("accept", "text/event-stream")
+            .reply(&routes.recover(handle_rejection))
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_config_reload() {
+        let dir = TempDir::new("reload");
+        let path = dir.join("gha.yaml");
+        let state_file = dir.join("gha_state.json");
+        let yaml = |switches: &str| {
+            format!(
+                "gpio_backend: simulated\nstate_file: {}\nhistory:\n  enabled: false\n\
+                 dht_configs: []\nswitch_devices:\n{}",
+                state_file.display(),
+                switches
+            )
+        };
+        std::fs::write(
+            &path,
+            yaml("  - {gpio_pin: 18, name: fan}\n  - {gpio_pin: 23, name: heater}\n"),
+        )
+        .unwrap();
+        let config = GHAConfig::load(&path).unwrap();
+        let sensor_manager = SensorManager::new(&config);
+        let auth = Authenticator::new(&AuthConfig::default());
+        let reloader = ConfigReloader::new(&path, sensor_manager.clone());
+        let routes = config_routes(reloader, auth.clone(), Vec::new())
+            .or(api_routes(sensor_manager.clone(), auth, Vec::new()))
+            .recover(handle_rejection);
+        sensor_manager.switch_on("heater").await.unwrap();
+
+        // heater moves to pin 24, fan is removed and vent added, listen_port needs a restart
+        std::fs::write(
+            &path,
+            format!(
+                "listen_port: 9000\n{}",
+                yaml("  - {gpio_pin: 24, name: heater}\n  - {gpio_pin: 18, name: vent}\n")
+            ),
+        )
+        .unwrap();
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/v1/config/reload")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["switches_added"], serde_json::json!(["vent"]));
+        assert_eq!(body["switches_updated"], serde_json::json!(["heater"]));
+        assert_eq!(body["switches_removed"], serde_json::json!(["fan"]));
+        assert_eq!(body["restart_required"], serde_json::json!(["listen_port"]));
+        assert_eq!(sensor_manager.listen_port().await, 6666);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/switches/heater")
+            .reply(&routes)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["pin_num"], 24);
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/switches/fan")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // An invalid file keeps the running config
+        std::fs::write(
+            &path,
+            yaml("  - {gpio_pin: 24, name: heater}\n  - {gpio_pin: 24, name: vent}\n"),
+        )
+        .unwrap();
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/v1/config/reload")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = warp::test::request()
+            .method("GET")
+            .path("/api/v1/switches/vent")
+            .reply(&routes)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["pin_num"], 18);
+    }
+
+    #[tokio::test]
+    async fn test_config_patch() {
+        let dir = TempDir::new("patch");
+        let path = dir.join("gha.yaml");
+        let backup = dir.join("gha.yaml.bak");
+        let yaml = format!(
+            "# greenhouse\ngpio_backend: simulated\nstate_file: {}\nhistory:\n  enabled: false\n\
+             dht_configs: []\n# relays\nswitch_devices:\n  \
+             - {{gpio_pin: 18, name: fan, auto: true}}\n  \
+             - {{gpio_pin: 23, name: heater, auto: true}}\n\n# none yet\nmonitors: []\n",
+            dir.join("gha_state.json").display()
+        );
+        std::fs::write(&path, &yaml).unwrap();
+        let sensor_manager = SensorManager::new(&GHAConfig::load(&path).unwrap());
+        let auth = Authenticator::new(&AuthConfig::default());
+        let reloader = ConfigReloader::new(&path, sensor_manager.clone());
+        let routes = config_routes(reloader, auth, Vec::new()).recover(handle_rejection);
+
+        // Entries are merged by name
+        let res = warp::test::request()
+            .method("PATCH")
+            .path("/api/v1/config")
+            .json(&serde_json::json!({
+                "switch_devices": [
+                    {"name": "fan", "auto": false},
+                    {"name": "heater", "removed": true},
+                    {"gpio_pin": 24, "name": "vent"}
+                ]
+            }))
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["switches_added"], serde_json::json!(["vent"]));
+        assert_eq!(body["switches_updated"], serde_json::json!(["fan"]));
+        assert_eq!(body["switches_removed"], serde_json::json!(["heater"]));
+        let switch_state = sensor_manager.switch_manager().find_switch("fan").await.unwrap();
+        assert_eq!(serde_json::to_value(switch_state).unwrap()["is_auto"], false);
+        // The patched setting is saved, the rest of the file and its comments are kept
+        let saved = GHAConfig::load(&path).unwrap();
+        let switch_devices = saved.switch_devices.unwrap();
+        assert_eq!(switch_devices.len(), 2);
+        assert_eq!(switch_devices[0].gpio_pin, Some(18));
+        assert_eq!(switch_devices[0].auto, Some(false));
+        assert_eq!(saved.state_file, sensor_manager.config().await.unwrap().state_file);
+        let saved_yaml = std::fs::read_to_string(&path).unwrap();
+        for comment in ["# greenhouse\n", "# relays\nswitch_devices:", "\n\n# none yet\n"] {
+            assert!(saved_yaml.contains(comment), "{:?} is lost", comment);
+        }
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), yaml);
+
+        // Unknown settings and invalid results aren't saved
+        for patch in [
+            serde_json::json!({"listen_prot": 8000}),
+            serde_json::json!({"dht_configs": [{"gpio_pin": 18, "name": "outside"}]}),
+        ] {
+            let res = warp::test::request()
+                .method("PATCH")
+                .path("/api/v1/config")
+                .json(&patch)
+                .reply(&routes)
+                .await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+        assert!(GHAConfig::load(&path).unwrap().dht_configs.is_empty());
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Timelike};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{parse_duration, Schedule, ScheduleWindow};
+use crate::error::GHAError;
+use crate::sensor_manager::SensorManager;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// How far ahead the scheduler looks for upcoming transitions
+const PLAN_HORIZON_DAYS: i64 = 8;
+
+/// Number of upcoming transitions reported per switch in `/switches_state`
+const NEXT_TRANSITION_COUNT: usize = 2;
+
+/// A planned change of a scheduled switch device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ScheduledTransition {
+    /// Local time of the transition, RFC 3339
+    at: String,
+    pin_state: u32,
+}
+
+/// Parsed `Schedule`: the minutes that windows start at and how long each lasts
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ScheduleWindows {
+    starts: WindowStarts,
+    duration: TimeDelta,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum WindowStarts {
+    /// Minute of the day
+    Daily(u32),
+    /// Every n minutes from midnight
+    Interval(u32),
+    Cron(CronExpression),
+}
+
+impl ScheduleWindows {
+    pub(crate) fn parse(schedule: &Schedule) -> Result<Self, String> {
+        let (starts, duration) = match &schedule.window {
+            ScheduleWindow::Daily { start, end } => {
+                let start = parse_time_of_day(start)?;
+                let end = parse_time_of_day(end)?;
+                if start == end {
+                    return Err("daily start and end must differ".to_string());
+                }
+                // A window ending before it starts runs past midnight
+                let minutes = (end + MINUTES_PER_DAY - start) % MINUTES_PER_DAY;
+                (WindowStarts::Daily(start), TimeDelta::minutes(minutes as i64))
+            }
+            ScheduleWindow::Interval { every, duration } => {
+                let every = parse_duration(every)?;
+                let duration = parse_duration(duration)?;
+                if every.num_seconds() % 60 != 0 || every > TimeDelta::days(1) {
+                    return Err("interval every must be whole minutes, up to 1d".to_string());
+                }
+                if duration >= every {
+                    return Err("interval duration must be shorter than every".to_string());
+                }
+                (WindowStarts::Interval(every.num_minutes() as u32), duration)
+            }
+            ScheduleWindow::Cron {
+                expression,
+                duration,
+            } => (
+                WindowStarts::Cron(CronExpression::parse(expression)?),
+                parse_duration(duration)?,
+            ),
+        };
+        if duration > TimeDelta::days(1) {
+            return Err("duration can not be longer than 1d".to_string());
+        }
+        Ok(Self { starts, duration })
+    }
+
+    /// First window start at or after `t`, if there's one within the plan horizon
+    fn next_start(&self, t: NaiveDateTime) -> Option<NaiveDateTime> {
+        let t = ceil_minute(t);
+        let minute_of_day = t.hour() * 60 + t.minute();
+        let (date, start) = match &self.starts {
+            WindowStarts::Daily(start) if *start >= minute_of_day => (t.date(), *start),
+            WindowStarts::Daily(start) => (t.date().succ_opt()?, *start),
+            WindowStarts::Interval(every) => {
+                let start = minute_of_day.div_ceil(*every) * every;
+                if start < MINUTES_PER_DAY {
+                    (t.date(), start)
+                } else {
+                    // Intervals restart at midnight
+                    (t.date().succ_opt()?, 0)
+                }
+            }
+            WindowStarts::Cron(expression) => return expression.next_start(t),
+        };
+        date.and_hms_opt(start / 60, start % 60, 0)
+    }
+
+    /// First window that hasn't ended at `t`, as (start, end)
+    fn next_window(&self, t: NaiveDateTime) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        let start = self.next_start(t - self.duration)?;
+        let start = if start + self.duration > t {
+            start
+        } else {
+            self.next_start(start + TimeDelta::minutes(1))?
+        };
+        Some((start, start + self.duration))
+    }
+}
+
+/// Round `t` up to a whole minute
+fn ceil_minute(t: NaiveDateTime) -> NaiveDateTime {
+    let minute = t.with_second(0).and_then(|m| m.with_nanosecond(0)).unwrap_or(t);
+    if minute < t {
+        minute + TimeDelta::minutes(1)
+    } else {
+        minute
+    }
+}
+
+/// Whether a switch device should be on now and when that changes next, from all of
+/// its schedules
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SchedulePlan {
+    is_on: bool,
+    transitions: Vec<(NaiveDateTime, bool)>,
+}
+
+impl SchedulePlan {
+    /// Plan the next `count` transitions from `now`, walking the windows of every schedule
+    /// in start order
+    pub(crate) fn new(schedules: &[ScheduleWindows], now: NaiveDateTime, count: usize) -> Self {
+        let horizon = now + TimeDelta::days(PLAN_HORIZON_DAYS);
+        let mut next: Vec<Option<(NaiveDateTime, NaiveDateTime)>> = schedules
+            .iter()
+            .map(|schedule| schedule.next_window(now))
+            .collect();
+
+        // Merge overlapping and back to back windows so the switch isn't toggled
+        // between them. A window is final once a later one doesn't overlap it, and each
+        // gives at least one transition.
+        let mut merged: Vec<(NaiveDateTime, NaiveDateTime)> = Vec::new();
+        while merged.len() <= count {
+            let earliest = next
+                .iter()
+                .enumerate()
+                .filter_map(|(i, window)| window.map(|window| (i, window)))
+                .min_by_key(|(_, window)| *window);
+            let Some((i, (start, end))) = earliest else {
+                break;
+            };
+            if start >= horizon {
+                break;
+            }
+            next[i] = schedules[i]
+                .next_start(start + TimeDelta::minutes(1))
+                .map(|start| (start, start + schedules[i].duration));
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        let is_on = merged.first().map(|(start, _)| *start <= now).unwrap_or(false);
+        let mut transitions = Vec::with_capacity(merged.len() * 2);
+        for (start, end) in merged {
+            if start > now {
+                transitions.push((start, true));
+            }
+            transitions.push((end, false));
+        }
+        transitions.truncate(count);
+        Self { is_on, transitions }
+    }
+
+    pub(crate) fn next_transitions(&self) -> Vec<ScheduledTransition> {
+        self.transitions
+            .iter()
+            .map(|(at, is_on)| ScheduledTransition {
+                at: local_rfc3339(at),
+                pin_state: if *is_on { 1 } else { 0 },
+            })
+            .collect()
+    }
+}
+
+fn local_rfc3339(t: &NaiveDateTime) -> String {
+    match Local.from_local_datetime(t).earliest() {
+        Some(t) => t.to_rfc3339(),
+        // Skipped by a DST change
+        None => t.format("%Y-%m-%dT%H:%M:%S").to_string(),
+    }
+}
+
+/// Turns scheduled auto switch devices on and off as their schedule windows start and
+/// end.
+#[derive(Debug, Clone)]
+pub(crate) struct Scheduler {
+    sensor_manager: SensorManager,
+    // Whether each switch was last scheduled on, by switch name
+    active: BTreeMap<String, bool>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(sensor_manager: SensorManager) -> Self {
+        Self {
+            sensor_manager,
+            active: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) async fn tick(&mut self, now: DateTime<Local>) -> Result<(), GHAError> {
+        let config = self.sensor_manager.config().await?;
+        let switch_manager = self.sensor_manager.switch_manager();
+        for switch_device in config.switch_devices.iter().flatten() {
+            let schedules: Vec<ScheduleWindows> = switch_device
+                .schedules
+                .iter()
+                .flatten()
+                .filter_map(|schedule| ScheduleWindows::parse(schedule).ok())
+                .collect();
+            if schedules.is_empty() {
+                continue;
+            }
+
+            let plan = SchedulePlan::new(
+                schedules.as_slice(),
+                now.naive_local(),
+                NEXT_TRANSITION_COUNT,
+            );
+            switch_manager
+                .update_next_transitions(
+                    switch_device.pin(),
+                    plan.next_transitions(),
+                )
+                .await?;
+
+            // Only act when a window starts or ends so monitors and manual changes
+            // aren't undone on every tick
+            let name = &switch_device.name;
+            if self.active.get(name) != Some(&plan.is_on) {
+                info!(
+                    "schedule for {} is {}",
+                    name,
+                    if plan.is_on { "on" } else { "off" }
+                );
+                if plan.is_on {
+                    self.sensor_manager.auto_switch_on(name).await?;
+                } else {
+                    self.sensor_manager.auto_switch_off(name).await?;
+                }
+                self.active.insert(name.clone(), plan.is_on);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) async fn start_scheduler_loop(sensor_manager: SensorManager) -> Result<(), GHAError> {
+    let delay: u64 = 5_000;
+    let mut scheduler = Scheduler::new(sensor_manager.clone());
+    tokio::spawn(async move {
+        while !sensor_manager.is_shutting_down() {
+            if let Err(e) = scheduler.tick(Local::now()).await {
+                error!("Error running schedules: {}", e);
+            }
+            // Publish any switch the schedules changed
+            sensor_manager.update_pin_state_gauges().await;
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        info!("Scheduler loop stopped");
+        Ok(())
+    })
+    .await?
+}
+
+/// Parse `HH:MM` into minutes since midnight
+fn parse_time_of_day(value: &str) -> Result<u32, String> {
+    let (hours, minutes) = value
+        .split_once(':')
+        .ok_or(format!("{} is not a HH:MM time", value))?;
+    match (hours.parse::<u32>(), minutes.parse::<u32>()) {
+        (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 => Ok(hours * 60 + minutes),
+        _ => Err(format!("{} is not a HH:MM time", value)),
+    }
+}
+
+/// A 5 field `minute hour day-of-month month day-of-week` cron expression, with
+/// support for `*`, lists, ranges and steps.
+#[derive(Debug, Clone, PartialEq)]
+struct CronExpression {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    // Like cron, when both day fields are restricted either one matching is enough
+    days_restricted: bool,
+}
+
+impl CronExpression {
+    fn parse(expression: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "cron expression {} must have 5 fields: minute hour day-of-month month day-of-week",
+                expression
+            ));
+        }
+        let mut days_of_week = parse_cron_field(fields[4], 0, 7)?;
+        // 7 is also sunday
+        if days_of_week & (1 << 7) > 0 {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_cron_field(fields[0], 0, 59)?,
+            hours: parse_cron_field(fields[1], 0, 23)?,
+            days_of_month: parse_cron_field(fields[2], 1, 31)?,
+            months: parse_cron_field(fields[3], 1, 12)?,
+            days_of_week,
+            days_restricted: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+        })
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day_of_month = is_set(self.days_of_month, date.day());
+        let day_of_week = is_set(self.days_of_week, date.weekday().num_days_from_sunday());
+        let day = if self.days_restricted {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        };
+        day && is_set(self.months, date.month())
+    }
+
+    /// First matching minute at or after `t`, which is a whole minute, looking a day past
+    /// the plan horizon
+    fn next_start(&self, t: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut date = t.date();
+        let mut from = (t.hour(), t.minute());
+        for _ in 0..=PLAN_HORIZON_DAYS + 1 {
+            if self.matches_day(date) {
+                for hour in (from.0..24).filter(|hour| is_set(self.hours, *hour)) {
+                    let first_minute = if hour == from.0 { from.1 } else { 0 };
+                    let minutes = self.minutes & !((1u64 << first_minute) - 1);
+                    if minutes > 0 {
+                        return date.and_hms_opt(hour, minutes.trailing_zeros(), 0);
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+            from = (0, 0);
+        }
+        None
+    }
+}
+
+fn is_set(mask: u64, v: u32) -> bool {
+    mask & (1 << v) > 0
+}
+
+/// Parse one cron field into a bit mask of the values it matches
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("invalid cron field {}, values are {}-{}", field, min, max);
+    let mut mask: u64 = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u32>().map_err(|_| invalid())?)),
+            None => (part, None),
+        };
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some((lo, hi)) = range.split_once('-') {
+            (
+                lo.parse::<u32>().map_err(|_| invalid())?,
+                hi.parse::<u32>().map_err(|_| invalid())?,
+            )
+        } else {
+            let v = range.parse::<u32>().map_err(|_| invalid())?;
+            // `5/15` means from 5 to the end in steps of 15
+            (v, if step.is_some() { max } else { v })
+        };
+        let step = step.unwrap_or(1);
+        if step == 0 || lo < min || hi > max || lo > hi {
+            return Err(invalid());
+        }
+        for v in (lo..=hi).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{NaiveDate, NaiveDateTime, TimeDelta};
+
+    use crate::config::{Schedule, ScheduleWindow};
+    use crate::scheduler::{CronExpression, SchedulePlan, ScheduleWindows};
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2024-06-02 is a sunday
+        NaiveDate::from_ymd_opt(2024, 6, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cron_expression() {
+        let weekdays = CronExpression::parse("*/15 6-8 * * 1-5").unwrap();
+        assert_eq!(weekdays.next_start(at(3, 6, 45)), Some(at(3, 6, 45)));
+        assert_eq!(weekdays.next_start(at(3, 6, 50)), Some(at(3, 7, 0)));
+        assert_eq!(weekdays.next_start(at(3, 9, 0)), Some(at(4, 6, 0)));
+        // Saturday to monday
+        assert_eq!(weekdays.next_start(at(1, 7, 0)), Some(at(3, 6, 0)));
+
+        let sundays = CronExpression::parse("0 12 * * 7").unwrap();
+        assert_eq!(sundays.next_start(at(2, 12, 0)), Some(at(2, 12, 0)));
+        assert_eq!(sundays.next_start(at(2, 12, 1)), Some(at(9, 12, 0)));
+
+        // Either day field matches when both are restricted
+        let days = CronExpression::parse("0 0 1 * 0").unwrap();
+        assert_eq!(days.next_start(at(1, 0, 0)), Some(at(1, 0, 0)));
+        assert_eq!(days.next_start(at(1, 0, 1)), Some(at(2, 0, 0)));
+        assert_eq!(days.next_start(at(2, 0, 1)), Some(at(9, 0, 0)));
+
+        // Past the plan horizon
+        let leap_day = CronExpression::parse("0 0 29 2 *").unwrap();
+        assert_eq!(leap_day.next_start(at(3, 0, 0)), None);
+
+        assert!(CronExpression::parse("0 6 * *").is_err());
+        assert!(CronExpression::parse("60 6 * * *").is_err());
+        assert!(CronExpression::parse("*/0 6 * * *").is_err());
+    }
+
+    #[test]
+    fn test_daily_plan() {
+        let daily = ScheduleWindows::parse(&Schedule {
+            window: ScheduleWindow::Daily {
+                start: "06:00".to_string(),
+                end: "06:10".to_string(),
+            },
+        })
+        .unwrap();
+
+        let plan = SchedulePlan::new(std::slice::from_ref(&daily), at(3, 5, 0), 3);
+        assert!(!plan.is_on);
+        assert_eq!(plan.transitions[0], (at(3, 6, 0), true));
+        assert_eq!(plan.transitions[1], (at(3, 6, 10), false));
+        assert_eq!(plan.transitions[2], (at(4, 6, 0), true));
+
+        let plan = SchedulePlan::new(&[daily], at(3, 6, 5), 3);
+        assert!(plan.is_on);
+        assert_eq!(plan.transitions[0], (at(3, 6, 10), false));
+        assert_eq!(plan.transitions[1], (at(4, 6, 0), true));
+
+        // Windows past midnight
+        let overnight = ScheduleWindows::parse(&Schedule {
+            window: ScheduleWindow::Daily {
+                start: "22:00".to_string(),
+                end: "02:00".to_string(),
+            },
+        })
+        .unwrap();
+        let plan = SchedulePlan::new(&[overnight], at(3, 1, 0), 3);
+        assert!(plan.is_on);
+        assert_eq!(plan.transitions[0], (at(3, 2, 0), false));
+    }
+
+    #[test]
+    fn test_interval_plan() {
+        let every_4h = ScheduleWindows::parse(&Schedule {
+            window: ScheduleWindow::Interval {
+                every: "4h".to_string(),
+                duration: "2m".to_string(),
+            },
+        })
+        .unwrap();
+        let plan = SchedulePlan::new(std::slice::from_ref(&every_4h), at(3, 9, 0), 3);
+        assert!(!plan.is_on);
+        assert_eq!(plan.transitions[0], (at(3, 12, 0), true));
+        assert_eq!(plan.transitions[1], (at(3, 12, 2), false));
+        assert_eq!(plan.transitions[2], (at(3, 16, 0), true));
+
+        // Part way through a window, and past the last start of the day
+        let plan = SchedulePlan::new(
+            std::slice::from_ref(&every_4h),
+            at(3, 12, 1) + TimeDelta::seconds(30),
+            2,
+        );
+        assert!(plan.is_on);
+        assert_eq!(plan.transitions, vec![(at(3, 12, 2), false), (at(3, 16, 0), true)]);
+        let plan = SchedulePlan::new(&[every_4h], at(3, 20, 5), 1);
+        assert_eq!(plan.transitions, vec![(at(4, 0, 0), true)]);
+
+        assert!(ScheduleWindows::parse(&Schedule {
+            window: ScheduleWindow::Interval {
+                every: "10m".to_string(),
+                duration: "10m".to_string(),
+            },
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_overlapping_schedules_merge() {
+        let morning = ScheduleWindows::parse(&Schedule {
+            window: ScheduleWindow::Daily {
+                start: "06:00".to_string(),
+                end: "07:00".to_string(),
+            },
+        })
+        .unwrap();
+        let cron = ScheduleWindows::parse(&Schedule {
+            window: ScheduleWindow::Cron {
+                expression: "30 6 * * *".to_string(),
+                duration: "1h".to_string(),
+            },
+        })
+        .unwrap();
+        let plan = SchedulePlan::new(&[morning, cron], at(3, 5, 0), 3);
+        assert_eq!(plan.transitions[0], (at(3, 6, 0), true));
+        assert_eq!(plan.transitions[1], (at(3, 7, 30), false));
+    }
+}
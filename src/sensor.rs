@@ -1,8 +1,21 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::error::Error;
 use std::fmt::Formatter;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
 
+use gpio_cdev::{Chip, Line, LineHandle, LineRequestFlags};
+use log::error;
 use rppal::gpio::{Gpio, IoPin, Mode};
 
+use crate::ads1115::Ads1115Sensor;
+use crate::bme280::Bme280Sensor;
+use crate::config::{GHAConfig, GpioBackendKind, GpioLine, SensorConfig};
+use crate::dht22::DHT22Sensor;
+use crate::ds18b20::DS18B20Sensor;
+use crate::i2c;
+use crate::sht31::Sht31Sensor;
+
 /// Temperature, in degrees celsius
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(transparent)]
@@ -49,15 +62,81 @@ impl std::fmt::Display for Humidity {
     }
 }
 
+/// A value read from a sensor
+#[derive(Copy, Clone, Debug, PartialEq)]
+// Not every kind of value has a sensor type yet
+#[allow(dead_code)]
+pub enum Measurement {
+    Temperature(TemperatureCelsius),
+    Humidity(Humidity),
+    /// Barometric pressure in hectopascals
+    Pressure(f64),
+    /// Soil moisture in percent, from dry at 0 to wet at 100
+    Moisture(f64),
+    /// Illuminance in lux
+    Light(f64),
+    /// CO2 concentration in parts per million
+    Co2(f64),
+}
+
+/// A sensor the sensor workers read, opened for each read by `open_sensor`
+pub trait Sensor: std::fmt::Debug + Send {
+    /// Read the sensor once, returning a measurement of each value it measures
+    fn measure(&mut self) -> Result<Vec<Measurement>, SensorError>;
+}
+
+/// Open the sensor of a `sensors` or `dht_configs` entry, 1-Wire probes are looked up in
+/// `w1_devices_dir` and I2C sensors are opened on their bus
+pub(crate) fn open_sensor(
+    sensor_config: &SensorConfig,
+    gpio: &dyn GpioBackend,
+    w1_devices_dir: &Path,
+) -> Result<Box<dyn Sensor>, SensorError> {
+    match sensor_config {
+        SensorConfig::Dht(dht_config) => {
+            let pin = gpio.open_pin(dht_config.pin(), Mode::Input)?;
+            Ok(Box::new(DHT22Sensor::from_pin(pin, dht_config.model())))
+        }
+        SensorConfig::Ds18b20(ds18b20_config) => Ok(Box::new(DS18B20Sensor::open(
+            w1_devices_dir,
+            &ds18b20_config.rom_id,
+        )?)),
+        SensorConfig::Bme280(_) | SensorConfig::Bmp280(_) => {
+            let (bus, address) = sensor_config.i2c_address().unwrap_or_default();
+            let with_humidity = matches!(sensor_config, SensorConfig::Bme280(_));
+            Ok(Box::new(Bme280Sensor::new(
+                i2c::open_device(bus, address)?,
+                with_humidity,
+            )?))
+        }
+        SensorConfig::Sht31(_) => {
+            let (bus, address) = sensor_config.i2c_address().unwrap_or_default();
+            Ok(Box::new(Sht31Sensor::new(i2c::open_device(bus, address)?)))
+        }
+        SensorConfig::Ads1115(ads1115_config) => {
+            let (bus, address) = sensor_config.i2c_address().unwrap_or_default();
+            Ok(Box::new(Ads1115Sensor::new(
+                i2c::open_device(bus, address)?,
+                ads1115_config,
+            )))
+        }
+    }
+}
+
 /// Potential kinds of errors that can be encountered reading from the DHT sensor
 #[derive(PartialEq, Eq, Debug, Hash, Clone, Copy)]
 pub enum SensorErrorKind {
     Initialization,
     ReadTimeout,
+    /// Reading a sensor's device file failed
+    Io,
+    /// A sensor returned data that couldn't be parsed
+    InvalidData,
 }
 
-/// Error initializing or reading the DHT22 sensor via a GPIO pin
+/// Error initializing or reading a sensor
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum SensorError {
     ValueBounds(String),
     CheckSum(u8, u8),
@@ -113,15 +192,19 @@ pub fn open_pin(bcm_gpio_pin: u8, mode: Mode) -> Result<IoPin, SensorError> {
         )
     })?;
 
-    let io_pin = pin.into_io(mode);
+    let mut io_pin = pin.into_io(mode);
+    if mode == Mode::Output {
+        // Keep outputs at their level after exit so the shutdown safe states stick
+        io_pin.set_reset_on_drop(false);
+    }
     Ok(io_pin)
 }
 
 /// Abstraction around an `rppal::gpio::IoPin` to allow for easier testing.
-pub trait DataPin {
+pub trait DataPin: std::fmt::Debug {
     fn is_low(&self) -> bool;
     fn is_high(&self) -> bool;
-    fn pin(&self) -> u8;
+    fn pin(&self) -> u32;
     fn set_high(&mut self);
     fn set_low(&mut self);
     fn set_mode(&mut self, mode: Mode);
@@ -136,8 +219,8 @@ impl DataPin for IoPin {
         IoPin::is_high(self)
     }
 
-    fn pin(&self) -> u8 {
-        IoPin::pin(self)
+    fn pin(&self) -> u32 {
+        IoPin::pin(self) as u32
     }
 
     fn set_high(&mut self) {
@@ -152,3 +235,412 @@ impl DataPin for IoPin {
         IoPin::set_mode(self, mode);
     }
 }
+
+impl<T: DataPin + ?Sized> DataPin for Box<T> {
+    fn is_low(&self) -> bool {
+        (**self).is_low()
+    }
+
+    fn is_high(&self) -> bool {
+        (**self).is_high()
+    }
+
+    fn pin(&self) -> u32 {
+        (**self).pin()
+    }
+
+    fn set_high(&mut self) {
+        (**self).set_high()
+    }
+
+    fn set_low(&mut self) {
+        (**self).set_low()
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        (**self).set_mode(mode)
+    }
+}
+
+/// A boxed `DataPin` handed out by a `GpioBackend`.
+pub type BoxedDataPin = Box<dyn DataPin + Send + Sync + 'static>;
+
+/// Source of GPIO pins for the sensor workers and output switches.
+///
+/// This lets the agent run against real hardware on a Raspberry Pi or against an
+/// in-memory simulation on any other machine.
+pub trait GpioBackend: std::fmt::Debug + Send + Sync {
+    /// Open the pin with the given `gpio_pin` number in the given mode.
+    fn open_pin(&self, pin: u32, mode: Mode) -> Result<BoxedDataPin, SensorError>;
+
+    /// Use the `gpio_line`s of a reloaded config, only the cdev backend has lines
+    fn set_lines(&self, _lines: BTreeMap<u32, GpioLine>) {}
+}
+
+/// Create the `GpioBackend` selected by `gpio_backend` in the gha.yaml config.
+pub(crate) fn create_gpio_backend(gha_config: &GHAConfig) -> Arc<dyn GpioBackend> {
+    match gha_config.gpio_backend() {
+        GpioBackendKind::Rppal => Arc::new(RppalGpio),
+        GpioBackendKind::Simulated => Arc::new(SimulatedGpio::default()),
+        GpioBackendKind::Cdev => Arc::new(CdevGpio::new(
+            gha_config.gpio_chip.clone().unwrap_or("gpiochip0".to_string()),
+            gha_config.gpio_lines(),
+        )),
+    }
+}
+
+/// `GpioBackend` for the Broadcom SoC of a Raspberry Pi, via rppal.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RppalGpio;
+
+impl GpioBackend for RppalGpio {
+    fn open_pin(&self, pin: u32, mode: Mode) -> Result<BoxedDataPin, SensorError> {
+        let pin = u8::try_from(pin).map_err(|e| {
+            SensorError::KindMsgCause(
+                SensorErrorKind::Initialization,
+                "pin number out of range",
+                Box::new(e),
+            )
+        })?;
+        Ok(Box::new(open_pin(pin, mode)?))
+    }
+}
+
+/// Consumer label the agent's lines are requested with, as shown by `gpioinfo`.
+const CDEV_CONSUMER: &str = "greenhouse-agent";
+
+/// `GpioBackend` for the Linux GPIO character device uAPI, for boards other than a
+/// Raspberry Pi.
+#[derive(Debug, Clone)]
+pub struct CdevGpio {
+    default_chip: String,
+    lines: Arc<RwLock<BTreeMap<u32, GpioLine>>>,
+}
+
+impl CdevGpio {
+    pub(crate) fn new(default_chip: String, lines: BTreeMap<u32, GpioLine>) -> Self {
+        Self {
+            default_chip,
+            lines: Arc::new(RwLock::new(lines)),
+        }
+    }
+
+    /// Chip path and line offset for the pin, falling back to offset `pin` on the
+    /// default chip.
+    fn line_address(&self, pin: u32) -> (String, u32) {
+        let (chip, offset) = match self.lines.read().unwrap().get(&pin) {
+            Some(line) => (line.chip.clone(), line.offset),
+            None => (None, pin),
+        };
+        let chip = chip.unwrap_or(self.default_chip.clone());
+        if chip.starts_with('/') {
+            (chip, offset)
+        } else {
+            (format!("/dev/{}", chip), offset)
+        }
+    }
+}
+
+impl GpioBackend for CdevGpio {
+    fn set_lines(&self, lines: BTreeMap<u32, GpioLine>) {
+        *self.lines.write().unwrap() = lines;
+    }
+
+    fn open_pin(&self, pin: u32, mode: Mode) -> Result<BoxedDataPin, SensorError> {
+        let (chip_path, offset) = self.line_address(pin);
+        let mut chip = Chip::new(&chip_path).map_err(|e| {
+            SensorError::KindMsgCause(
+                SensorErrorKind::Initialization,
+                "unable to open GPIO chip",
+                Box::new(e),
+            )
+        })?;
+        let line = chip.get_line(offset).map_err(|e| {
+            SensorError::KindMsgCause(
+                SensorErrorKind::Initialization,
+                "unable to acquire line from chip",
+                Box::new(e),
+            )
+        })?;
+        let handle = CdevPin::request(&line, mode, 0).map_err(|e| {
+            SensorError::KindMsgCause(
+                SensorErrorKind::Initialization,
+                "unable to request line",
+                Box::new(e),
+            )
+        })?;
+        Ok(Box::new(CdevPin {
+            pin,
+            line,
+            mode,
+            handle: Some(handle),
+        }))
+    }
+}
+
+/// A requested character device line. Changing the mode releases the line and
+/// requests it again with the new direction.
+#[derive(Debug)]
+struct CdevPin {
+    pin: u32,
+    line: Line,
+    mode: Mode,
+    handle: Option<LineHandle>,
+}
+
+impl CdevPin {
+    fn request(line: &Line, mode: Mode, value: u8) -> Result<LineHandle, gpio_cdev::Error> {
+        let flags = match mode {
+            Mode::Output => LineRequestFlags::OUTPUT,
+            _ => LineRequestFlags::INPUT,
+        };
+        line.request(flags, value, CDEV_CONSUMER)
+    }
+
+    fn value(&self) -> Option<u8> {
+        self.handle.as_ref().and_then(|handle| handle.get_value().ok())
+    }
+
+    fn set_value(&mut self, value: u8) {
+        if let Some(handle) = &self.handle {
+            if let Err(e) = handle.set_value(value) {
+                error!("unable to set line {} to {}: {}", self.pin, value, e);
+            }
+        }
+    }
+}
+
+impl DataPin for CdevPin {
+    fn is_low(&self) -> bool {
+        self.value() == Some(0)
+    }
+
+    fn is_high(&self) -> bool {
+        self.value() == Some(1)
+    }
+
+    fn pin(&self) -> u32 {
+        self.pin
+    }
+
+    fn set_high(&mut self) {
+        self.set_value(1)
+    }
+
+    fn set_low(&mut self) {
+        self.set_value(0)
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        if mode == self.mode && self.handle.is_some() {
+            return;
+        }
+        // Keep driving the current level when switching to an output
+        let value = self.value().unwrap_or(0);
+        self.handle = None;
+        match CdevPin::request(&self.line, mode, value) {
+            Ok(handle) => {
+                self.handle = Some(handle);
+                self.mode = mode;
+            }
+            Err(e) => error!("unable to request line {} as {:?}: {}", self.pin, mode, e),
+        }
+    }
+}
+
+/// Temperature and humidity reported by a simulated DHT22 when nothing else is set.
+const SIMULATED_DHT_READING: (f64, f64) = (21.0, 50.0);
+
+/// In-memory `GpioBackend` for running the agent off a Raspberry Pi.
+///
+/// Output pins remember the last level written to them, and every pin answers a DHT22
+/// read with the pulse train for its simulated temperature and humidity.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedGpio {
+    lines: Arc<std::sync::Mutex<BTreeMap<u32, SimulatedLine>>>,
+}
+
+#[derive(Debug)]
+struct SimulatedLine {
+    mode: Mode,
+    is_high: bool,
+    reading: (f64, f64),
+    pulses: VecDeque<bool>,
+}
+
+impl Default for SimulatedLine {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Input,
+            is_high: false,
+            reading: SIMULATED_DHT_READING,
+            pulses: VecDeque::new(),
+        }
+    }
+}
+
+impl SimulatedGpio {
+    /// Set the temperature and humidity a simulated DHT22 on `bcm_gpio_pin` reports.
+    #[cfg(test)]
+    pub fn set_dht_reading(&self, bcm_gpio_pin: u32, temp_c: f64, humidity: f64) {
+        let mut lines = self.lines.lock().unwrap();
+        lines.entry(bcm_gpio_pin).or_default().reading = (temp_c, humidity);
+    }
+}
+
+impl GpioBackend for SimulatedGpio {
+    fn open_pin(&self, pin: u32, mode: Mode) -> Result<BoxedDataPin, SensorError> {
+        let mut pin = SimulatedPin {
+            pin,
+            lines: self.lines.clone(),
+        };
+        pin.set_mode(mode);
+        Ok(Box::new(pin))
+    }
+}
+
+/// A pin handed out by `SimulatedGpio`, sharing its state with every other handle to
+/// the same pin number.
+#[derive(Debug)]
+struct SimulatedPin {
+    pin: u32,
+    lines: Arc<std::sync::Mutex<BTreeMap<u32, SimulatedLine>>>,
+}
+
+impl SimulatedPin {
+    fn with_line<R>(&self, f: impl FnOnce(&mut SimulatedLine) -> R) -> R {
+        let mut lines = self.lines.lock().unwrap();
+        f(lines.entry(self.pin).or_default())
+    }
+
+    /// Consume the next sample of a pending DHT22 pulse train, or the idle line level.
+    fn sample(&self) -> bool {
+        self.with_line(|line| match line.mode {
+            Mode::Output => line.is_high,
+            // The data line is pulled high when the sensor is not transmitting
+            _ => line.pulses.pop_front().unwrap_or(true),
+        })
+    }
+}
+
+impl DataPin for SimulatedPin {
+    fn is_low(&self) -> bool {
+        !self.sample()
+    }
+
+    fn is_high(&self) -> bool {
+        self.sample()
+    }
+
+    fn pin(&self) -> u32 {
+        self.pin
+    }
+
+    fn set_high(&mut self) {
+        self.with_line(|line| line.is_high = true)
+    }
+
+    fn set_low(&mut self) {
+        self.with_line(|line| line.is_high = false)
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.with_line(|line| {
+            // Releasing the line after the host start signal is when a DHT22 answers
+            if line.mode == Mode::Output && mode == Mode::Input {
+                let (temp_c, humidity) = line.reading;
+                line.pulses = dht22_pulses(temp_c, humidity);
+            }
+            line.mode = mode;
+        })
+    }
+}
+
+/// Build the low/high samples a DHT22 sends for the given reading, one sample per poll
+/// of the data pin.
+fn dht22_pulses(temp_c: f64, humidity: f64) -> VecDeque<bool> {
+    let humidity_raw = (humidity * 10.0).round() as u16;
+    let mut temp_raw = (temp_c.abs() * 10.0).round() as u16 & 0x7FFF;
+    if temp_c < 0.0 {
+        temp_raw |= 0x8000;
+    }
+    let [h0, h1] = humidity_raw.to_be_bytes();
+    let [t0, t1] = temp_raw.to_be_bytes();
+    let checksum = h0.wrapping_add(h1).wrapping_add(t0).wrapping_add(t1);
+
+    let mut pulses = VecDeque::new();
+    let mut push = |is_high: bool, count: usize| {
+        pulses.extend(std::iter::repeat_n(is_high, count));
+    };
+
+    // Sensor response: 80us low, 80us high
+    push(false, 80);
+    push(true, 80);
+    for byte in [h0, h1, t0, t1, checksum] {
+        for bit in (0..8).rev() {
+            // Each bit is 50us low followed by 26-28us high for a 0 or 70us high for a 1
+            push(false, 50);
+            push(true, if byte & (1 << bit) > 0 { 70 } else { 26 });
+        }
+    }
+    // End of transmission
+    push(false, 50);
+    pulses
+}
+
+#[cfg(test)]
+mod test {
+    use rppal::gpio::Mode;
+
+    use std::collections::BTreeMap;
+
+    use crate::config::{DhtModel, GpioLine};
+    use crate::dht22::DHT22Sensor;
+    use crate::sensor::{CdevGpio, GpioBackend, SimulatedGpio};
+
+    #[test]
+    fn test_cdev_line_address() {
+        let mut lines = BTreeMap::new();
+        lines.insert(18, GpioLine { chip: Some("gpiochip1".to_string()), offset: 4 });
+        lines.insert(23, GpioLine { chip: None, offset: 7 });
+        lines.insert(25, GpioLine { chip: Some("/dev/gpiochip2".to_string()), offset: 1 });
+        let gpio = CdevGpio::new("gpiochip0".to_string(), lines);
+
+        assert_eq!(("/dev/gpiochip1".to_string(), 4), gpio.line_address(18));
+        assert_eq!(("/dev/gpiochip0".to_string(), 7), gpio.line_address(23));
+        assert_eq!(("/dev/gpiochip2".to_string(), 1), gpio.line_address(25));
+        assert_eq!(("/dev/gpiochip0".to_string(), 12), gpio.line_address(12));
+    }
+
+    #[test]
+    fn test_simulated_output_pin() {
+        let gpio = SimulatedGpio::default();
+        let mut pin = gpio.open_pin(18, Mode::Output).unwrap();
+        assert!(pin.is_low());
+        pin.set_high();
+        assert!(pin.is_high());
+
+        // A second handle to the same pin sees the level written by the first
+        let other = gpio.open_pin(18, Mode::Output).unwrap();
+        assert!(other.is_high());
+        assert!(gpio.open_pin(23, Mode::Output).unwrap().is_low());
+    }
+
+    #[test]
+    fn test_simulated_dht22_read() {
+        let gpio = SimulatedGpio::default();
+        gpio.set_dht_reading(22, -3.4, 61.2);
+
+        let pin = gpio.open_pin(22, Mode::Input).unwrap();
+        let mut sensor = DHT22Sensor::from_pin(pin, DhtModel::Dht22);
+        let (temp_c, humidity) = sensor.read().unwrap();
+        assert_eq!(-3.4, f64::from(temp_c));
+        assert_eq!(61.2, f64::from(humidity));
+
+        let pin = gpio.open_pin(17, Mode::Input).unwrap();
+        let mut sensor = DHT22Sensor::from_pin(pin, DhtModel::Dht22);
+        let (temp_c, humidity) = sensor.read().unwrap();
+        assert_eq!(21.0, f64::from(temp_c));
+        assert_eq!(50.0, f64::from(humidity));
+    }
+}
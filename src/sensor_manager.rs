@@ -1,61 +1,123 @@
 use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicI64};
 use std::sync::atomic::Ordering::Relaxed;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use log::{debug, error, info, warn};
-use prometheus::{Gauge, Opts, Registry};
-use prometheus::core::{AtomicF64, GenericGauge};
-use rppal::gpio::IoPin;
-use rppal::gpio::Mode::{Input, Output};
+use rppal::gpio::Mode::Output;
 use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc, Mutex};
 use tokio::sync::mpsc::{Receiver, Sender};
 
-use crate::config::{DhtConfig, GHAConfig, SwitchDevice};
-use crate::dht22::DHT22Sensor;
-use crate::error::{GHAError, PinError};
-use crate::sensor::{Humidity, open_pin, TemperatureCelsius};
+use crate::config::{
+    ConfigChanges, GHAConfig, HistoryConfig, SensorConfig, SensorMetric, SensorType, SwitchDevice,
+    SwitchLevel,
+};
+use crate::ds18b20;
+use crate::error::{GHAError, PinError, RequestError};
+use crate::events::{EventBus, LiveEvent};
+use crate::history::HistoryStore;
+use crate::metrics::{Metrics, SensorMetrics, SwitchMetric};
+use crate::persistence::SwitchStateStore;
+use crate::scheduler::ScheduledTransition;
+use crate::sensor::{
+    BoxedDataPin, create_gpio_backend, GpioBackend, Measurement, open_sensor, SensorError,
+};
 
 #[derive(Debug, Clone)]
 pub(crate) struct SensorManager {
     config: Arc<Mutex<GHAConfig>>,
-    pub(crate) metrics_registry: Registry,
-    gauge_sender: Sender<DhtReadingTask>,
-    gauge_receiver: Arc<Mutex<Receiver<DhtReadingTask>>>,
+    gpio: Arc<dyn GpioBackend>,
+    /// Where 1-Wire probes are looked up
+    w1_devices_dir: PathBuf,
+    pub(crate) metrics: Metrics,
+    gauge_sender: Sender<SensorReadingTask>,
+    gauge_receiver: Arc<Mutex<Receiver<SensorReadingTask>>>,
     output_pin_state: OutputPinState,
     switch_manager: SwitchManager,
-    sensor_gauges: Arc<Mutex<Vec<DhtGauge>>>,
+    sensor_gauges: Arc<Mutex<Vec<SensorGauge>>>,
     switch_gauges: Arc<Mutex<Vec<SwitchGauge>>>,
+    history: HistoryStore,
+    events: EventBus,
+    /// Unix millis of the last monitor loop tick, 0 until the loop starts
+    monitor_progress: Arc<AtomicI64>,
+    /// Reading tasks of sensors added by a config reload are only sent once workers run
+    workers_started: Arc<AtomicBool>,
 }
 
 impl SensorManager {
     pub(crate) fn new(gha_config: &GHAConfig) -> Self {
-        // Create a prometheus metrics registry
-        let metrics_registry = Registry::new();
+        // Prometheus metrics named by the configured style
+        let metrics = Metrics::new(gha_config.metrics_style());
+
+        // GPIO backend for sensor and switch pins
+        let gpio = create_gpio_backend(gha_config);
+        info!("Using {:?} GPIO backend", gha_config.gpio_backend());
+        let w1_devices_dir = gha_config.w1_devices_dir().to_path_buf();
+        let sensor_configs = gha_config.sensor_configs();
+        if sensor_configs.iter().any(|s| matches!(s, SensorConfig::Ds18b20(_))) {
+            info!(
+                "Found DS18B20 probes: {:?}",
+                ds18b20::discover(&w1_devices_dir)
+            );
+        }
+
+        // Switch state saved by previous runs
+        let switch_state_store = SwitchStateStore::load(
+            gha_config.state_file.as_deref(),
+            SensorManager::switch_devices(gha_config),
+        );
+
+        // Recorded readings and switch transitions
+        let history = HistoryStore::new(
+            &gha_config.history(),
+            SensorManager::switch_devices(gha_config),
+        );
+
+        // Readings and switch changes pushed to live stream clients
+        let events = EventBus::new();
 
         // sensor reading task channels for async workers
-        let (gauge_sender, gauge_receiver) = mpsc::channel::<DhtReadingTask>(32);
+        let (gauge_sender, gauge_receiver) = mpsc::channel::<SensorReadingTask>(32);
         let gauge_receiver = Arc::new(Mutex::new(gauge_receiver));
         // Vec to hold gauges created from config
-        let sensor_gauges = SensorManager::create_dht_gauges(
-            gha_config.dht_configs.clone(),
-            metrics_registry.clone(),
+        let sensor_gauges = SensorManager::create_sensor_gauges(
+            sensor_configs,
+            &metrics,
+            history.clone(),
+            events.clone(),
+            gha_config.sensor_stale_after_millis(),
         );
         let switch_gauges = SensorManager::create_switch_gauges(
             gha_config.switch_devices.clone().unwrap().clone(),
-            metrics_registry.clone(),
+            &metrics,
         );
         SensorManager {
             config: Arc::new(Mutex::new(gha_config.clone())),
-            metrics_registry: metrics_registry.clone(),
+            output_pin_state: SensorManager::create_output_pin_state(
+                gha_config,
+                gpio.as_ref(),
+                switch_state_store.clone(),
+                history.clone(),
+            ),
+            gpio,
+            w1_devices_dir,
+            metrics,
             gauge_sender: gauge_sender.clone(),
             gauge_receiver: gauge_receiver.clone(),
-            output_pin_state: SensorManager::create_output_pin_state(gha_config),
-            switch_manager: SensorManager::create_switch_manager(gha_config),
+            switch_manager: SensorManager::create_switch_manager(
+                gha_config,
+                switch_state_store,
+                events.clone(),
+            ),
             sensor_gauges: Arc::new(Mutex::new(sensor_gauges)),
             switch_gauges: Arc::new(Mutex::new(switch_gauges)),
+            history,
+            events,
+            monitor_progress: Arc::new(AtomicI64::new(0)),
+            workers_started: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -71,36 +133,56 @@ impl SensorManager {
         self.switch_manager.clone()
     }
 
-    fn create_dht_gauges(dht_configs: Vec<DhtConfig>, metrics_registry: Registry) -> Vec<DhtGauge> {
-        // Vec to hold gauges created from config
-        let mut sensor_gauges: Vec<DhtGauge> = Vec::with_capacity(dht_configs.len());
+    pub(crate) fn history(&self) -> HistoryStore {
+        self.history.clone()
+    }
+
+    pub(crate) fn events(&self) -> EventBus {
+        self.events.clone()
+    }
 
-        // Register dht_gauges from config
-        for dht_config in dht_configs.as_slice() {
-            let sensor_gauge_dht = DhtGauge::new(dht_config.clone());
-            sensor_gauges.push(sensor_gauge_dht.clone());
-            metrics_registry.register_dht_gauge(&sensor_gauge_dht);
+    fn create_sensor_gauges(
+        sensor_configs: Vec<SensorConfig>,
+        metrics: &Metrics,
+        history: HistoryStore,
+        events: EventBus,
+        stale_after_millis: i64,
+    ) -> Vec<SensorGauge> {
+        // Vec to hold gauges created from config
+        let mut sensor_gauges: Vec<SensorGauge> = Vec::with_capacity(sensor_configs.len());
+
+        // Register sensor gauges from config
+        for sensor_config in sensor_configs {
+            let sensor_metrics = metrics.sensor(
+                sensor_config.name(),
+                sensor_config.gpio_pin(),
+                sensor_config.measures(),
+            );
+            let sensor_gauge = SensorGauge::new(
+                sensor_config,
+                sensor_metrics,
+                history.clone(),
+                events.clone(),
+                stale_after_millis,
+            );
+            metrics.register_sensor(&sensor_gauge.metrics).unwrap();
+            sensor_gauges.push(sensor_gauge);
         }
         sensor_gauges
     }
 
     fn create_switch_gauges(
         switch_devices: Vec<SwitchDevice>,
-        metrics_registry: Registry,
+        metrics: &Metrics,
     ) -> Vec<SwitchGauge> {
         let mut switch_gauges: Vec<SwitchGauge> = Vec::with_capacity(switch_devices.len());
         for switch_device in switch_devices.as_slice() {
-            let mr = metrics_registry.clone();
             let switch_gauge = SwitchGauge {
                 switch_device: switch_device.clone(),
-                state: Gauge::with_opts(Opts::new(
-                    format!("{}_state", switch_device.name),
-                    format!("{} switch state", switch_device.name),
-                ))
-                    .unwrap(),
+                state: metrics.switch(&switch_device.name),
             };
             switch_gauges.push(switch_gauge.clone());
-            mr.register(Box::new(switch_gauge.state.clone())).unwrap();
+            metrics.register_switch(&switch_gauge.state).unwrap();
         }
         switch_gauges
     }
@@ -113,31 +195,45 @@ impl SensorManager {
         let switch_devices = SensorManager::switch_devices(gha_config);
         switch_devices
             .iter()
-            .map(|device| device.gpio_pin)
+            .map(|device| device.pin())
             .collect()
     }
 
-    fn create_output_pin_state(gha_config: &GHAConfig) -> OutputPinState {
+    fn create_output_pin_state(
+        gha_config: &GHAConfig,
+        gpio: &dyn GpioBackend,
+        store: SwitchStateStore,
+        history: HistoryStore,
+    ) -> OutputPinState {
         let mut output_pins: Vec<u32> = SensorManager::output_pins(gha_config);
-        match gha_config.dht_board_pin {
-            Some(pin) => output_pins.push(pin),
-            None => {}
+        if let Some(pin) = gha_config.dht_board_pin {
+            output_pins.push(pin);
         }
-        OutputPinState::new(output_pins)
+        let boot_levels = SensorManager::switch_devices(gha_config)
+            .iter()
+            .filter_map(|switch_device| {
+                store
+                    .boot_level(switch_device)
+                    .map(|is_high| (switch_device.pin(), is_high))
+            })
+            .collect();
+        OutputPinState::new(output_pins, gpio, boot_levels, store, history)
     }
 
-    fn create_switch_manager(gha_config: &GHAConfig) -> SwitchManager {
+    fn create_switch_manager(
+        gha_config: &GHAConfig,
+        store: SwitchStateStore,
+        events: EventBus,
+    ) -> SwitchManager {
         let switch_devices = SensorManager::switch_devices(gha_config);
-        SwitchManager::new(switch_devices)
+        SwitchManager::new(switch_devices, store, events)
     }
 
     async fn start_sensor_tasks(&self) -> Result<(), GHAError> {
-        let sensor_gauges = self.sensor_gauges.lock().await.clone();
-        for sensor_gauge in &sensor_gauges {
-            let sensor_gauge = sensor_gauge.clone();
-            let _ = self
+        for sensor_gauge in self.read_sensor_gauges().await {
+            self
                 .gauge_sender
-                .send(DhtReadingTask::new(sensor_gauge))
+                .send(SensorReadingTask::new(sensor_gauge))
                 .await?;
         }
         Ok(())
@@ -160,7 +256,6 @@ impl SensorManager {
             .clone()
             .listen_port
             .unwrap()
-            .clone()
     }
 
     async fn dht_board_pin(&self) -> Result<u32, GHAError> {
@@ -172,14 +267,322 @@ impl SensorManager {
 
     pub(crate) async fn dht_sensor_board_on(&self) -> Result<bool, GHAError> {
         let output_pin_state = self.output_pin_state();
-        Ok(output_pin_state.pin_on(self.dht_board_pin().await?).await?)
+        output_pin_state.pin_on(self.dht_board_pin().await?).await
     }
 
     async fn dht_sensor_board_off(&self) -> Result<bool, GHAError> {
         let output_pin_state = self.output_pin_state();
-        Ok(output_pin_state
+        output_pin_state
             .pin_off(self.dht_board_pin().await?)
-            .await?)
+            .await
+    }
+
+    /// Whether shutdown has started, loops stop when this is set
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.output_pin_state.is_shutting_down()
+    }
+
+    /// Drive switch devices to their `safe_state`, power off the dht board if configured and
+    /// flush the history. Pins can't be changed afterwards.
+    pub(crate) async fn shutdown(&self) -> Result<(), GHAError> {
+        let config = self.config().await?;
+        let mut safe_levels = BTreeMap::new();
+        for switch_device in config.switch_devices.iter().flatten() {
+            if let Some(safe_state) = switch_device.safe_state {
+                safe_levels.insert(switch_device.pin(), safe_state == SwitchLevel::On);
+            }
+        }
+        if config.is_shutdown_dht_board_off() {
+            safe_levels.insert(self.dht_board_pin().await?, false);
+        }
+
+        let driven = self.output_pin_state.shutdown(safe_levels).await;
+        for switch_device in config.switch_devices.iter().flatten() {
+            if driven.contains(&switch_device.pin()) {
+                info!(
+                    "Drove switch {} to its safe state {:?}",
+                    switch_device.name,
+                    switch_device.safe_state.unwrap()
+                );
+            }
+        }
+        if config.is_shutdown_dht_board_off() {
+            info!("Powered off the dht board");
+        }
+        self.update_pin_state_gauges().await;
+        self.history.flush().await;
+        Ok(())
+    }
+
+    /// Apply a reloaded config without a restart. Sensors and switch devices are matched by
+    /// name and those left unchanged keep running untouched. Settings only read at startup
+    /// keep their running values and are listed in `restart_required`.
+    pub(crate) async fn reload_config(
+        &self,
+        mut new_config: GHAConfig,
+    ) -> Result<ConfigChanges, GHAError> {
+        // Held for the whole reload so reloads don't interleave
+        let mut config = self.config.lock().await;
+        if self.is_shutting_down() {
+            return Err(GHAError::from_string(
+                "The config can't be reloaded during shutdown".to_string(),
+            ));
+        }
+        let mut changes = ConfigChanges {
+            restart_required: new_config.keep_startup_settings(&config),
+            ..ConfigChanges::default()
+        };
+        self.gpio.set_lines(new_config.gpio_lines());
+        let new_sensor_gauges = self.reload_sensors(&new_config, &mut changes).await;
+        self.reload_switches(&config, &new_config, &mut changes).await;
+        *config = new_config;
+        drop(config);
+
+        if self.workers_started.load(Relaxed) {
+            let config = self.config().await?;
+            for sensor_gauge in new_sensor_gauges {
+                if !config.is_sensor_read(&sensor_gauge.config) {
+                    continue;
+                }
+                self.gauge_sender
+                    .send(SensorReadingTask::new(sensor_gauge))
+                    .await?;
+            }
+        }
+        self.update_pin_state_gauges().await;
+        info!("Reloaded config: {:?}", changes);
+        self.events.publish(LiveEvent::ConfigReloaded(changes.clone()));
+        Ok(changes)
+    }
+
+    /// Swap in the sensors of `new_config`, returns the added and changed sensors which need
+    /// a reading task
+    async fn reload_sensors(
+        &self,
+        new_config: &GHAConfig,
+        changes: &mut ConfigChanges,
+    ) -> Vec<SensorGauge> {
+        let sensor_configs = new_config.sensor_configs();
+        let mut sensor_gauges = self.sensor_gauges.lock().await;
+        for sensor_gauge in sensor_gauges.iter() {
+            let name = sensor_gauge.config.name();
+            if !sensor_configs.iter().any(|sensor_config| sensor_config.name() == name) {
+                sensor_gauge.retire();
+                self.metrics.remove_sensor(&sensor_gauge.metrics);
+                changes.sensors_removed.push(name.to_string());
+            }
+        }
+
+        let mut new_sensor_gauges = Vec::new();
+        let mut reloaded = Vec::with_capacity(sensor_configs.len());
+        for sensor_config in sensor_configs {
+            let name = sensor_config.name().to_string();
+            let sensor_gauge = match sensor_gauges.iter().find(|sg| sg.config.name() == name) {
+                Some(sensor_gauge) if sensor_gauge.config == sensor_config => {
+                    reloaded.push(sensor_gauge.clone());
+                    continue;
+                }
+                Some(sensor_gauge) => {
+                    changes.sensors_updated.push(name.clone());
+                    let metrics = self.changed_sensor_metrics(sensor_gauge, &sensor_config);
+                    sensor_gauge.with_config(sensor_config, metrics)
+                }
+                None => {
+                    let sensor_metrics = self.metrics.sensor(
+                        &name,
+                        sensor_config.gpio_pin(),
+                        sensor_config.measures(),
+                    );
+                    let sensor_gauge = SensorGauge::new(
+                        sensor_config,
+                        sensor_metrics,
+                        self.history.clone(),
+                        self.events.clone(),
+                        new_config.sensor_stale_after_millis(),
+                    );
+                    if let Err(e) = self.metrics.register_sensor(&sensor_gauge.metrics) {
+                        error!("Unable to register the metrics of sensor {}: {}", name, e);
+                    }
+                    changes.sensors_added.push(name.clone());
+                    sensor_gauge
+                }
+            };
+            new_sensor_gauges.push(sensor_gauge.clone());
+            reloaded.push(sensor_gauge);
+        }
+        *sensor_gauges = reloaded;
+        new_sensor_gauges
+    }
+
+    /// Metrics for the changed config of a sensor, replaced with ones carrying over the
+    /// latest values when its pin or measured values changed
+    fn changed_sensor_metrics(
+        &self,
+        sensor_gauge: &SensorGauge,
+        sensor_config: &SensorConfig,
+    ) -> SensorMetrics {
+        let pin = sensor_config.gpio_pin();
+        let measures = sensor_config.measures();
+        if sensor_gauge.metrics.is_for(pin, measures) {
+            return sensor_gauge.metrics.clone();
+        }
+        self.metrics.remove_sensor(&sensor_gauge.metrics);
+        let metrics = self.metrics.sensor(sensor_config.name(), pin, measures);
+        metrics.copy_from(&sensor_gauge.metrics);
+        if let Err(e) = self.metrics.register_sensor(&metrics) {
+            error!(
+                "Unable to register the metrics of sensor {}: {}",
+                sensor_config.name(),
+                e
+            );
+        }
+        metrics
+    }
+
+    /// Swap in the switch devices of `new_config`. Removed switch devices are switched off
+    /// and released, one moved to another pin keeps its override_auto flag.
+    async fn reload_switches(
+        &self,
+        config: &GHAConfig,
+        new_config: &GHAConfig,
+        changes: &mut ConfigChanges,
+    ) {
+        let switch_devices = SensorManager::switch_devices(config);
+        let new_switch_devices = SensorManager::switch_devices(new_config);
+        let mut switch_gauges = self.switch_gauges.lock().await;
+        let switch_gauge = |name: &str| {
+            switch_gauges
+                .iter()
+                .find(|sg| sg.switch_device.name == name)
+                .cloned()
+        };
+
+        // Release pins first so a reload can hand a pin to another switch device
+        let mut moved_overrides = BTreeMap::new();
+        for switch_device in switch_devices {
+            let name = &switch_device.name;
+            let new_switch_device = new_switch_devices.iter().find(|sw| &sw.name == name);
+            if new_switch_device.map(|sw| sw.pin()) == Some(switch_device.pin()) {
+                continue;
+            }
+            self.output_pin_state.remove_pin(switch_device.pin()).await;
+            let switch_state = self.switch_manager.remove_switch(switch_device.pin()).await;
+            if let Some(switch_state) = switch_state {
+                moved_overrides.insert(name.clone(), switch_state.override_auto);
+            }
+            if new_switch_device.is_none() {
+                if let Some(switch_gauge) = switch_gauge(name) {
+                    self.metrics.remove_switch(&switch_gauge.state);
+                }
+                changes.switches_removed.push(name.clone());
+            }
+        }
+
+        let store = &self.switch_manager.store;
+        let mut reloaded = Vec::with_capacity(new_switch_devices.len());
+        for new_switch_device in new_switch_devices {
+            let name = &new_switch_device.name;
+            let switch_device = switch_devices.iter().find(|sw| &sw.name == name);
+            if switch_device == Some(new_switch_device) {
+                reloaded.extend(switch_gauge(name));
+                continue;
+            }
+
+            let pin_num = new_switch_device.pin();
+            let reloaded_gauge = match switch_device {
+                Some(switch_device) => {
+                    if switch_device.pin() == pin_num {
+                        self.switch_manager
+                            .update_auto(pin_num, new_switch_device.auto.unwrap_or(false))
+                            .await;
+                    } else {
+                        let override_auto = moved_overrides.get(name).copied().unwrap_or(false);
+                        self.add_switch_device(new_switch_device, override_auto).await;
+                    }
+                    changes.switches_updated.push(name.clone());
+                    switch_gauge(name).map(|sg| SwitchGauge {
+                        switch_device: new_switch_device.clone(),
+                        state: sg.state,
+                    })
+                }
+                None => {
+                    let override_auto = store
+                        .restored(name)
+                        .map(|switch| switch.override_auto)
+                        .unwrap_or(false);
+                    self.add_switch_device(new_switch_device, override_auto).await;
+                    changes.switches_added.push(name.clone());
+                    let new_gauge = SwitchGauge {
+                        switch_device: new_switch_device.clone(),
+                        state: self.metrics.switch(name),
+                    };
+                    if let Err(e) = self.metrics.register_switch(&new_gauge.state) {
+                        error!("Unable to register the metrics of switch {}: {}", name, e);
+                    }
+                    Some(new_gauge)
+                }
+            };
+            reloaded.extend(reloaded_gauge);
+        }
+        *switch_gauges = reloaded;
+        store.set_switch_devices(new_switch_devices);
+        self.history.set_switch_devices(new_switch_devices);
+    }
+
+    /// Open the pin of a switch device from a reloaded config and start tracking its state
+    async fn add_switch_device(&self, switch_device: &SwitchDevice, override_auto: bool) {
+        let pin_num = switch_device.pin();
+        let boot_level = self.switch_manager.store.boot_level(switch_device);
+        if let Err(e) = self
+            .output_pin_state
+            .add_pin(pin_num, self.gpio.as_ref(), boot_level)
+            .await
+        {
+            error!("unable to validate output pin {}: {}", pin_num, e);
+        }
+        self.switch_manager.add_switch(switch_device, override_auto).await;
+    }
+
+    pub(crate) fn record_monitor_progress(&self) {
+        self.monitor_progress.store(unix_millis(), Relaxed);
+    }
+
+    /// The monitor loop and sensors whose worker hasn't finished a tick or read in
+    /// `stall_after_millis`
+    pub(crate) async fn stalled_loops(&self, stall_after_millis: i64) -> Vec<String> {
+        let since = unix_millis() - stall_after_millis;
+        let mut stalled = Vec::new();
+        let monitor_progress = self.monitor_progress.load(Relaxed);
+        if monitor_progress > 0 && monitor_progress < since {
+            stalled.push("monitor loop".to_string());
+        }
+        if self.workers_started.load(Relaxed) {
+            for sensor_gauge in self.read_sensor_gauges().await {
+                if sensor_gauge.health.last_read_attempt.load(Relaxed) < since {
+                    stalled.push(format!("sensor worker for {}", sensor_gauge.config.name()));
+                }
+            }
+        }
+        stalled
+    }
+
+    /// One line summary of sensor health, like `3/3 sensors reporting`
+    pub(crate) async fn health_status(&self) -> String {
+        let sensor_gauges = self.sensor_gauges.lock().await;
+        let not_reporting: Vec<&str> = sensor_gauges
+            .iter()
+            .filter(|sensor_gauge| !sensor_gauge.is_initialized() || sensor_gauge.is_stale())
+            .map(|sensor_gauge| sensor_gauge.config.name())
+            .collect();
+        let mut status = format!(
+            "{}/{} sensors reporting",
+            sensor_gauges.len() - not_reporting.len(),
+            sensor_gauges.len()
+        );
+        if !not_reporting.is_empty() {
+            status.push_str(&format!(", waiting on {}", not_reporting.join(", ")));
+        }
+        status
     }
 
     async fn is_dht_sensor_board_on(&self) -> Result<bool, GHAError> {
@@ -189,23 +592,27 @@ impl SensorManager {
             .await?)
     }
 
-    async fn dht_gauge_by_pin_num(&self, pin_num: u32) -> DhtGauge {
-        self.sensor_gauges.lock().await.clone().dht_gauge(pin_num)
+    /// The sensor with `name`, None once a config reload removed it
+    pub(crate) async fn sensor_gauge_by_name(&self, name: &str) -> Option<SensorGauge> {
+        self.sensor_gauges
+            .lock()
+            .await
+            .iter()
+            .find(|sensor_gauge| sensor_gauge.config.name() == name)
+            .cloned()
     }
 
-    pub(crate) async fn dht_gauge_by_name(&self, name: String) -> DhtGauge {
-        let pin = self
-            .config
+    /// Sensors the workers read, sensors on the dht board are left out without a
+    /// `dht_board_pin`
+    async fn read_sensor_gauges(&self) -> Vec<SensorGauge> {
+        let config = self.config.lock().await.clone();
+        self.sensor_gauges
             .lock()
             .await
-            .clone()
-            .dht_configs
-            .clone()
             .iter()
-            .find(|dht_config| *dht_config.name == name)
-            .unwrap()
-            .gpio_pin;
-        self.dht_gauge_by_pin_num(pin).await
+            .filter(|sensor_gauge| config.is_sensor_read(&sensor_gauge.config))
+            .cloned()
+            .collect()
     }
 
     async fn start_reading_worker(&self) -> Result<(), GHAError> {
@@ -217,137 +624,203 @@ impl SensorManager {
             loop {
                 let sender_chan = sender_chan.clone();
                 let mut task = gauge_receiver.lock().await.recv().await.unwrap();
-                debug!("got task for: {}", task.sensor_gauge_dht.config.name);
+                if sensor_manager.is_shutting_down() {
+                    break;
+                }
+                // The sensor was removed or changed by a config reload
+                if task.sensor_gauge.is_retired() {
+                    debug!("dropped task for: {}", task.sensor_gauge.config.name());
+                    continue;
+                }
+                let name = task.sensor_gauge.config.name().to_string();
+                debug!("got task for: {}", name);
+                // A sensor that can't be opened or read is not a stalled worker
+                task.sensor_gauge
+                    .health
+                    .last_read_attempt
+                    .store(unix_millis(), Relaxed);
 
-                let pin_number: u32 = task.sensor_gauge_dht.config.gpio_pin;
                 let delay: u64 = 10_000;
+                let is_on_dht_board = task.sensor_gauge.config.is_on_dht_board();
 
                 // Check if sensor board is on
-                let is_sensor_board_on = sensor_manager.is_dht_sensor_board_on().await?;
-                if !is_sensor_board_on {
+                if is_on_dht_board && !sensor_manager.is_dht_sensor_board_on().await? {
                     warn!(
-                        "Sensor board pin is not on. Retry read of pin {} later",
-                        pin_number
+                        "Sensor board pin is not on. Retry read of sensor {} later",
+                        name
                     );
                     let _ = send_delayed(sender_chan, task, Duration::from_millis(3000)).await;
                     continue;
                 }
 
-                if let Ok(pin) = open_pin(pin_number as u8, Input) {
-                    let mut sensor = DHT22Sensor::from_pin(pin);
-                    match sensor.read() {
-                        Ok((temp_c, humidity)) => {
-                            task.retries = 0;
-                            let initialized = task.sensor_gauge_dht.initialized.clone();
-                            initialized.store(true, Relaxed);
-                            task.sensor_gauge_dht.set_good_values(temp_c, humidity);
-                            task.last_good_reading = SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap()
-                                .as_millis()
+                let opened = open_sensor(
+                    &task.sensor_gauge.config,
+                    sensor_manager.gpio.as_ref(),
+                    &sensor_manager.w1_devices_dir,
+                );
+                match opened {
+                    Ok(mut sensor) => {
+                        task.sensor_gauge.metrics.read_attempts.inc();
+                        match sensor.measure() {
+                            Ok(measurements) => {
+                                task.retries = 0;
+                                task.sensor_gauge.set_good_values(&measurements);
+                                task.last_good_reading = SystemTime::now()
+                                    .duration_since(UNIX_EPOCH)
+                                    .unwrap()
+                                    .as_millis()
+                            }
+                            Err(e) => {
+                                task.retries += 1;
+                                task.sensor_gauge.metrics.record_read_error(&e);
+                                warn!(
+                                    "Error reading sensor[{}]: {}, retry[{}]",
+                                    name, e, task.retries
+                                );
+                            }
                         }
-                        Err(e) => {
-                            task.retries += 1;
-                            warn!(
-                                "Error reading sensor[{}:{}]: {}, retry[{}]",
-                                task.sensor_gauge_dht.config.name,
-                                task.sensor_gauge_dht.config.gpio_pin,
-                                e,
-                                task.retries
+                        let now_millis = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap()
+                            .as_millis();
+
+                        if is_on_dht_board
+                            && task.last_good_reading > 0
+                            && now_millis
+                            > (task.last_good_reading + task.max_bad_reading_duration as u128)
+                        {
+                            error!(
+                                "To long since last good reading from sensor {}. Restarting sensor board.",
+                                name
                             );
+                            task.sensor_gauge.metrics.board_power_cycles.inc();
+                            sensor_manager.dht_sensor_board_off().await?;
+                            tokio::time::sleep(Duration::from_millis(2000)).await;
+                            sensor_manager.dht_sensor_board_on().await?;
+                        }
+                        if task.retries > 0 {
+                            let task = task.clone();
+                            let _ = send_delayed(sender_chan, task, Duration::from_millis(3000)).await;
+                        } else {
+                            let task = task.clone();
+                            let _ = send_delayed(sender_chan, task, Duration::from_millis(delay)).await;
                         }
                     }
-
-                    let now_millis = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis();
-
-                    if task.last_good_reading > 0
-                        && now_millis
-                        > (task.last_good_reading + task.max_bad_reading_duration as u128)
-                    {
-                        error!(
-                            "To long since last good reading from pin {}. Restarting sensor board.",
-                            pin_number
-                        );
-                        sensor_manager.dht_sensor_board_off().await?;
-                        tokio::time::sleep(Duration::from_millis(2000)).await;
-                        sensor_manager.dht_sensor_board_on().await?;
-                    }
-                    if task.retries > 0 {
-                        let task = task.clone();
-                        let _ = send_delayed(sender_chan, task, Duration::from_millis(3000)).await;
-                    } else {
+                    Err(e) => {
+                        warn!("Unable to open sensor {}: {}", name, e);
                         let task = task.clone();
                         let _ = send_delayed(sender_chan, task, Duration::from_millis(delay)).await;
                     }
-                } else {
-                    warn!("no gpio pin found: {}", pin_number);
-                    let task = task.clone();
-                    let _ = send_delayed(sender_chan, task, Duration::from_millis(delay)).await;
                 }
             }
+            debug!("Sensor worker stopped");
+            Ok(())
         })
             .await?
     }
 
     pub(crate) async fn start_sensor_workers(&self) -> Result<(), GHAError> {
+        self.workers_started.store(true, Relaxed);
         let worker_count = std::thread::available_parallelism().unwrap().get();
         info!("Starting {} workers", worker_count);
         for _ in 0..worker_count {
             let sensor_manager = self.clone();
-            let _ =
-                tokio::spawn(async move { sensor_manager.clone().start_reading_worker().await });
+            tokio::spawn(async move { sensor_manager.clone().start_reading_worker().await });
         }
+        // Invalidate sensors that stop reporting good readings
+        let sensor_gauges = self.sensor_gauges.clone();
+        tokio::spawn(async move {
+            loop {
+                let now_millis = unix_millis();
+                for sensor_gauge in sensor_gauges.lock().await.iter() {
+                    sensor_gauge.check_staleness(now_millis);
+                }
+                tokio::time::sleep(Duration::from_millis(5_000)).await;
+            }
+        });
         // Start first reading task
-        Ok(self.start_sensor_tasks().await?)
+        self.start_sensor_tasks().await
+    }
+
+    /// Read every sensor once into a registry of its own and return the metrics text. Unlike
+    /// `new` no output pins are opened, so switch devices and the dht board are left as they
+    /// are, and nothing is written to the history.
+    pub(crate) async fn read_metrics_once(gha_config: &GHAConfig, attempts: u32) -> String {
+        let metrics = Metrics::new(gha_config.metrics_style());
+        let gpio = create_gpio_backend(gha_config);
+        let history = HistoryStore::new(
+            &HistoryConfig {
+                enabled: Some(false),
+                ..HistoryConfig::default()
+            },
+            &[],
+        );
+        let sensor_configs = gha_config
+            .sensor_configs()
+            .into_iter()
+            .filter(|sensor_config| gha_config.is_sensor_read(sensor_config))
+            .collect();
+        let sensor_gauges = SensorManager::create_sensor_gauges(
+            sensor_configs,
+            &metrics,
+            history,
+            EventBus::new(),
+            gha_config.sensor_stale_after_millis(),
+        );
+        read_gauges_once(&sensor_gauges, gpio.as_ref(), gha_config.w1_devices_dir(), attempts)
+            .await;
+        metrics.text()
+    }
+
+    /// Metrics in the Prometheus text format
+    pub(crate) fn metrics_text(&self) -> String {
+        self.metrics.text()
     }
 
     pub(crate) async fn update_pin_state_gauges(&self) {
         let switch_gauges = self.switch_gauges.lock().await.to_vec();
         for switch_gauge in switch_gauges {
-            if self
+            // Pins that failed to open have no state
+            let is_high = match self
                 .output_pin_state()
-                .is_pin_high(switch_gauge.switch_device.gpio_pin)
+                .is_pin_high(switch_gauge.switch_device.pin())
                 .await
-                .unwrap()
             {
-                let _ = self
-                    .switch_manager()
-                    .update_pin_state(switch_gauge.switch_device.gpio_pin, 1)
-                    .await;
-                switch_gauge.state.set(1.0)
-            } else {
-                let _ = self
-                    .switch_manager()
-                    .update_pin_state(switch_gauge.switch_device.gpio_pin, 0)
-                    .await;
-                switch_gauge.state.set(0.0)
-            }
+                Ok(is_high) => is_high,
+                Err(_) => continue,
+            };
+            let switch_state = self
+                .switch_manager()
+                .update_pin_state(switch_gauge.switch_device.pin(), u32::from(is_high))
+                .await;
+            // Monitors and schedules drive auto switches unless overridden
+            let auto = switch_state
+                .map(|state| state.is_auto && !state.override_auto)
+                .unwrap_or(false);
+            switch_gauge.state.set(is_high, auto);
         }
     }
 
-    pub(crate) async fn is_switch_on(&self, name: &str) -> Result<bool, GHAError> {
-        let switch_gauges = self.switch_gauges.lock().await;
-        let switch_gauge = switch_gauges
-            .iter()
-            .find(|sw| sw.switch_device.name == name.to_string());
-        Ok(switch_gauge.unwrap().state.get() == 1.0)
-    }
-
     async fn switch_device_by_name(&self, name: &str) -> Result<SwitchDevice, GHAError> {
-        let config = self.config.lock().await.clone();
-        let switch_devices = config.switch_devices.unwrap();
-        Ok(switch_devices
+        let config = self.config.lock().await;
+        SensorManager::switch_devices(&config)
             .iter()
             .find(|sw| sw.name.as_str() == name)
-            .unwrap()
-            .clone())
+            .cloned()
+            .ok_or_else(|| GHAError::from_string(format!("switch {} not found", name)))
+    }
+
+    /// Whether the output pin of the switch is currently high
+    pub(crate) async fn is_switch_on(&self, name: &str) -> Result<bool, GHAError> {
+        let switch_device = self.switch_device_by_name(name).await?;
+        Ok(self
+            .output_pin_state()
+            .is_pin_high(switch_device.pin())
+            .await?)
     }
 
     pub(crate) async fn auto_switch_on(&self, name: &str) -> Result<(), GHAError> {
-        let switch_state = self.switch_manager().switches_state().await?.by_name(name);
+        let switch_state = self.switch_manager().find_switch(name).await?;
         if switch_state.is_auto && !switch_state.override_auto {
             self.switch_on(name).await?;
         } else {
@@ -359,13 +832,13 @@ impl SensorManager {
     pub(crate) async fn switch_on(&self, name: &str) -> Result<(), GHAError> {
         let switch_device = self.switch_device_by_name(name).await?;
         self.output_pin_state()
-            .pin_on(switch_device.gpio_pin)
+            .pin_on(switch_device.pin())
             .await?;
         Ok(())
     }
 
     pub(crate) async fn auto_switch_off(&self, name: &str) -> Result<(), GHAError> {
-        let switch_state = self.switch_manager().switches_state().await?.by_name(name);
+        let switch_state = self.switch_manager().find_switch(name).await?;
         if switch_state.is_auto && !switch_state.override_auto {
             self.switch_off(name).await?;
         } else {
@@ -377,11 +850,58 @@ impl SensorManager {
     pub(crate) async fn switch_off(&self, name: &str) -> Result<(), GHAError> {
         let switch_device = self.switch_device_by_name(name).await?;
         self.output_pin_state()
-            .pin_off(switch_device.gpio_pin)
+            .pin_off(switch_device.pin())
             .await?;
         Ok(())
     }
 
+    /// Apply a switch update from the api, override_auto is changed before the pin state.
+    /// Nothing changes unless the switch exists and the whole update is valid.
+    pub(crate) async fn update_switch(
+        &self,
+        pin_num: u32,
+        update: SwitchUpdate,
+    ) -> Result<SwitchState, PinError> {
+        let switch_manager = self.switch_manager();
+        switch_manager.switch_state(pin_num).await?;
+        match update.state {
+            Some(val) if val > 1 => return Err(PinError::InvalidPinValue { pin: pin_num, val }),
+            Some(_) if self.is_shutting_down() => return Err(PinError::ShuttingDown(pin_num)),
+            _ => {}
+        }
+        if let Some(override_auto) = update.override_auto {
+            switch_manager.update_override_auto(pin_num, override_auto).await?;
+        }
+        if let Some(state) = update.state {
+            self.output_pin_state().set_pin_state(pin_num, state).await?;
+            self.update_pin_state_gauges().await;
+        }
+        switch_manager.switch_state(pin_num).await
+    }
+
+    /// Latest values of every sensor
+    pub(crate) async fn sensor_readings(&self) -> SensorReadings {
+        let sensors = self
+            .sensor_gauges
+            .lock()
+            .await
+            .iter()
+            .map(SensorGauge::reading)
+            .collect();
+        SensorReadings { sensors }
+    }
+
+    /// Latest values of the sensor with `name`
+    pub(crate) async fn sensor_reading(&self, name: &str) -> Result<SensorReading, RequestError> {
+        self.sensor_gauges
+            .lock()
+            .await
+            .iter()
+            .find(|sensor_gauge| sensor_gauge.config.name() == name)
+            .map(SensorGauge::reading)
+            .ok_or_else(|| RequestError::NotFound(format!("sensor {} not found", name)))
+    }
+
 
     pub(crate) async fn wait_for_sensor_initialization(&self) -> Result<(), GHAError> {
         let start_time = SystemTime::now();
@@ -406,24 +926,30 @@ impl SensorManager {
 
     async fn all_sensors_initialized(&self) -> Result<bool, GHAError> {
         Ok(self
-            .sensor_gauges
-            .lock()
+            .read_sensor_gauges()
             .await
             .iter()
-            .all(|sg| sg.initialized.load(Relaxed) == true))
+            .all(|sg| sg.initialized.load(Relaxed)))
     }
 
     async fn sensor_gauge_count(&self) -> Result<usize, GHAError> {
-        Ok(self.sensor_gauges.lock().await.len())
+        Ok(self.read_sensor_gauges().await.len())
     }
 }
 
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
 async fn send_delayed(
-    sender_chan: Sender<DhtReadingTask>,
-    task: DhtReadingTask,
+    sender_chan: Sender<SensorReadingTask>,
+    task: SensorReadingTask,
     duration: Duration,
 ) -> Result<(), GHAError> {
-    let _ = tokio::spawn(async move {
+    tokio::spawn(async move {
         let _ = tokio::time::sleep(duration).await;
         let _ = sender_chan.send(task).await;
     })
@@ -431,107 +957,249 @@ async fn send_delayed(
     Ok(())
 }
 
-trait GetGauge {
-    fn dht_gauge(&self, pin_num: u32) -> DhtGauge;
-}
-
-impl GetGauge for Vec<DhtGauge> {
-    fn dht_gauge(&self, pin_num: u32) -> DhtGauge {
-        self.iter()
-            .find(|s| s.config.gpio_pin == pin_num)
-            .unwrap()
-            .clone()
+/// Read each sensor once, retrying failed reads `attempts` times 2s apart
+async fn read_gauges_once(
+    sensor_gauges: &[SensorGauge],
+    gpio: &dyn GpioBackend,
+    w1_devices_dir: &Path,
+    attempts: u32,
+) {
+    for sensor_gauge in sensor_gauges {
+        for attempt in 1..=attempts {
+            sensor_gauge.metrics.read_attempts.inc();
+            let measured = open_sensor(&sensor_gauge.config, gpio, w1_devices_dir)
+                .and_then(|mut sensor| sensor.measure());
+            match measured {
+                Ok(measurements) => {
+                    sensor_gauge.set_good_values(&measurements);
+                    break;
+                }
+                Err(e) => {
+                    sensor_gauge.metrics.record_read_error(&e);
+                    warn!("Error reading sensor {}: {}", sensor_gauge.config.name(), e);
+                    if attempt < attempts {
+                        tokio::time::sleep(Duration::from_millis(2000)).await;
+                    }
+                }
+            }
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 struct SwitchGauge {
     switch_device: SwitchDevice,
-    state: GenericGauge<AtomicF64>,
+    state: SwitchMetric,
 }
 
 #[derive(Debug, Clone)]
-pub(crate) struct DhtGauge {
-    config: DhtConfig,
+pub(crate) struct SensorGauge {
+    config: SensorConfig,
     initialized: Arc<AtomicBool>,
-    temp_c: GenericGauge<AtomicF64>,
-    pub(crate) temp_f: GenericGauge<AtomicF64>,
-    humidity: GenericGauge<AtomicF64>,
+    metrics: SensorMetrics,
+    health: SensorHealth,
+    history: HistoryStore,
+    events: EventBus,
+    /// Set when a config reload removes or changes the sensor, its reading task stops
+    retired: Arc<AtomicBool>,
 }
 
-impl DhtGauge {
-    fn new(config: DhtConfig) -> Self {
-        let name = config.name.clone();
+impl SensorGauge {
+    fn new(
+        config: SensorConfig,
+        metrics: SensorMetrics,
+        history: HistoryStore,
+        events: EventBus,
+        stale_after_millis: i64,
+    ) -> Self {
         Self {
             config,
+            metrics,
+            history,
+            events,
+            health: SensorHealth::new(stale_after_millis),
             initialized: Arc::new(AtomicBool::new(false)),
-            temp_c: Gauge::with_opts(Opts::new(
-                format!("{}_c", name),
-                format!("{} gauge celsius", name),
-            ))
-                .unwrap(),
-            temp_f: Gauge::with_opts(Opts::new(
-                format!("{}_f", name),
-                format!("{} gauge fahrenheit", name),
-            ))
-                .unwrap(),
-            humidity: Gauge::with_opts(Opts::new(
-                format!("{}_h", name),
-                format!("{} gauge humidity", name),
-            ))
-                .unwrap(),
-        }
-    }
-
-    fn set_good_values(&self, temp_c: TemperatureCelsius, humidity: Humidity) {
-        let temp_c = f64::from(temp_c);
-        let temp_c = if let Some(temp_offset) = self.config.temp_offset {
-            temp_c + temp_offset
-        } else {
-            temp_c
-        };
+            retired: Arc::new(AtomicBool::new(false)),
+        }
+    }
 
-        let temp_f: f64 = (temp_c * 1.8f64) + 32f64;
-        let humidity = f64::from(humidity);
-        let humidity = if let Some(humidity_offset) = self.config.humidity_offset {
-            humidity + humidity_offset
-        } else {
-            humidity
-        };
-        info!(
-            "temp/humidity[{}:{}]: {}C {}F / {}% RH",
-            self.config.name, self.config.gpio_pin, temp_c, temp_f, humidity
-        );
-        self.temp_c.set(temp_c);
-        self.temp_f.set(temp_f);
-        self.humidity.set(humidity);
+    /// Retire this gauge for one with the changed config of the same sensor, which keeps
+    /// its latest values in `metrics`
+    fn with_config(&self, config: SensorConfig, metrics: SensorMetrics) -> Self {
+        self.retire();
+        Self {
+            config,
+            metrics,
+            retired: Arc::new(AtomicBool::new(false)),
+            ..self.clone()
+        }
+    }
+
+    fn retire(&self) {
+        self.retired.store(true, Relaxed);
+    }
+
+    fn is_retired(&self) -> bool {
+        self.retired.load(Relaxed)
+    }
+
+    pub(crate) fn is_initialized(&self) -> bool {
+        self.initialized.load(Relaxed)
+    }
+
+    /// No good reading within `sensor_stale_after`, the values are NaN until the next one
+    pub(crate) fn is_stale(&self) -> bool {
+        self.health.freshness.lock().unwrap().stale
+    }
+
+    /// Seconds since the last good reading, or since startup before the first one
+    pub(crate) fn seconds_since_good_reading(&self) -> f64 {
+        let last_good_reading = self.health.freshness.lock().unwrap().last_good_reading;
+        (unix_millis() - last_good_reading) as f64 / 1000.0
+    }
+
+    /// Update the seconds since the last good reading and invalidate the values once
+    /// they're stale
+    fn check_staleness(&self, now_millis: i64) {
+        // Held while the values are invalidated so a good reading can't land in between
+        let mut freshness = self.health.freshness.lock().unwrap();
+        let since_millis = now_millis - freshness.last_good_reading;
+        self.metrics
+            .seconds_since_good_reading
+            .set(since_millis as f64 / 1000.0);
+        if since_millis > self.health.stale_after_millis && !freshness.stale {
+            warn!(
+                "Sensor {} is stale, no good reading for {}s",
+                self.config.name(),
+                since_millis / 1000
+            );
+            freshness.stale = true;
+            for metric in self.config.measures() {
+                self.metrics.set_value(*metric, f64::NAN);
+            }
+        }
+    }
+
+    fn reading(&self) -> SensorReading {
+        let initialized = self.is_initialized();
+        let stale = self.is_stale();
+        let values = self
+            .config
+            .measures()
+            .iter()
+            .map(|&metric| {
+                let value = (initialized && !stale).then(|| self.metrics.value(metric));
+                (metric.name().to_string(), value)
+            })
+            .collect();
+        SensorReading {
+            name: self.config.name().to_string(),
+            sensor_type: self.config.sensor_type(),
+            gpio_pin: self.config.gpio_pin(),
+            initialized,
+            stale,
+            seconds_since_good_reading: self.metrics.seconds_since_good_reading.get(),
+            values,
+        }
+    }
+
+    /// Latest value of `metric`, NaN for metrics the sensor doesn't measure
+    pub(crate) fn metric(&self, metric: SensorMetric) -> f64 {
+        self.metrics.value(metric)
+    }
+
+    fn set_good_values(&self, measurements: &[Measurement]) {
+        let mut values = BTreeMap::new();
+        for measurement in measurements {
+            match *measurement {
+                Measurement::Temperature(temp_c) => {
+                    let temp_c = f64::from(temp_c) + self.config.temp_offset().unwrap_or(0.0);
+                    values.insert(SensorMetric::TempC, temp_c);
+                    values.insert(SensorMetric::TempF, (temp_c * 1.8f64) + 32f64);
+                }
+                Measurement::Humidity(humidity) => {
+                    let humidity =
+                        f64::from(humidity) + self.config.humidity_offset().unwrap_or(0.0);
+                    values.insert(SensorMetric::Humidity, humidity);
+                }
+                Measurement::Pressure(pressure) => {
+                    values.insert(SensorMetric::Pressure, pressure);
+                }
+                Measurement::Moisture(moisture) => {
+                    values.insert(SensorMetric::Moisture, moisture);
+                }
+                Measurement::Light(lux) => {
+                    values.insert(SensorMetric::Light, lux);
+                }
+                Measurement::Co2(ppm) => {
+                    values.insert(SensorMetric::Co2, ppm);
+                }
+            }
+        }
+        let mut freshness = self.health.freshness.lock().unwrap();
+        let values: BTreeMap<String, f64> = values
+            .into_iter()
+            .map(|(metric, value)| {
+                self.metrics.set_value(metric, value);
+                (metric.name().to_string(), value)
+            })
+            .collect();
+        info!("reading[{}]: {:?}", self.config.name(), values);
+        self.initialized.store(true, Relaxed);
+        freshness.last_good_reading = unix_millis();
+        self.metrics.seconds_since_good_reading.set(0.0);
+        if std::mem::replace(&mut freshness.stale, false) {
+            info!("Sensor {} is reporting again", self.config.name());
+        }
+        drop(freshness);
+        self.events
+            .publish(LiveEvent::reading(self.config.name(), values.clone()));
+        self.history.record_reading(self.config.name(), values);
     }
 }
 
-trait RegisterDhtGauge {
-    fn register_dht_gauge(&self, dht_gauge: &DhtGauge);
+/// Freshness of a sensor
+#[derive(Debug, Clone)]
+struct SensorHealth {
+    freshness: Arc<std::sync::Mutex<Freshness>>,
+    /// Unix millis of the last time a worker took up the sensor, or of startup before the first
+    last_read_attempt: Arc<AtomicI64>,
+    stale_after_millis: i64,
 }
 
-impl RegisterDhtGauge for Registry {
-    fn register_dht_gauge(&self, dht_gauge: &DhtGauge) {
-        self.register(Box::new(dht_gauge.temp_c.clone())).unwrap();
-        self.register(Box::new(dht_gauge.temp_f.clone())).unwrap();
-        self.register(Box::new(dht_gauge.humidity.clone())).unwrap();
+/// Changed together so a good reading always clears `stale`
+#[derive(Debug)]
+struct Freshness {
+    /// Unix millis of the last good reading, or of startup before the first one
+    last_good_reading: i64,
+    stale: bool,
+}
+
+impl SensorHealth {
+    fn new(stale_after_millis: i64) -> Self {
+        Self {
+            freshness: Arc::new(std::sync::Mutex::new(Freshness {
+                last_good_reading: unix_millis(),
+                stale: false,
+            })),
+            last_read_attempt: Arc::new(AtomicI64::new(unix_millis())),
+            stale_after_millis,
+        }
     }
 }
 
 #[derive(Debug, Clone)]
-struct DhtReadingTask {
-    sensor_gauge_dht: DhtGauge,
+struct SensorReadingTask {
+    sensor_gauge: SensorGauge,
     retries: i32,
     last_good_reading: u128,
     max_bad_reading_duration: i32,
 }
 
-impl DhtReadingTask {
-    fn new(sensor_gauge_dht: DhtGauge) -> Self {
+impl SensorReadingTask {
+    fn new(sensor_gauge: SensorGauge) -> Self {
         Self {
-            sensor_gauge_dht,
+            sensor_gauge,
             retries: 0,
             last_good_reading: 0,
             max_bad_reading_duration: 60 * 1000, // 1 minute
@@ -542,27 +1210,49 @@ impl DhtReadingTask {
 #[derive(Debug, Clone)]
 pub(crate) struct SwitchManager {
     switch_state: Arc<Mutex<BTreeMap<u32, SwitchState>>>,
+    store: SwitchStateStore,
+    events: EventBus,
 }
 
 impl SwitchManager {
-    fn new(switch_devices: &Vec<SwitchDevice>) -> Self {
+    fn new(switch_devices: &Vec<SwitchDevice>, store: SwitchStateStore, events: EventBus) -> Self {
         let mut tree = BTreeMap::new();
         for switch_device in switch_devices {
-            let auto = match switch_device.auto {
-                None => false,
-                Some(a) => a,
-            };
-            let switch_state = SwitchState {
-                name: switch_device.name.clone(),
-                pin_num: switch_device.gpio_pin,
-                is_auto: auto,
-                override_auto: false,
-                pin_state: None,
-            };
-            tree.insert(switch_device.gpio_pin, switch_state);
+            let override_auto = store
+                .restored(&switch_device.name)
+                .map(|switch| switch.override_auto)
+                .unwrap_or(false);
+            if override_auto {
+                info!("Restored override_auto for {}", switch_device.name);
+            }
+            let switch_state = SwitchState::new(switch_device, override_auto, &store);
+            tree.insert(switch_device.pin(), switch_state);
         }
         SwitchManager {
             switch_state: Arc::new(Mutex::new(tree)),
+            store,
+            events,
+        }
+    }
+
+    /// Track a switch device added by a config reload
+    async fn add_switch(&self, switch_device: &SwitchDevice, override_auto: bool) {
+        let switch_state = SwitchState::new(switch_device, override_auto, &self.store);
+        self.switch_state
+            .lock()
+            .await
+            .insert(switch_device.pin(), switch_state);
+    }
+
+    /// Stop tracking a switch device, returns its last state
+    async fn remove_switch(&self, pin_num: u32) -> Option<SwitchState> {
+        self.switch_state.lock().await.remove(&pin_num)
+    }
+
+    /// Apply the `auto` flag of a switch device changed by a config reload
+    async fn update_auto(&self, pin_num: u32, is_auto: bool) {
+        if let Some(switch_state) = self.switch_state.lock().await.get_mut(&pin_num) {
+            switch_state.is_auto = is_auto;
         }
     }
 
@@ -573,7 +1263,15 @@ impl SwitchManager {
     ) -> Result<SwitchState, PinError> {
         let mut tree = self.switch_state.lock().await;
         if let Some(switch_state) = tree.get_mut(&pin_num) {
+            if switch_state.override_auto != value {
+                self.events.publish(LiveEvent::OverrideAuto {
+                    name: switch_state.name.clone(),
+                    pin_num,
+                    override_auto: value,
+                });
+            }
             switch_state.override_auto = value;
+            self.store.record_override_auto(pin_num, value).await;
             Ok(switch_state.clone())
         } else {
             Err(PinError::InvalidPin(pin_num))
@@ -587,6 +1285,13 @@ impl SwitchManager {
     ) -> Result<SwitchState, PinError> {
         let mut tree = self.switch_state.lock().await;
         if let Some(switch_state) = tree.get_mut(&pin_num) {
+            if switch_state.pin_state != Some(value) {
+                self.events.publish(LiveEvent::PinState {
+                    name: switch_state.name.clone(),
+                    pin_num,
+                    pin_state: value,
+                });
+            }
             switch_state.pin_state = Some(value);
             Ok(switch_state.clone())
         } else {
@@ -594,6 +1299,20 @@ impl SwitchManager {
         }
     }
 
+    pub(crate) async fn update_next_transitions(
+        &self,
+        pin_num: u32,
+        next_transitions: Vec<ScheduledTransition>,
+    ) -> Result<SwitchState, PinError> {
+        let mut tree = self.switch_state.lock().await;
+        if let Some(switch_state) = tree.get_mut(&pin_num) {
+            switch_state.next_transitions = next_transitions;
+            Ok(switch_state.clone())
+        } else {
+            Err(PinError::InvalidPin(pin_num))
+        }
+    }
+
     pub(crate) async fn switches_state(&self) -> Result<SwitchesState, GHAError> {
         let mut switches = Vec::new();
         let tree = self.switch_state.lock().await;
@@ -602,6 +1321,21 @@ impl SwitchManager {
         }
         Ok(SwitchesState { switches })
     }
+
+    pub(crate) async fn switch_state(&self, pin_num: u32) -> Result<SwitchState, PinError> {
+        let tree = self.switch_state.lock().await;
+        tree.get(&pin_num).cloned().ok_or(PinError::InvalidPin(pin_num))
+    }
+
+    /// Find a switch device by name, or by gpio pin number
+    pub(crate) async fn find_switch(&self, id: &str) -> Result<SwitchState, RequestError> {
+        let tree = self.switch_state.lock().await;
+        tree.values()
+            .find(|switch_state| switch_state.name == id)
+            .or_else(|| id.parse::<u32>().ok().and_then(|pin_num| tree.get(&pin_num)))
+            .cloned()
+            .ok_or_else(|| RequestError::NotFound(format!("switch {} not found", id)))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -611,6 +1345,8 @@ pub(crate) struct SwitchState {
     is_auto: bool,
     override_auto: bool,
     pin_state: Option<u32>,
+    /// Upcoming changes from the switch device's schedules
+    next_transitions: Vec<ScheduledTransition>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -618,38 +1354,164 @@ pub(crate) struct SwitchesState {
     switches: Vec<SwitchState>,
 }
 
+impl SwitchState {
+    fn new(switch_device: &SwitchDevice, override_auto: bool, store: &SwitchStateStore) -> Self {
+        SwitchState {
+            name: switch_device.name.clone(),
+            pin_num: switch_device.pin(),
+            is_auto: switch_device.auto.unwrap_or(false),
+            override_auto,
+            pin_state: store.boot_level(switch_device).map(u32::from),
+            next_transitions: Vec::new(),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn pin_num(&self) -> u32 {
+        self.pin_num
+    }
+
+    pub(crate) fn override_auto(&self) -> bool {
+        self.override_auto
+    }
+
+    pub(crate) fn pin_state(&self) -> Option<u32> {
+        self.pin_state
+    }
+}
+
 impl SwitchesState {
-    pub(crate) fn by_name(&self, name: &str) -> SwitchState {
-        self.switches.iter().find(|s| s.name.as_str() == name).unwrap().clone()
+    pub(crate) fn switches(&self) -> &[SwitchState] {
+        &self.switches
     }
 }
 
+/// Body of `PUT /api/v1/switches/{name}`, fields that are left out aren't changed
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SwitchUpdate {
+    /// 0 for off or 1 for on
+    pub(crate) state: Option<u32>,
+    pub(crate) override_auto: Option<bool>,
+}
+
+/// Latest values of a sensor, the values are null until its first good reading and while
+/// it's stale
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SensorReading {
+    name: String,
+    #[serde(rename = "type")]
+    sensor_type: SensorType,
+    /// Null for sensors on a bus
+    gpio_pin: Option<u32>,
+    initialized: bool,
+    stale: bool,
+    seconds_since_good_reading: f64,
+    /// Each metric the sensor measures, like `temp_c`
+    #[serde(flatten)]
+    values: BTreeMap<String, Option<f64>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SensorReadings {
+    sensors: Vec<SensorReading>,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct OutputPinState {
-    pin_state: Arc<Mutex<BTreeMap<u32, IoPin>>>,
+    pin_state: Arc<Mutex<BTreeMap<u32, BoxedDataPin>>>,
+    store: SwitchStateStore,
+    history: HistoryStore,
+    /// Set under the pin lock once shutdown has driven the pins to their safe states
+    shutting_down: Arc<AtomicBool>,
 }
 
 impl OutputPinState {
-    fn new(pins: Vec<u32>) -> Self {
+    /// Open the output pins, driving any pin in `boot_levels` to its level
+    fn new(
+        pins: Vec<u32>,
+        gpio: &dyn GpioBackend,
+        boot_levels: BTreeMap<u32, bool>,
+        store: SwitchStateStore,
+        history: HistoryStore,
+    ) -> Self {
         let mut tree = BTreeMap::new();
         for pin_num in pins {
-            if let Ok(pin) = open_pin(pin_num as u8, Output) {
-                tree.insert(pin_num, pin);
-            } else {
-                error!("unable to validate output pin {}", pin_num)
+            match open_output_pin(gpio, pin_num, boot_levels.get(&pin_num).copied()) {
+                Ok(pin) => {
+                    tree.insert(pin_num, pin);
+                }
+                Err(e) => error!("unable to validate output pin {}: {}", pin_num, e),
             }
         }
         OutputPinState {
             pin_state: Arc::new(Mutex::new(tree)),
+            store,
+            history,
+            shutting_down: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    /// Set the pin state using the IoPin's stored in tree_mut
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Relaxed)
+    }
+
+    /// Open the pin of a switch device added by a config reload
+    async fn add_pin(
+        &self,
+        pin_num: u32,
+        gpio: &dyn GpioBackend,
+        boot_level: Option<bool>,
+    ) -> Result<(), SensorError> {
+        let pin = open_output_pin(gpio, pin_num, boot_level)?;
+        self.pin_state.lock().await.insert(pin_num, pin);
+        Ok(())
+    }
+
+    /// Switch off and release the pin of a switch device removed by a config reload
+    async fn remove_pin(&self, pin_num: u32) {
+        if let Some(mut pin) = self.pin_state.lock().await.remove(&pin_num) {
+            if pin.is_high() {
+                pin.set_low();
+                self.history.record_switch(pin_num, false);
+            }
+        }
+    }
+
+    /// Drive the pins in `safe_levels` and refuse any later change, returns the pins that
+    /// were driven. The state file keeps the last commanded levels for `boot_state: restore`.
+    async fn shutdown(&self, safe_levels: BTreeMap<u32, bool>) -> Vec<u32> {
+        let mut tree = self.pin_state.lock().await;
+        self.shutting_down.store(true, Relaxed);
+        let mut driven = Vec::new();
+        for (pin_num, is_high) in safe_levels {
+            if let Some(pin) = tree.get_mut(&pin_num) {
+                let was_high = pin.is_high();
+                if is_high {
+                    pin.set_high();
+                } else {
+                    pin.set_low();
+                }
+                if was_high != is_high {
+                    self.history.record_switch(pin_num, is_high);
+                }
+                driven.push(pin_num);
+            }
+        }
+        driven
+    }
+
+    /// Set the pin state using the pins stored in tree_mut
     pub(crate) async fn set_pin_state(&self, pin_num: u32, val: u32) -> Result<bool, PinError> {
         let tree_mux = self.pin_state.clone();
         info!("set pin: {} = {}", pin_num, val);
         let mut tree = tree_mux.lock().await;
 
+        if self.is_shutting_down() {
+            return Err(PinError::ShuttingDown(pin_num));
+        }
         if let Some(pin) = tree.get_mut(&pin_num) {
             if val > 1 {
                 Err(PinError::InvalidPinValue { pin: pin_num, val })
@@ -660,11 +1522,16 @@ impl OutputPinState {
                     _ => false,
                 };
 
+                let was_high = pin.is_high();
                 if is_high {
                     pin.set_high();
                 } else {
                     pin.set_low();
                 }
+                if was_high != is_high {
+                    self.history.record_switch(pin_num, is_high);
+                }
+                self.store.record_pin_state(pin_num, is_high).await;
                 Ok(pin.is_high())
             }
         } else {
@@ -690,3 +1557,160 @@ impl OutputPinState {
         Ok(self.set_pin_state(pin_num, 0u32).await?)
     }
 }
+
+/// Open an output pin, driving it to `boot_level` when set
+fn open_output_pin(
+    gpio: &dyn GpioBackend,
+    pin_num: u32,
+    boot_level: Option<bool>,
+) -> Result<BoxedDataPin, SensorError> {
+    let mut pin = gpio.open_pin(pin_num, Output)?;
+    if let Some(is_high) = boot_level {
+        info!("boot pin: {} = {}", pin_num, is_high);
+        if is_high {
+            pin.set_high();
+        } else {
+            pin.set_low();
+        }
+    }
+    Ok(pin)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::time::Duration;
+
+    use crate::config::{DhtConfig, HistoryConfig, MetricsStyle, SensorConfig, SensorMetric};
+    use crate::error::PinError;
+    use crate::events::EventBus;
+    use crate::history::HistoryStore;
+    use crate::metrics::Metrics;
+    use crate::persistence::SwitchStateStore;
+    use crate::sensor::{Measurement, SensorError, SensorErrorKind, SimulatedGpio};
+    use crate::sensor_manager::{unix_millis, OutputPinState, SensorGauge, SensorManager};
+    use crate::test_util::simulated_config;
+
+    #[tokio::test]
+    async fn test_output_pin_state_shutdown() {
+        let history = HistoryStore::new(
+            &HistoryConfig {
+                enabled: Some(false),
+                ..HistoryConfig::default()
+            },
+            &[],
+        );
+        let output_pin_state = OutputPinState::new(
+            vec![18, 23, 24],
+            &SimulatedGpio::default(),
+            BTreeMap::new(),
+            SwitchStateStore::load(None, &[]),
+            history,
+        );
+        output_pin_state.set_pin_state(18, 1).await.unwrap();
+        output_pin_state.set_pin_state(24, 1).await.unwrap();
+
+        let driven = output_pin_state
+            .shutdown(BTreeMap::from([(18, false), (23, true), (99, false)]))
+            .await;
+        assert_eq!(driven, vec![18, 23]);
+        assert!(!output_pin_state.is_pin_high(18).await.unwrap());
+        assert!(output_pin_state.is_pin_high(23).await.unwrap());
+        // Pins without a safe state are left alone
+        assert!(output_pin_state.is_pin_high(24).await.unwrap());
+        assert!(matches!(
+            output_pin_state.set_pin_state(18, 1).await,
+            Err(PinError::ShuttingDown(18))
+        ));
+        assert!(!output_pin_state.is_pin_high(18).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_sensor_gauge_staleness() {
+        let history = HistoryStore::new(
+            &HistoryConfig {
+                enabled: Some(false),
+                ..HistoryConfig::default()
+            },
+            &[],
+        );
+        let config = SensorConfig::Dht(DhtConfig {
+            gpio_pin: Some(17),
+            name: "outside".to_string(),
+            temp_offset: None,
+            humidity_offset: Some(-2.0),
+            gpio_line: None,
+            model: None,
+        });
+        let metrics =
+            Metrics::new(MetricsStyle::Labeled).sensor("outside", Some(17), config.measures());
+        let gauge = SensorGauge::new(config, metrics, history, EventBus::new(), 60_000);
+        gauge.set_good_values(&[
+            Measurement::Temperature(20.0.into()),
+            Measurement::Humidity(50.0.into()),
+        ]);
+        assert_eq!(gauge.metric(SensorMetric::TempF), 68.0);
+        assert_eq!(gauge.metric(SensorMetric::Humidity), 48.0);
+        assert!(gauge.metric(SensorMetric::Pressure).is_nan());
+        gauge.metrics.record_read_error(&SensorError::CheckSum(1, 2));
+        gauge.metrics.record_read_error(&SensorError::KindMsg(
+            SensorErrorKind::ReadTimeout,
+            "timeout",
+        ));
+        assert_eq!(gauge.metrics.checksum_errors.get(), 1);
+        assert_eq!(gauge.metrics.timeouts.get(), 1);
+
+        let now = unix_millis();
+        gauge.check_staleness(now + 30_000);
+        assert!(!gauge.is_stale());
+        assert_eq!(gauge.metrics.seconds_since_good_reading.get().round(), 30.0);
+        assert_eq!(gauge.metric(SensorMetric::TempC), 20.0);
+
+        gauge.check_staleness(now + 90_000);
+        assert!(gauge.is_stale());
+        assert!(gauge.metric(SensorMetric::TempC).is_nan());
+        assert_eq!(gauge.reading().values["temp_c"], None);
+
+        gauge.set_good_values(&[Measurement::Temperature(21.0.into())]);
+        assert!(!gauge.is_stale());
+        assert_eq!(gauge.reading().values["temp_c"], Some(21.0));
+    }
+
+    #[tokio::test]
+    async fn test_read_ds18b20_probes() {
+        let mut config = simulated_config();
+        config.w1_devices_dir = Some("test_w1_devices".to_string());
+        config.metrics_style = Some(MetricsStyle::Labeled);
+        config.sensors = serde_yaml::from_str(
+            "[{type: ds18b20, name: tank, rom_id: 28-0316a2795aff, temp_offset: -0.125},
+              {type: ds18b20, name: soil, rom_id: 28-ffffffffffff}]",
+        )
+        .unwrap();
+        assert!(config.is_reading_sensors());
+        config.switch_devices =
+            serde_yaml::from_str("[{name: fan, gpio_pin: 18, boot_state: on}]").unwrap();
+        let text = SensorManager::read_metrics_once(&config, 1).await;
+
+        assert!(text.contains("greenhouse_temperature_celsius{pin=\"\",sensor=\"tank\"} 23"));
+        assert!(text.contains("greenhouse_sensor_read_attempts_total{pin=\"\",sensor=\"soil\"} 1"));
+        // Switch devices aren't opened, so they have no state to export
+        assert!(!text.contains("greenhouse_switch_on"));
+    }
+
+    #[tokio::test]
+    async fn test_sensor_that_fails_to_open_is_not_stalled() {
+        let mut config = simulated_config();
+        config.w1_devices_dir = Some("test_w1_devices".to_string());
+        config.sensors =
+            serde_yaml::from_str("[{type: ds18b20, name: soil, rom_id: 28-ffffffffffff}]").unwrap();
+        let sensor_manager = SensorManager::new(&config);
+        for sensor_gauge in sensor_manager.read_sensor_gauges().await {
+            sensor_gauge.health.last_read_attempt.store(0, Relaxed);
+        }
+        sensor_manager.start_sensor_workers().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(sensor_manager.stalled_loops(60_000).await, Vec::<String>::new());
+    }
+}
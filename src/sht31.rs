@@ -0,0 +1,100 @@
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::i2c::{sensirion_crc8, I2cDevice};
+use crate::sensor::{Humidity, Measurement, Sensor, SensorError, SensorErrorKind};
+
+/// Single shot measurement with high repeatability and without clock stretching
+const MEASURE_HIGH_REPEATABILITY: [u8; 2] = [0x24, 0x00];
+/// Longest high repeatability measurement is 15ms, reads NACK until it's done
+const MEASUREMENT_TIME: Duration = Duration::from_millis(20);
+
+/// Read temperature and humidity from a Sensirion SHT31, checking the CRC of each word
+#[derive(Debug)]
+pub(crate) struct Sht31Sensor<I: I2cDevice> {
+    device: I,
+}
+
+impl<I: I2cDevice> Sht31Sensor<I> {
+    pub(crate) fn new(device: I) -> Self {
+        Self { device }
+    }
+
+    pub(crate) fn read(&mut self) -> Result<Vec<Measurement>, SensorError> {
+        self.device.write(&MEASURE_HIGH_REPEATABILITY)?;
+        sleep(MEASUREMENT_TIME);
+        let mut data = [0u8; 6];
+        self.device.read(&mut data)?;
+        parse_measurement(&data)
+    }
+}
+
+impl<I: I2cDevice> Sensor for Sht31Sensor<I> {
+    fn measure(&mut self) -> Result<Vec<Measurement>, SensorError> {
+        self.read()
+    }
+}
+
+/// Parse the temperature and humidity words, each followed by its CRC
+fn parse_measurement(data: &[u8; 6]) -> Result<Vec<Measurement>, SensorError> {
+    let mut words = [0u16; 2];
+    for (word, chunk) in words.iter_mut().zip(data.chunks(3)) {
+        let computed = sensirion_crc8(&chunk[..2]);
+        if computed != chunk[2] {
+            return Err(SensorError::CheckSum(chunk[2], computed));
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+    if words == [0xFFFF, 0xFFFF] {
+        return Err(SensorError::KindMsg(
+            SensorErrorKind::InvalidData,
+            "sensor returned all ones",
+        ));
+    }
+    let temp_c = -45.0 + 175.0 * words[0] as f64 / 65535.0;
+    let humidity = 100.0 * words[1] as f64 / 65535.0;
+    if !(-40.0..=125.0).contains(&temp_c) {
+        return Err(SensorError::ValueBounds(format!(
+            "temp_c {} is out of bounds",
+            temp_c
+        )));
+    }
+    Ok(vec![
+        Measurement::Temperature(temp_c.into()),
+        Measurement::Humidity(Humidity::from(humidity)),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use crate::i2c::{sensirion_crc8, RegisterMap};
+    use crate::sensor::{Measurement, SensorError};
+    use crate::sht31::Sht31Sensor;
+
+    #[test]
+    fn test_sht31_read() {
+        // The example in the datasheet
+        assert_eq!(sensirion_crc8(&[0xBE, 0xEF]), 0x92);
+
+        let mut device = RegisterMap::default();
+        device.respond(&[0x24, 0x00], &[0x66, 0x66, 0x93, 0x80, 0x00, 0xA2]);
+        let mut sensor = Sht31Sensor::new(device);
+        let measurements = sensor.read().unwrap();
+        assert!(
+            matches!(measurements[0], Measurement::Temperature(t) if (f64::from(t) - 25.0).abs() < 0.001)
+        );
+        assert!(
+            matches!(measurements[1], Measurement::Humidity(h) if (f64::from(h) - 50.0).abs() < 0.001)
+        );
+        assert_eq!(sensor.device.writes, vec![vec![0x24, 0x00]]);
+
+        let mut device = RegisterMap::default();
+        device.respond(&[0x24, 0x00], &[0x66, 0x66, 0x93, 0x80, 0x01, 0xA2]);
+        let mut sensor = Sht31Sensor::new(device);
+        assert!(matches!(sensor.read(), Err(SensorError::CheckSum(0xA2, _))));
+
+        let mut device = RegisterMap::default();
+        device.respond(&[0x24, 0x00], &[0xFF, 0xFF, 0xAC, 0xFF, 0xFF, 0xAC]);
+        assert!(Sht31Sensor::new(device).read().is_err());
+    }
+}
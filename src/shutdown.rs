@@ -0,0 +1,26 @@
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+use crate::error::GHAError;
+
+/// SIGTERM and SIGINT listeners, once registered the signals no longer kill the process
+pub(crate) struct ShutdownSignal {
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl ShutdownSignal {
+    pub(crate) fn new() -> Result<Self, GHAError> {
+        Ok(Self {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    /// Wait for either signal, returns its name
+    pub(crate) async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+    }
+}
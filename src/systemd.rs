@@ -0,0 +1,168 @@
+use std::ffi::OsString;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::error::GHAError;
+use crate::sensor_manager::SensorManager;
+
+/// How often STATUS is refreshed when systemd doesn't ask for watchdog pings
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Watchdog pings stop once the monitor loop or a sensor worker makes no progress for
+/// this long, the monitor ticks every 10s and sensors are read every 3s to 10s
+const STALL_AFTER_MILLIS: i64 = 60_000;
+
+/// Sends sd_notify(3) messages to the socket in `$NOTIFY_SOCKET`, does nothing when the
+/// agent isn't started by systemd
+#[derive(Debug, Clone)]
+pub(crate) struct Notifier {
+    socket: Option<Arc<(UnixDatagram, SocketAddr)>>,
+}
+
+impl Notifier {
+    pub(crate) fn from_env() -> Self {
+        match Self::new(std::env::var_os("NOTIFY_SOCKET")) {
+            Ok(notifier) => notifier,
+            Err(e) => {
+                warn!("Unable to use NOTIFY_SOCKET, not notifying systemd: {}", e);
+                Self { socket: None }
+            }
+        }
+    }
+
+    fn new(notify_socket: Option<OsString>) -> Result<Self, GHAError> {
+        let path = match notify_socket {
+            Some(path) if !path.is_empty() => path,
+            _ => return Ok(Self { socket: None }),
+        };
+        // A leading @ is a socket in the abstract namespace
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: Some(Arc::new((socket, addr))),
+        })
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    /// Send newline separated `VARIABLE=value` assignments
+    pub(crate) fn notify(&self, state: &str) {
+        if let Some(socket) = &self.socket {
+            let (socket, addr) = socket.as_ref();
+            if let Err(e) = socket.send_to_addr(state.as_bytes(), addr) {
+                debug!("Unable to notify systemd: {}", e);
+            }
+        }
+    }
+
+    pub(crate) fn ready(&self, status: &str) {
+        self.notify(&format!("READY=1\nSTATUS={}", status));
+    }
+
+    pub(crate) fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    pub(crate) fn stopping(&self) {
+        self.notify("STOPPING=1\nSTATUS=Driving switch devices to their safe states");
+    }
+}
+
+/// Time between watchdog pings, half of `WATCHDOG_USEC` when `WATCHDOG_PID` is unset or
+/// is this process
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Keep STATUS current with the sensor health and ping the watchdog while the monitor loop
+/// and sensor workers are making progress
+pub(crate) async fn start_notify_loop(sensor_manager: SensorManager, notifier: Notifier) {
+    let watchdog = watchdog_interval(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    );
+    if let Some(interval) = watchdog {
+        info!("Pinging the systemd watchdog every {:?}", interval);
+    }
+    let delay = watchdog.map_or(STATUS_INTERVAL, |interval| interval.min(STATUS_INTERVAL));
+    let mut was_stalled = false;
+    while !sensor_manager.is_shutting_down() {
+        notifier.status(&sensor_manager.health_status().await);
+        if watchdog.is_some() {
+            let stalled = sensor_manager.stalled_loops(STALL_AFTER_MILLIS).await;
+            if stalled.is_empty() {
+                notifier.notify("WATCHDOG=1");
+            } else if !was_stalled {
+                warn!(
+                    "Holding back watchdog pings, no progress from {}",
+                    stalled.join(", ")
+                );
+            }
+            was_stalled = !stalled.is_empty();
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    use crate::systemd::{watchdog_interval, Notifier};
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None, 42),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(15))
+        );
+        // Meant for another process
+        assert_eq!(watchdog_interval(Some("30000000"), Some("7"), 42), None);
+        assert_eq!(watchdog_interval(None, None, 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+    }
+
+    #[test]
+    fn test_notifier() {
+        let dir = TempDir::new("notify");
+        let path = dir.join("notify.sock");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier::new(Some(path.clone().into_os_string())).unwrap();
+        assert!(notifier.is_enabled());
+        notifier.ready("3/3 sensors reporting");
+        notifier.notify("WATCHDOG=1");
+
+        let mut buf = [0u8; 256];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=3/3 sensors reporting");
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+
+        assert!(!Notifier::new(None).unwrap().is_enabled());
+    }
+}
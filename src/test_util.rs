@@ -0,0 +1,92 @@
+//! Fixtures shared by the module tests
+
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+use crate::config::{
+    ApiToken, DhtConfig, GHAConfig, GpioBackendKind, HistoryConfig, Role, SwitchDevice,
+};
+use crate::sensor_manager::SensorManager;
+
+/// Temp dirs created by this test run, to keep the dirs of parallel tests apart
+static TEMP_DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// An auto switch device on a BCM pin
+pub(crate) fn switch_device(gpio_pin: u32, name: &str) -> SwitchDevice {
+    SwitchDevice {
+        gpio_pin: Some(gpio_pin),
+        name: name.to_string(),
+        auto: Some(true),
+        gpio_line: None,
+        schedules: None,
+        boot_state: None,
+        safe_state: None,
+    }
+}
+
+pub(crate) fn api_token(name: &str, role: Role, sha256: &str) -> ApiToken {
+    ApiToken {
+        name: name.to_string(),
+        role,
+        sha256: sha256.to_string(),
+    }
+}
+
+/// The default config on simulated GPIO, without a state file or history
+pub(crate) fn simulated_config() -> GHAConfig {
+    let mut config = GHAConfig::default();
+    config.gpio_backend = Some(GpioBackendKind::Simulated);
+    config.state_file = None;
+    config.history = Some(HistoryConfig {
+        enabled: Some(false),
+        ..HistoryConfig::default()
+    });
+    config
+}
+
+/// Switches fan on 18 and heater on 23 and the DHT sensor outside on 17, simulated
+pub(crate) fn sensor_manager() -> SensorManager {
+    let mut config = simulated_config();
+    config.switch_devices = Some(vec![switch_device(18, "fan"), switch_device(23, "heater")]);
+    config.dht_configs = vec![DhtConfig {
+        gpio_pin: Some(17),
+        name: "outside".to_string(),
+        temp_offset: None,
+        humidity_offset: None,
+        gpio_line: None,
+        model: None,
+    }];
+    SensorManager::new(&config)
+}
+
+/// A directory of its own for one test, removed with everything in it when dropped
+#[derive(Debug)]
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "gha_{}_{}_{}",
+            name,
+            std::process::id(),
+            TEMP_DIR_COUNT.fetch_add(1, Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    /// Path of `file_name` in the dir
+    pub(crate) fn join(&self, file_name: &str) -> PathBuf {
+        self.path.join(file_name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,237 @@
+use std::convert::Infallible;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use hyper::server::conn::Http;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use log::{debug, error, info};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::timeout;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use warp::http::Uri;
+use warp::{Filter, Rejection, Reply};
+
+use crate::error::GHAError;
+
+/// Clients that don't finish the TLS handshake in time are dropped so they can't hold
+/// connections open
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait after a failed accept before trying again
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Serves the certificate loaded from the configured paths, reloaded on SIGHUP so a
+/// renewed certificate is picked up without a restart
+struct ReloadingCertResolver {
+    cert_path: String,
+    key_path: String,
+    certified_key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCertResolver {
+    fn load(cert_path: &str, key_path: &str) -> Result<Self, GHAError> {
+        Ok(Self {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            certified_key: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+        })
+    }
+
+    /// Swap in the certificate from disk, the current one is kept if it can't be loaded
+    fn reload(&self) -> Result<(), GHAError> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.certified_key.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, GHAError> {
+    let mut cert_reader = BufReader::new(File::open(cert_path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut cert_reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(GHAError::from_string(format!(
+            "No certificates found in {}",
+            cert_path
+        )));
+    }
+
+    let mut key_reader = BufReader::new(File::open(key_path)?);
+    let key = rustls_pemfile::read_all(&mut key_reader)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| GHAError::from_string(format!("No private key found in {}", key_path)))?;
+    let signing_key = any_supported_type(&key).map_err(|e| {
+        GHAError::from_string(format!("Unsupported private key in {}: {}", key_path, e))
+    })?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Bind `addr` and return the future that serves `service` over HTTPS
+pub(crate) async fn bind_tls<S>(
+    service: S,
+    addr: SocketAddr,
+    cert_path: &str,
+    key_path: &str,
+) -> Result<impl Future<Output = Result<(), GHAError>> + Send, GHAError>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let resolver = Arc::new(ReloadingCertResolver::load(cert_path, key_path)?);
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    // Reload the certificate on SIGHUP
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match resolver.reload() {
+                Ok(()) => info!("Reloaded TLS certificate {}", resolver.cert_path),
+                Err(e) => error!("Unable to reload TLS certificate, keeping the old one: {}", e),
+            }
+        }
+    });
+
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving HTTPS on {}", addr);
+    Ok(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                // Like running out of file descriptors, which passes as connections close
+                Err(e) => {
+                    error!("Unable to accept a connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let service = service.clone();
+            tokio::spawn(async move {
+                let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", peer);
+                        return;
+                    }
+                };
+                if let Err(e) = Http::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades()
+                    .await
+                {
+                    debug!("Connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    })
+}
+
+/// Redirect every plain HTTP request to the same path over HTTPS on `https_port`
+pub(crate) fn redirect_routes(
+    https_port: u16,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::header::<String>("host")
+        .and(warp::path::full())
+        .and(
+            warp::query::raw()
+                .or(warp::any().map(String::new))
+                .unify(),
+        )
+        .map(move |host: String, path: warp::path::FullPath, query: String| {
+            warp::redirect::permanent(https_uri(&host, https_port, path.as_str(), &query))
+        })
+}
+
+fn https_uri(host: &str, https_port: u16, path: &str, query: &str) -> Uri {
+    // Drop the plain HTTP port from the host header
+    let host = match host.find(']') {
+        // IPv6 address like [::1]:8080
+        Some(end) if host.starts_with('[') => &host[..=end],
+        _ => host.split(':').next().unwrap_or(host),
+    };
+    let authority = if https_port == 443 {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, https_port)
+    };
+    let query = if query.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", query)
+    };
+    format!("https://{}{}{}", authority, path, query)
+        .parse()
+        .unwrap_or_else(|_| Uri::from_static("/"))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::TempDir;
+    use crate::tls::{https_uri, load_certified_key};
+
+    #[test]
+    fn test_https_uri() {
+        assert_eq!(
+            https_uri("greenhouse.local:8080", 8443, "/api/v1/switches", ""),
+            "https://greenhouse.local:8443/api/v1/switches"
+        );
+        assert_eq!(
+            https_uri("greenhouse.local", 443, "/api/v1/history", "sensor=outside"),
+            "https://greenhouse.local/api/v1/history?sensor=outside"
+        );
+        assert_eq!(https_uri("[::1]:8080", 8443, "/", ""), "https://[::1]:8443/");
+    }
+
+    #[test]
+    fn test_load_certified_key() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = TempDir::new("tls");
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        let certified_key =
+            load_certified_key(cert_path.to_str().unwrap(), key_path.to_str().unwrap()).unwrap();
+        assert_eq!(certified_key.cert.len(), 1);
+        // A key isn't a certificate
+        assert!(
+            load_certified_key(key_path.to_str().unwrap(), key_path.to_str().unwrap()).is_err()
+        );
+    }
+}
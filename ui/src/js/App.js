@@ -4,6 +4,94 @@ import React, {useEffect, useState} from "react";
 
 const data_host = process.env.API_HOST;
 
+// Api token for agents with auth tokens configured, kept in the browser's local storage
+const apiToken = () => {
+    return window.localStorage.getItem("gha_api_token");
+};
+
+const authHeaders = () => {
+    const token = apiToken();
+    return token ? {"authorization": `Bearer ${token}`} : {};
+};
+
+// Ask for a token when the agent rejects the request and reload with it
+const checkAuth = (response) => {
+    if (response.status === 401 || response.status === 403) {
+        const token = window.prompt("Api token");
+        if (token) {
+            window.localStorage.setItem("gha_api_token", token);
+            window.location.reload();
+        }
+    }
+    return response;
+};
+
+// Wait before reconnecting to the live stream after it drops
+const STREAM_RETRY_MS = 5000;
+
+// Call handlers[event] with the parsed data of one Server-Sent Events block
+const dispatchEvent = (block, handlers) => {
+    let event = "message";
+    const data = [];
+    block.split("\n").forEach((line) => {
+        if (line.startsWith("event:")) {
+            event = line.slice(6).trim();
+        } else if (line.startsWith("data:")) {
+            data.push(line.slice(5).replace(/^ /, ""));
+        }
+    });
+    if (data.length > 0 && handlers[event]) {
+        handlers[event](JSON.parse(data.join("\n")));
+    }
+};
+
+// Dispatch the events of a streamed response until it ends
+const readEvents = async (response, handlers) => {
+    const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+    let buffer = "";
+    for (;;) {
+        const {value, done} = await reader.read();
+        if (done) {
+            return;
+        }
+        buffer += value.replace(/\r\n?/g, "\n");
+        let end;
+        while ((end = buffer.indexOf("\n\n")) >= 0) {
+            dispatchEvent(buffer.slice(0, end), handlers);
+            buffer = buffer.slice(end + 2);
+        }
+    }
+};
+
+// Read the live stream with fetch, unlike EventSource it can send the authorization header.
+// Reconnects when the stream drops until `signal` is aborted.
+const streamEvents = (url, handlers, signal) => {
+    const connect = () => {
+        fetch(url, {headers: {...authHeaders(), "accept": "text/event-stream"}, signal})
+            .then(checkAuth)
+            .then((response) => {
+                // checkAuth already asked for a token, retrying would ask again
+                if (response.status === 401 || response.status === 403) {
+                    return false;
+                }
+                if (!response.ok) {
+                    throw new Error(`live stream: ${response.status}`);
+                }
+                return readEvents(response, handlers).then(() => true);
+            })
+            .catch((err) => {
+                console.log(err.message);
+                return true;
+            })
+            .then((retry) => {
+                if (retry && !signal.aborted) {
+                    setTimeout(connect, STREAM_RETRY_MS);
+                }
+            });
+    };
+    connect();
+};
+
 const noServerAlert = (e) => {
     alert(`no server 🤷 😿 👉 ${JSON.stringify(e)}`);
 };
@@ -84,23 +172,37 @@ const Sensor = ({metric}) => {
     </li>);
 };
 
-const Sensors = ({metrics}) => {
-    const metric_lasts = [/*'c', */'f', 'h']
-
-    const metrics_list = metrics.split("\n")
-        .map((line) => line.trim())
-        .filter((line) => {
-            return !line.startsWith("#");
-        })
-        .map((line) => {
-            const parts = line.split(" ");
-            return {name: parts[0], value: Number.parseFloat(parts[1]).toFixed(2)};
-        })
-        .filter((metrics) => {
-            const last = metrics.name.substring(metrics.name.length - 1, metrics.name.length);
-            return metric_lasts.includes(last);
-        });
+// Stale and uninitialized sensors have no values
+const sensorValue = (value) => {
+    return value === null ? "-" : Number(value).toFixed(2);
+};
 
+// Fahrenheit and humidity of each sensor, named like the legacy gauges
+const parseSensors = (sensors) => {
+    return sensors.flatMap((sensor) => {
+        return [
+            {name: `${sensor.name}_f`, value: sensorValue(sensor.temp_f)},
+            {name: `${sensor.name}_h`, value: sensorValue(sensor.humidity)},
+        ];
+    });
+};
+
+// Replace the values of a sensor's gauges with a reading from the live stream
+const applyReading = (metrics_list, reading) => {
+    const values = {
+        [`${reading.sensor}_c`]: reading.temp_c,
+        [`${reading.sensor}_f`]: reading.temp_f,
+        [`${reading.sensor}_h`]: reading.humidity,
+    };
+    return metrics_list.map((m) => {
+        if (m.name in values) {
+            return {name: m.name, value: Number(values[m.name]).toFixed(2)};
+        }
+        return m;
+    });
+};
+
+const Sensors = ({metrics_list}) => {
     return (
         <div>
             <h2>Sensors</h2>
@@ -115,10 +217,18 @@ const Sensors = ({metrics}) => {
 
 const App = () => {
     const [switches, setSwitches] = useState([]);
-    const [metrics, setMetrics] = useState('');
+    const [metrics, setMetrics] = useState([]);
+
+    const apiUpdateSwitch = (switch_state, update) => {
+        return fetch(`${data_host}/api/v1/switches/${switch_state.name}`, {
+            method: "PUT",
+            headers: {"content-type": "application/json", ...authHeaders()},
+            body: JSON.stringify(update),
+        }).then(checkAuth);
+    };
 
     const apiUpdatePinState = (switch_state) => {
-        fetch(`${data_host}/pin/output/${switch_state.pin_num}/${switch_state.pin_state}`)
+        apiUpdateSwitch(switch_state, {state: switch_state.pin_state})
             .then((data) => {
                 data.text().then((txt) => {
                     console.log(`status: ${data.status} ${data.statusText}`);
@@ -136,8 +246,7 @@ const App = () => {
     };
 
     const apiUpdatePinOverride = (switch_state) => {
-        const value = switch_state.override_auto ? 1 : 0;
-        fetch(`${data_host}/pin/override_auto/${switch_state.pin_num}/${value}`)
+        apiUpdateSwitch(switch_state, {override_auto: switch_state.override_auto})
             .then((data) => {
                 data.text().then((txt) => {
                     console.log(`status: ${data.status} ${data.statusText}`);
@@ -155,7 +264,8 @@ const App = () => {
     };
 
     const fetchSwitchesState = () => {
-        fetch(`${data_host}/switches_state`)
+        fetch(`${data_host}/api/v1/switches`, {headers: authHeaders()})
+            .then(checkAuth)
             .then((response) => response.json())
             .then((data) => {
                 console.log(data);
@@ -168,11 +278,12 @@ const App = () => {
     };
 
     const fetchMetrics = () => {
-        fetch(`${data_host}/metrics`)
-            .then((response) => response.text())
+        fetch(`${data_host}/api/v1/sensors`, {headers: authHeaders()})
+            .then(checkAuth)
+            .then((response) => response.json())
             .then((data) => {
                 // console.log(data);
-                setMetrics(data);
+                setMetrics(parseSensors(data.sensors));
             })
             .catch((err) => {
                 // console.log(err.message);
@@ -188,6 +299,32 @@ const App = () => {
         fetchMetrics();
     }, []);
 
+    // Live readings and switch changes pushed by the agent
+    useEffect(() => {
+        const controller = new AbortController();
+        streamEvents(`${data_host}/api/v1/events`, {
+            reading: (reading) => {
+                setMetrics((metrics_list) => applyReading(metrics_list, reading));
+            },
+            pin_state: (change) => {
+                setSwitches((switches) => switches.map((sw) => {
+                    return sw.pin_num === change.pin_num ? {...sw, pin_state: change.pin_state} : sw;
+                }));
+            },
+            override_auto: (change) => {
+                setSwitches((switches) => switches.map((sw) => {
+                    return sw.pin_num === change.pin_num ? {...sw, override_auto: change.override_auto} : sw;
+                }));
+            },
+            // Switches and sensors may have been added or removed
+            config_reloaded: () => {
+                fetchSwitchesState();
+                fetchMetrics();
+            },
+        }, controller.signal);
+        return () => controller.abort();
+    }, []);
+
     const updatePinState = async (switch_state) => {
         console.log(`updated_switch_state: ${switch_state}`);
         apiUpdatePinState(switch_state);
@@ -204,7 +341,7 @@ const App = () => {
             <h1>Greenhouse Agent</h1>
             <SwitchesGrid switches={switches} onUpdatePinState={updatePinState}
                           onUpdatePinOverride={updatePinOverride}></SwitchesGrid>
-            <Sensors metrics={metrics}></Sensors>
+            <Sensors metrics_list={metrics}></Sensors>
         </Container>
     )
 };